@@ -0,0 +1,16 @@
+#![no_main]
+
+use bittorrent_starter_rust::peer::{MessageFramer, PeerMessage};
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+// whatever a peer sends, decoding must fail cleanly instead of panicking
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    let mut framer = MessageFramer::new();
+    while let Ok(Some(msg)) = framer.decode(&mut src)
+    {
+        let _ = PeerMessage::try_from(msg);
+    }
+});
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+// a peer that sent nothing for this long while we waited on requests is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+pub const UPLOAD_SLOTS: usize = 4;
+
+#[derive(Debug)]
+struct ChokedPeer
+{
+    ip: IpAddr,
+    peer_interested: bool,
+    // bytes since the last rechoke
+    uploaded: u64,
+    choked: watch::Sender<bool>,
+}
+
+impl ChokedPeer
+{
+    fn is_choked(&self) -> bool
+    {
+        *self.choked.borrow()
+    }
+    fn set_choked(&self, choked: bool)
+    {
+        self.choked.send_if_modified(|current| {
+            let changed = *current != choked;
+            *current = choked;
+            changed
+        });
+    }
+}
+
+/// What we download from one address.
+#[derive(Debug, Default)]
+struct Source
+{
+    // bytes since the last rechoke
+    downloaded: u64,
+    // since the last block, while requests to the peer are outstanding
+    waiting_since: Option<Instant>,
+}
+
+/// Tit-for-tat upload slots for the connections of one torrent.
+///
+/// Every `RECHOKE_INTERVAL` the interested peers that gave us the most (or, when seeding, took the most)
+/// get the regular slots; one more slot rotates between random peers every `OPTIMISTIC_INTERVAL`.
+/// Connections learn their state through the receiver `register` returns.
+///
+/// We download over the connections we open and upload over the ones peers open to us, so what
+/// we got is matched to a connection by the peer's address. A peer that left our requests
+/// unanswered for `SNUB_TIMEOUT` gets no regular slot.
+#[derive(Debug)]
+pub struct Choker
+{
+    slots: usize,
+    peers: BTreeMap<u64, ChokedPeer>,
+    sources: HashMap<IpAddr, Source>,
+    next_id: u64,
+    optimistic: Option<u64>,
+    optimistic_at: Option<Instant>,
+}
+
+impl Choker
+{
+    pub fn new(slots: usize) -> Self
+    {
+        Self
+        {
+            slots: slots.max(1),
+            peers: BTreeMap::new(),
+            sources: HashMap::new(),
+            next_id: 0,
+            optimistic: None,
+            optimistic_at: None,
+        }
+    }
+    /// A new connection from `ip` starts choked.
+    pub fn register(&mut self, ip: IpAddr) -> (u64, watch::Receiver<bool>)
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (choked, receiver) = watch::channel(true);
+        self.peers.insert(id, ChokedPeer
+        {
+            ip,
+            peer_interested: false,
+            uploaded: 0,
+            choked,
+        });
+        (id, receiver)
+    }
+    pub fn unregister(&mut self, id: u64)
+    {
+        self.peers.remove(&id);
+        if self.optimistic == Some(id)
+        {
+            self.optimistic = None;
+        }
+    }
+    /// An interested peer gets a free slot right away instead of waiting for the next rechoke.
+    pub fn set_interested(&mut self, id: u64, interested: bool)
+    {
+        let unchoked = self.peers.values().filter(|peer| !peer.is_choked()).count();
+        let Some(peer) = self.peers.get_mut(&id) else {
+            return;
+        };
+        peer.peer_interested = interested;
+        if interested && unchoked < self.slots
+        {
+            peer.set_choked(false);
+        }
+    }
+    pub fn record_upload(&mut self, id: u64, bytes: usize)
+    {
+        if let Some(peer) = self.peers.get_mut(&id)
+        {
+            peer.uploaded += bytes as u64;
+        }
+    }
+    /// Whether requests to `ip` are outstanding; the snub clock runs while they are.
+    pub fn set_waiting(&mut self, ip: IpAddr, waiting: bool, now: Instant)
+    {
+        let source = self.sources.entry(ip).or_default();
+        if !waiting
+        {
+            source.waiting_since = None;
+        }
+        else if source.waiting_since.is_none()
+        {
+            source.waiting_since = Some(now);
+        }
+    }
+    pub fn record_download(&mut self, ip: IpAddr, bytes: usize, now: Instant)
+    {
+        let source = self.sources.entry(ip).or_default();
+        source.downloaded += bytes as u64;
+        if let Some(since) = &mut source.waiting_since
+        {
+            *since = now;
+        }
+    }
+    fn is_snubbing(&self, ip: IpAddr, now: Instant) -> bool
+    {
+        self.sources.get(&ip)
+            .and_then(|source| source.waiting_since)
+            .is_some_and(|since| now.duration_since(since) >= SNUB_TIMEOUT)
+    }
+    pub fn is_choked(&self, id: u64) -> bool
+    {
+        self.peers.get(&id).is_none_or(ChokedPeer::is_choked)
+    }
+    /// Reassigns the slots; `seeding` ranks by what peers took from us instead of what they gave.
+    pub fn rechoke(&mut self, seeding: bool, now: Instant)
+    {
+        let downloaded = |peer: &ChokedPeer| self.sources.get(&peer.ip).map_or(0, |source| source.downloaded);
+        let mut ranked: Vec<(u64, u64)> = self.peers.iter()
+            .filter(|(_, peer)| peer.peer_interested && !self.is_snubbing(peer.ip, now))
+            .map(|(id, peer)| (*id, if seeding { peer.uploaded } else { downloaded(peer) }))
+            .collect();
+        // ties go to the peer connected first, which keeps the order stable between rounds
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let regular: Vec<u64> = ranked.iter().take(self.slots - 1).map(|(id, _)| *id).collect();
+
+        let rotate = self.optimistic_at.is_none_or(|at| now.duration_since(at) >= OPTIMISTIC_INTERVAL);
+        let keeps_optimistic = self.optimistic
+            .and_then(|id| self.peers.get(&id))
+            .is_some_and(|peer| peer.peer_interested);
+        if rotate || !keeps_optimistic
+        {
+            let candidates: Vec<u64> = self.peers.iter()
+                .filter(|(id, peer)| peer.peer_interested && !regular.contains(id) && self.optimistic != Some(**id))
+                .map(|(id, _)| *id)
+                .collect();
+            self.optimistic = fastrand::choice(candidates).or(self.optimistic.filter(|_| keeps_optimistic));
+            self.optimistic_at = Some(now);
+        }
+        if self.optimistic.is_some_and(|id| regular.contains(&id))
+        {
+            self.optimistic = None;
+        }
+
+        for (id, peer) in &mut self.peers
+        {
+            peer.set_choked(!regular.contains(id) && self.optimistic != Some(*id));
+            peer.uploaded = 0;
+        }
+        // addresses we no longer wait on come back with their next block
+        self.sources.retain(|_, source| {
+            source.downloaded = 0;
+            source.waiting_since.is_some()
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod test_choker
+{
+    use std::net::IpAddr;
+    use std::time::Instant;
+    use crate::choker::{Choker, OPTIMISTIC_INTERVAL, RECHOKE_INTERVAL, SNUB_TIMEOUT};
+
+    fn ip(id: u64) -> IpAddr
+    {
+        IpAddr::from([127, 0, 0, id as u8 + 1])
+    }
+
+    fn interested(choker: &mut Choker, count: usize) -> Vec<u64>
+    {
+        (0..count)
+            .map(|n| {
+                let (id, _) = choker.register(ip(n as u64));
+                choker.set_interested(id, true);
+                id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unchokes_fastest_peers()
+    {
+        let now = Instant::now();
+        let mut choker = Choker::new(3);
+        let peers = interested(&mut choker, 5);
+        for (rate, id) in peers.iter().enumerate()
+        {
+            choker.record_download(ip(*id), rate * 1000, now);
+        }
+
+        choker.rechoke(false, now);
+
+        assert!(!choker.is_choked(peers[4]) && !choker.is_choked(peers[3]), "Top two get regular slots");
+        let unchoked = peers.iter().filter(|id| !choker.is_choked(**id)).count();
+        assert_eq!(unchoked, 3, "Two regular slots and one optimistic");
+    }
+
+    #[test]
+    fn counts_only_the_last_round()
+    {
+        let now = Instant::now();
+        let mut choker = Choker::new(2);
+        let peers = interested(&mut choker, 3);
+        choker.record_upload(peers[0], 10_000);
+        choker.rechoke(true, now);
+        assert!(!choker.is_choked(peers[0]), "Biggest taker wins");
+
+        choker.record_upload(peers[2], 10);
+        choker.rechoke(true, now + RECHOKE_INTERVAL);
+
+        assert!(!choker.is_choked(peers[2]), "Counters start over every rechoke");
+    }
+
+    #[test]
+    fn rotates_optimistic_unchoke()
+    {
+        let start = Instant::now();
+        let mut choker = Choker::new(1);
+        let peers = interested(&mut choker, 2);
+
+        choker.rechoke(true, start);
+        let first = peers.iter().copied().find(|id| !choker.is_choked(*id)).unwrap();
+        choker.rechoke(true, start + RECHOKE_INTERVAL);
+        assert!(!choker.is_choked(first), "Optimistic unchoke holds for 30 seconds");
+
+        choker.rechoke(true, start + OPTIMISTIC_INTERVAL);
+        let second = peers.iter().copied().find(|id| !choker.is_choked(*id)).unwrap();
+        assert_ne!(first, second, "Should rotate to the other peer");
+    }
+
+    #[test]
+    fn keeps_optimistic_slot_until_it_rotates()
+    {
+        let start = Instant::now();
+        let mut choker = Choker::new(2);
+        let peers = interested(&mut choker, 2);
+        choker.record_upload(peers[1], 50_000);
+
+        choker.rechoke(true, start);
+        assert!(!choker.is_choked(peers[1]) && !choker.is_choked(peers[0]), "One regular, one optimistic");
+
+        choker.unregister(peers[1]);
+        choker.rechoke(true, start + RECHOKE_INTERVAL);
+        assert!(!choker.is_choked(peers[0]), "Optimistic slot is kept until it rotates");
+    }
+
+    #[test]
+    fn ranks_by_upload_when_seeding()
+    {
+        let now = Instant::now();
+        let mut choker = Choker::new(2);
+        let peers = interested(&mut choker, 3);
+        choker.record_download(ip(peers[0]), 50_000, now);
+        choker.record_upload(peers[2], 10_000);
+
+        choker.rechoke(true, now);
+
+        assert!(!choker.is_choked(peers[2]), "Biggest taker gets the regular slot");
+    }
+
+    #[test]
+    fn drops_snubbing_peers_from_regular_slots()
+    {
+        let start = Instant::now();
+        let mut choker = Choker::new(2);
+        let peers = interested(&mut choker, 2);
+        choker.set_waiting(ip(peers[0]), true, start);
+        choker.record_download(ip(peers[0]), 50_000, start);
+        choker.rechoke(false, start);
+        assert!(!choker.is_choked(peers[0]) && !choker.is_choked(peers[1]), "One regular, one optimistic");
+
+        choker.rechoke(false, start + SNUB_TIMEOUT);
+        assert!(!choker.is_choked(peers[1]), "The other peer takes the regular slot");
+        assert_eq!(choker.optimistic, Some(peers[0]), "A snubbing peer can only be unchoked optimistically");
+
+        choker.record_download(ip(peers[0]), 1000, start + SNUB_TIMEOUT);
+        choker.rechoke(false, start + SNUB_TIMEOUT + RECHOKE_INTERVAL);
+        assert!(!choker.is_choked(peers[0]) && choker.optimistic.is_none(), "A block ends the snub");
+    }
+
+    #[test]
+    fn fills_free_slots_on_interest()
+    {
+        let mut choker = Choker::new(1);
+
+        let peers = interested(&mut choker, 2);
+
+        assert!(!choker.is_choked(peers[0]), "Free slot is used right away");
+        assert!(choker.is_choked(peers[1]), "No slot left");
+    }
+}
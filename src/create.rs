@@ -0,0 +1,382 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Context;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use crate::peer::Peer;
+use crate::storage::Storage;
+use crate::torrent::File;
+use crate::verify;
+
+pub const MIN_PIECE_LENGTH: usize = Peer::BLOCK_MAX as usize;
+pub const MAX_PIECE_LENGTH: usize = 16 << 20;
+// the automatic piece length aims for about this many pieces
+const TARGET_PIECES: usize = 1500;
+
+/// Builds a .torrent from a file or a directory.
+///
+/// The result is canonical bencode: dictionary keys sorted, no keys for unset fields.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder
+{
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<usize>,
+    trackers: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    source: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MetaInfo
+{
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Vec::is_empty")]
+    announce_list: Vec<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    creation_date: Option<i64>,
+    info: NewInfo,
+    /// BEP 19 web seeds.
+    #[serde(rename = "url-list", skip_serializing_if = "Vec::is_empty")]
+    url_list: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct NewInfo
+{
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<File>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    pieces: ByteBuf,
+    /// BEP 27: peers only come from the trackers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+impl TorrentBuilder
+{
+    /// Stamped with this crate as the creator and the current time.
+    pub fn new(path: impl Into<PathBuf>) -> Self
+    {
+        Self
+        {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            private: false,
+            comment: None,
+            created_by: Some(format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+            creation_date: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|now| now.as_secs() as i64),
+            source: None,
+        }
+    }
+    /// Defaults to the file or directory name.
+    pub fn name(mut self, name: impl Into<String>) -> Self
+    {
+        self.name = Some(name.into());
+        self
+    }
+    /// A power of two of at least `MIN_PIECE_LENGTH`; `None` picks one from the total size.
+    pub fn piece_length(mut self, piece_length: Option<usize>) -> Self
+    {
+        self.piece_length = piece_length;
+        self
+    }
+    /// Adds a tier of trackers; the first tracker becomes `announce`.
+    pub fn tier(mut self, trackers: Vec<String>) -> Self
+    {
+        if !trackers.is_empty()
+        {
+            self.trackers.push(trackers);
+        }
+        self
+    }
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self
+    {
+        self.web_seeds.push(url.into());
+        self
+    }
+    pub fn private(mut self, private: bool) -> Self
+    {
+        self.private = private;
+        self
+    }
+    pub fn comment(mut self, comment: Option<String>) -> Self
+    {
+        self.comment = comment;
+        self
+    }
+    pub fn created_by(mut self, created_by: Option<String>) -> Self
+    {
+        self.created_by = created_by;
+        self
+    }
+    /// Seconds since the epoch; `None` leaves it out, which keeps builds reproducible.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self
+    {
+        self.creation_date = creation_date;
+        self
+    }
+    /// Makes the infohash differ between trackers that share a private torrent.
+    pub fn source(mut self, source: Option<String>) -> Self
+    {
+        self.source = source;
+        self
+    }
+    /// Walks the path, hashes the pieces on all cores and returns the bencoded metainfo.
+    pub fn build(self) -> anyhow::Result<Vec<u8>>
+    {
+        let metadata = std::fs::metadata(&self.path).with_context(|| format!("Reading {}", self.path.display()))?;
+        let name = match self.name {
+            Some(name) => name,
+            None => {
+                // `.` and `..` only get a name once resolved
+                let path = std::fs::canonicalize(&self.path).with_context(|| format!("Resolving {}", self.path.display()))?;
+                path.file_name().and_then(|name| name.to_str())
+                    .with_context(|| format!("{} has no usable name", path.display()))?
+                    .to_string()
+            }
+        };
+        let (files, on_disk) = if metadata.is_dir()
+        {
+            let mut on_disk = Vec::new();
+            walk(&self.path, &mut on_disk)?;
+            anyhow::ensure!(!on_disk.is_empty(), "{} has no files", self.path.display());
+            let files = on_disk.iter()
+                .map(|(path, length)| Ok(File { length: *length, path: components(path.strip_prefix(&self.path)?)? }))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            (Some(files), on_disk)
+        } else {
+            (None, vec![(self.path.clone(), metadata.len() as usize)])
+        };
+
+        let storage = Storage::from_files(on_disk);
+        let total = storage.layout().last().map_or(0, |(_, range)| range.end);
+        anyhow::ensure!(total > 0, "{} holds no data", self.path.display());
+        let piece_length = match self.piece_length {
+            Some(length) => {
+                anyhow::ensure!(length.is_power_of_two() && length >= MIN_PIECE_LENGTH,
+                    "Piece length {} should be a power of two of at least {}", length, MIN_PIECE_LENGTH);
+                length
+            }
+            None => auto_piece_length(total),
+        };
+        let hashes = verify::in_parallel(total.div_ceil(piece_length), |index| {
+            let offset = index * piece_length;
+            let piece = storage.read(offset, piece_length.min(total - offset))?;
+            Ok(<[u8; 20]>::from(Sha1::digest(piece)))
+        });
+        let pieces = hashes.into_iter().collect::<anyhow::Result<Vec<_>>>().context("Hashing pieces")?;
+
+        let meta_info = MetaInfo
+        {
+            announce: self.trackers.iter().flatten().next().cloned(),
+            // a single tracker needs no list
+            announce_list: if self.trackers.iter().flatten().count() > 1 { self.trackers } else { Vec::new() },
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            info: NewInfo
+            {
+                length: files.is_none().then_some(total),
+                files,
+                name,
+                piece_length,
+                pieces: ByteBuf::from(pieces.concat()),
+                private: self.private.then_some(1),
+                source: self.source,
+            },
+            url_list: self.web_seeds,
+        };
+        serde_bencode::to_bytes(&meta_info).context("Encoding torrent")
+    }
+}
+
+/// About `TARGET_PIECES` pieces, as a power of two between the minimum and maximum length.
+pub fn auto_piece_length(total: usize) -> usize
+{
+    (total / TARGET_PIECES).next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Every file under `dir` with its length, in path order so the result does not depend on the file system.
+/// Symbolic links are skipped: they may point out of `dir` or back into it.
+fn walk(dir: &Path, files: &mut Vec<(PathBuf, usize)>) -> anyhow::Result<()>
+{
+    let mut entries = std::fs::read_dir(dir).with_context(|| format!("Listing {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries
+    {
+        let path = entry.path();
+        let metadata = std::fs::symlink_metadata(&path).with_context(|| format!("Reading {}", path.display()))?;
+        if metadata.is_symlink()
+        {
+            continue;
+        }
+        if metadata.is_dir()
+        {
+            walk(&path, files)?;
+        } else {
+            files.push((path, metadata.len() as usize));
+        }
+    }
+    Ok(())
+}
+
+fn components(path: &Path) -> anyhow::Result<Vec<String>>
+{
+    path.components()
+        .map(|part| part.as_os_str().to_str().map(String::from)
+            .with_context(|| format!("{} is not valid UTF-8", path.display())))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test_create
+{
+    use crate::create::{auto_piece_length, TorrentBuilder, MAX_PIECE_LENGTH, MIN_PIECE_LENGTH};
+    use crate::storage::Storage;
+    use crate::torrent::{Keys, Torrent};
+    use crate::verify::verify;
+
+    #[test]
+    fn picks_piece_lengths()
+    {
+        assert_eq!(auto_piece_length(1), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1500 << 20), 1 << 20);
+        assert_eq!(auto_piece_length(usize::MAX >> 8), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn builds_single_file_torrent()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..40_000).map(|_| fastrand::u8(..)).collect();
+        std::fs::write(dir.path().join("a.bin"), &data).unwrap();
+
+        let bytes = TorrentBuilder::new(dir.path().join("a.bin"))
+            .tier(vec!["http://one/announce".to_string()])
+            .creation_date(None)
+            .created_by(None)
+            .build()
+            .unwrap();
+
+        let torrent = Torrent::try_from(bytes.clone()).unwrap();
+        assert_eq!(torrent.info.name, "a.bin");
+        assert_eq!(torrent.announce, "http://one/announce");
+        assert!(torrent.announce_list.is_empty(), "One tracker needs no list");
+        assert!(matches!(torrent.info.keys, Keys::SingleFile { length: 40_000 }));
+        assert_eq!(torrent.info.piece_length, MIN_PIECE_LENGTH);
+        assert!(verify(&torrent, &Storage::new(&torrent, &dir.path().join("a.bin")).unwrap()).is_complete());
+        assert_eq!(
+            bytes,
+            TorrentBuilder::new(dir.path().join("a.bin")).tier(vec!["http://one/announce".to_string()]).creation_date(None).created_by(None).build().unwrap(),
+            "Same input, same bytes"
+        );
+    }
+
+    #[test]
+    fn builds_multi_file_torrent()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("b"), vec![2u8; 20_000]).unwrap();
+        std::fs::write(root.join("sub/a"), vec![1u8; 30_000]).unwrap();
+        std::fs::write(root.join("a"), b"").unwrap();
+
+        let bytes = TorrentBuilder::new(&root)
+            .piece_length(Some(1 << 15))
+            .tier(vec!["http://one/announce".to_string(), "http://two/announce".to_string()])
+            .tier(vec!["udp://three:80".to_string()])
+            .web_seed("http://mirror/root")
+            .private(true)
+            .comment(Some("test".to_string()))
+            .source(Some("SRC".to_string()))
+            .creation_date(Some(1_700_000_000))
+            .build()
+            .unwrap();
+
+        let torrent = Torrent::try_from(bytes.clone()).unwrap();
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("Directory should make a multi-file torrent");
+        };
+        let paths: Vec<_> = files.iter().map(|file| (file.path.join("/"), file.length)).collect();
+        assert_eq!(paths, vec![("a".to_string(), 0), ("b".to_string(), 20_000), ("sub/a".to_string(), 30_000)]);
+        assert_eq!(torrent.info.pieces.0.len(), 2);
+        assert_eq!(torrent.announce_list.len(), 2);
+        assert!(verify(&torrent, &Storage::new(&torrent, dir.path()).unwrap()).is_complete());
+
+        let text = String::from_utf8_lossy(&bytes);
+        for key in ["7:comment", "10:created by", "13:creation date", "7:privatei1e", "6:source3:SRC", "8:url-listl18:http://mirror/roote"]
+        {
+            assert!(text.contains(key), "Missing {}", key);
+        }
+        // canonical: top-level keys in sorted order
+        let order = ["8:announce", "13:announce-list", "7:comment", "10:created by", "13:creation date", "4:info", "8:url-list"];
+        let positions: Vec<_> = order.iter().map(|key| text.find(key).unwrap()).collect();
+        assert!(positions.is_sorted(), "Keys out of order: {:?}", positions);
+    }
+
+    #[test]
+    fn names_torrent_after_resolved_path()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), b"data").unwrap();
+
+        let bytes = TorrentBuilder::new(root.join("sub/..")).build().unwrap();
+
+        assert_eq!(Torrent::try_from(bytes).unwrap().info.name, "root");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), b"data").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("b")).unwrap();
+
+        let bytes = TorrentBuilder::new(&root).build().unwrap();
+
+        let Keys::MultiFile { files } = Torrent::try_from(bytes).unwrap().info.keys else {
+            panic!("Directory should make a multi-file torrent");
+        };
+        let paths: Vec<_> = files.iter().map(|file| file.path.join("/")).collect();
+        assert_eq!(paths, vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn rejects_bad_piece_length()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), b"data").unwrap();
+
+        assert!(TorrentBuilder::new(dir.path().join("a")).piece_length(Some(3 << 14)).build().is_err());
+        assert!(TorrentBuilder::new(dir.path().join("a")).piece_length(Some(1 << 10)).build().is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// deeper input is rejected rather than risking the stack
+pub const MAX_DEPTH: usize = 256;
+
+/// A bencoded value. Byte strings stay bytes; dictionaries keep bencode's sorted key order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value
+{
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+/// Why input is not bencode; every position is a byte offset into the input.
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum DecodeError
+{
+    #[error("Unexpected end of input at {0}")]
+    UnexpectedEnd(usize),
+    #[error("Unexpected byte {byte:?} at {pos}")]
+    UnexpectedByte { byte: char, pos: usize },
+    #[error("Integer at {0} has no digits")]
+    EmptyInteger(usize),
+    #[error("Number at {0} has a leading zero")]
+    LeadingZero(usize),
+    #[error("Integer at {0} is negative zero")]
+    NegativeZero(usize),
+    #[error("Number at {0} does not fit")]
+    Overflow(usize),
+    #[error("Dictionary key at {0} is not a byte string")]
+    KeyNotBytes(usize),
+    #[error("Dictionary key at {0} is out of order")]
+    UnsortedKey(usize),
+    #[error("Dictionary key at {0} is repeated")]
+    DuplicateKey(usize),
+    #[error("Nesting deeper than {MAX_DEPTH} at {0}")]
+    TooDeep(usize),
+    #[error("Trailing data at {0}")]
+    TrailingData(usize),
+}
+
+impl Value
+{
+    /// For display: byte strings as text when they are UTF-8, as hex otherwise. Hex gets a `0x`
+    /// prefix, so it cannot be mistaken for text spelling the same digits.
+    pub fn to_json(&self) -> serde_json::Value
+    {
+        match self {
+            Value::Integer(number) => (*number).into(),
+            Value::Bytes(bytes) => render(bytes).into(),
+            Value::List(values) => values.iter().map(Value::to_json).collect::<Vec<_>>().into(),
+            Value::Dict(entries) => entries.iter()
+                .map(|(key, value)| (render(key), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+}
+
+fn render(bytes: &[u8]) -> String
+{
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("0x{}", hex::encode(bytes)),
+    }
+}
+
+/// Decodes exactly one value; anything after it is an error.
+pub fn decode(bytes: &[u8]) -> Result<Value, DecodeError>
+{
+    let (value, end) = decode_prefix(bytes)?;
+    if end != bytes.len()
+    {
+        return Err(DecodeError::TrailingData(end));
+    }
+    Ok(value)
+}
+
+/// Decodes the value at the start of `bytes` and returns where it ends.
+pub fn decode_prefix(bytes: &[u8]) -> Result<(Value, usize), DecodeError>
+{
+    Decoder { bytes, pos: 0 }.value(0).map(|(value, decoder)| (value, decoder.pos))
+}
+
+struct Decoder<'a>
+{
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a>
+{
+    fn peek(&self) -> Result<u8, DecodeError>
+    {
+        self.bytes.get(self.pos).copied().ok_or(DecodeError::UnexpectedEnd(self.pos))
+    }
+    fn unexpected(&self, byte: u8) -> DecodeError
+    {
+        DecodeError::UnexpectedByte { byte: byte as char, pos: self.pos }
+    }
+    fn value(mut self, depth: usize) -> Result<(Value, Self), DecodeError>
+    {
+        if depth > MAX_DEPTH
+        {
+            return Err(DecodeError::TooDeep(self.pos));
+        }
+        let value = match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let number = self.integer(b'e')?;
+                Value::Integer(number)
+            }
+            b'0'..=b'9' => Value::Bytes(self.string()?.to_vec()),
+            b'l' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                while self.peek()? != b'e'
+                {
+                    let (value, next) = self.value(depth + 1)?;
+                    values.push(value);
+                    self = next;
+                }
+                self.pos += 1;
+                Value::List(values)
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut entries = BTreeMap::new();
+                let mut last: Option<&[u8]> = None;
+                while self.peek()? != b'e'
+                {
+                    let at = self.pos;
+                    if !self.peek()?.is_ascii_digit()
+                    {
+                        return Err(DecodeError::KeyNotBytes(at));
+                    }
+                    let key = self.string()?;
+                    match last.map(|last| last.cmp(key)) {
+                        Some(std::cmp::Ordering::Equal) => return Err(DecodeError::DuplicateKey(at)),
+                        Some(std::cmp::Ordering::Greater) => return Err(DecodeError::UnsortedKey(at)),
+                        _ => {}
+                    }
+                    last = Some(key);
+                    let (value, next) = self.value(depth + 1)?;
+                    entries.insert(key.to_vec(), value);
+                    self = next;
+                }
+                self.pos += 1;
+                Value::Dict(entries)
+            }
+            byte => return Err(self.unexpected(byte)),
+        };
+        Ok((value, self))
+    }
+    /// A byte string: `<length>:<bytes>`.
+    fn string(&mut self) -> Result<&'a [u8], DecodeError>
+    {
+        let at = self.pos;
+        let length = usize::try_from(self.integer(b':')?).map_err(|_| DecodeError::Overflow(at))?;
+        let start = self.pos;
+        let string = start.checked_add(length).and_then(|end| self.bytes.get(start..end))
+            .ok_or(DecodeError::UnexpectedEnd(self.bytes.len()))?;
+        self.pos += length;
+        Ok(string)
+    }
+    /// Decimal digits up to `end`, which is consumed; only integers may be negative.
+    fn integer(&mut self, end: u8) -> Result<i64, DecodeError>
+    {
+        let start = self.pos;
+        let negative = end == b'e' && self.peek()? == b'-';
+        if negative
+        {
+            self.pos += 1;
+        }
+        let digits = self.pos;
+        while self.peek()?.is_ascii_digit()
+        {
+            self.pos += 1;
+        }
+        let terminator = self.peek()?;
+        if terminator != end
+        {
+            return Err(self.unexpected(terminator));
+        }
+        let number = &self.bytes[digits..self.pos];
+        match number {
+            [] => return Err(DecodeError::EmptyInteger(start)),
+            [b'0'] if negative => return Err(DecodeError::NegativeZero(start)),
+            [b'0', _, ..] => return Err(DecodeError::LeadingZero(start)),
+            _ => {}
+        }
+        // digits only, so this can only fail on overflow
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).expect("ASCII digits");
+        let number = text.parse().map_err(|_| DecodeError::Overflow(start))?;
+        self.pos += 1;
+        Ok(number)
+    }
+}
+
+
+#[cfg(test)]
+mod test_decoder
+{
+    use std::collections::BTreeMap;
+    use crate::decoder::{decode, decode_prefix, DecodeError, Value, MAX_DEPTH};
+
+    fn bytes(text: &str) -> Value
+    {
+        Value::Bytes(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn decodes_scalars()
+    {
+        assert_eq!(decode(b"i42e"), Ok(Value::Integer(42)));
+        assert_eq!(decode(b"i-52e"), Ok(Value::Integer(-52)));
+        assert_eq!(decode(b"i0e"), Ok(Value::Integer(0)));
+        assert_eq!(decode(b"5:hello"), Ok(bytes("hello")));
+        assert_eq!(decode(b"0:"), Ok(bytes("")));
+        assert_eq!(decode("5:\u{e9}t\u{e9}".as_bytes()), Ok(bytes("\u{e9}t\u{e9}")), "Length counts bytes, not characters");
+        assert_eq!(decode(b"2:\xff\x00"), Ok(Value::Bytes(vec![0xff, 0])));
+    }
+
+    #[test]
+    fn decodes_containers()
+    {
+        assert_eq!(decode(b"le"), Ok(Value::List(vec![])));
+        assert_eq!(decode(b"de"), Ok(Value::Dict(BTreeMap::new())));
+        assert_eq!(
+            decode(b"l5:helloi52eli1eleee"),
+            Ok(Value::List(vec![bytes("hello"), Value::Integer(52), Value::List(vec![Value::Integer(1), Value::List(vec![])])]))
+        );
+        let dict = decode(b"d3:foo3:bar5:helloi52e4:listl2:abee").unwrap();
+        assert_eq!(
+            dict.to_json().to_string(),
+            r#"{"foo":"bar","hello":52,"list":["ab"]}"#
+        );
+        assert_eq!(decode_prefix(b"lei1e"), Ok((Value::List(vec![]), 2)), "Stops after the closing e");
+    }
+
+    #[test]
+    fn renders_binary_as_hex()
+    {
+        let value = decode(b"d1:\xffl2:\xfe\x01ee").unwrap();
+        assert_eq!(value.to_json().to_string(), r#"{"0xff":["0xfe01"]}"#);
+
+        let both = decode(b"d2:ffi1e1:\xffi2ee").unwrap();
+        assert_eq!(both.to_json().to_string(), r#"{"0xff":2,"ff":1}"#, "Text and binary keys stay apart");
+
+        let values = decode(b"l2:ff1:\xffe").unwrap();
+        assert_eq!(values.to_json().to_string(), r#"["ff","0xff"]"#, "Text and binary values stay apart");
+    }
+
+    #[test]
+    fn rejects_malformed_input()
+    {
+        let cases: [(&[u8], DecodeError); 16] = [
+            (b"", DecodeError::UnexpectedEnd(0)),
+            (b"i03e", DecodeError::LeadingZero(1)),
+            (b"i-0e", DecodeError::NegativeZero(1)),
+            (b"ie", DecodeError::EmptyInteger(1)),
+            (b"i-e", DecodeError::EmptyInteger(1)),
+            (b"i1x", DecodeError::UnexpectedByte { byte: 'x', pos: 2 }),
+            (b"i99999999999999999999e", DecodeError::Overflow(1)),
+            (b"03:abc", DecodeError::LeadingZero(0)),
+            (b"-1:a", DecodeError::UnexpectedByte { byte: '-', pos: 0 }),
+            (b"5:abc", DecodeError::UnexpectedEnd(5)),
+            (b"l1:a", DecodeError::UnexpectedEnd(4)),
+            (b"di1e1:ae", DecodeError::KeyNotBytes(1)),
+            (b"d1:bi1e1:ai2ee", DecodeError::UnsortedKey(7)),
+            (b"d1:ai1e1:ai2ee", DecodeError::DuplicateKey(7)),
+            (b"i1ei2e", DecodeError::TrailingData(3)),
+            (b"x", DecodeError::UnexpectedByte { byte: 'x', pos: 0 }),
+        ];
+        for (input, error) in cases
+        {
+            assert_eq!(decode(input), Err(error), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn limits_nesting()
+    {
+        let deep = [vec![b'l'; MAX_DEPTH + 2], vec![b'e'; MAX_DEPTH + 2]].concat();
+        assert_eq!(decode(&deep), Err(DecodeError::TooDeep(MAX_DEPTH + 1)));
+
+        let fine = [vec![b'l'; MAX_DEPTH], vec![b'e'; MAX_DEPTH]].concat();
+        assert!(decode(&fine).is_ok());
+    }
+}
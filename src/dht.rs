@@ -0,0 +1,977 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use crate::tracker::peers;
+
+// nodes per bucket, and how many closest nodes a lookup converges on
+pub const K: usize = 8;
+// queries in flight per lookup round
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// a node not heard from for this long may be replaced
+const STALE: Duration = Duration::from_secs(15 * 60);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_HASH: usize = 200;
+// keeps a `get_peers` response inside one UDP datagram
+const MAX_VALUES: usize = 50;
+const SEARCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// 20 bytes of id, then a compact IPv4 address
+const COMPACT_NODE: usize = 26;
+
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// KRPC error codes
+const PROTOCOL_ERROR: i64 = 203;
+const METHOD_UNKNOWN: i64 = 204;
+
+fn random_bytes<const N: usize>() -> [u8; N]
+{
+    std::array::from_fn(|_| fastrand::u8(..))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId
+{
+    pub fn random() -> Self
+    {
+        Self(random_bytes())
+    }
+    pub fn distance(&self, other: &[u8; 20]) -> [u8; 20]
+    {
+        let mut distance = [0; 20];
+        for (i, byte) in distance.iter_mut().enumerate()
+        {
+            *byte = self.0[i] ^ other[i];
+        }
+        distance
+    }
+    /// The bucket `other` falls into: the length of the prefix it shares with us.
+    fn bucket(&self, other: &NodeId) -> Option<usize>
+    {
+        let distance = self.distance(&other.0);
+        let zeros = distance.iter()
+            .position(|&byte| byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>
+    {
+        Ok(Self(bytes.try_into().context("Node id should be 20 bytes")?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node
+{
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// The compact node info form: id followed by a compact IPv4 address, per node.
+pub fn nodes_to_compact(nodes: &[Node]) -> Vec<u8>
+{
+    let mut bytes = Vec::with_capacity(COMPACT_NODE * nodes.len());
+    for node in nodes
+    {
+        if node.addr.is_ipv4()
+        {
+            bytes.extend(node.id.0);
+            bytes.extend(peers::to_compact_v4(&[node.addr]));
+        }
+    }
+    bytes
+}
+
+pub fn nodes_from_compact(bytes: &[u8]) -> Option<Vec<(NodeId, SocketAddr)>>
+{
+    if !bytes.len().is_multiple_of(COMPACT_NODE)
+    {
+        return None;
+    }
+    bytes.chunks_exact(COMPACT_NODE)
+        .map(|node| {
+            let id = NodeId(node[..20].try_into().expect("guaranty to be 20"));
+            let addr = peers::from_compact_v4(&node[20..])?.pop()?;
+            Some((id, addr))
+        })
+        .collect()
+}
+
+/// Kademlia k-buckets, one per shared prefix length with our id.
+#[derive(Debug)]
+pub struct RoutingTable
+{
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable
+{
+    pub fn new(id: NodeId) -> Self
+    {
+        Self
+        {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+    /// Records that `id` at `addr` is alive. A full bucket only makes room by dropping a stale node.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> bool
+    {
+        let Some(bucket) = self.id.bucket(&id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[bucket];
+        if let Some(i) = bucket.iter().position(|node| node.id == id)
+        {
+            // the most recently seen node goes last
+            let mut node = bucket.remove(i);
+            node.addr = addr;
+            node.last_seen = now;
+            bucket.push(node);
+            return true;
+        }
+        if bucket.len() >= K
+        {
+            let Some(stale) = bucket.iter().position(|node| now.duration_since(node.last_seen) >= STALE) else {
+                return false;
+            };
+            bucket.remove(stale);
+        }
+        bucket.push(Node { id, addr, last_seen: now });
+        true
+    }
+    pub fn remove(&mut self, addr: SocketAddr)
+    {
+        for bucket in &mut self.buckets
+        {
+            bucket.retain(|node| node.addr != addr);
+        }
+    }
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<Node>
+    {
+        let mut nodes: Vec<Node> = self.nodes().collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+    pub fn nodes(&self) -> impl Iterator<Item = Node> + '_
+    {
+        self.buckets.iter().flatten().copied()
+    }
+    pub fn len(&self) -> usize
+    {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+}
+
+/// `announce_peer` tokens: a hash of the querying IP with a secret that rotates every five minutes.
+#[derive(Debug)]
+struct Tokens
+{
+    secret: [u8; 8],
+    previous: [u8; 8],
+    rotated: Instant,
+}
+
+impl Tokens
+{
+    fn new(now: Instant) -> Self
+    {
+        let secret = random_bytes();
+        Self
+        {
+            secret,
+            previous: secret,
+            rotated: now,
+        }
+    }
+    fn rotate(&mut self, now: Instant)
+    {
+        while now.duration_since(self.rotated) >= TOKEN_ROTATION
+        {
+            self.previous = self.secret;
+            self.secret = random_bytes();
+            self.rotated += TOKEN_ROTATION;
+        }
+    }
+    fn token(&mut self, ip: IpAddr, now: Instant) -> Vec<u8>
+    {
+        self.rotate(now);
+        Self::hash(&self.secret, ip)
+    }
+    /// Tokens from the current and the previous secret are accepted.
+    fn is_valid(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool
+    {
+        self.rotate(now);
+        token == Self::hash(&self.secret, ip) || token == Self::hash(&self.previous, ip)
+    }
+    fn hash(secret: &[u8; 8], ip: IpAddr) -> Vec<u8>
+    {
+        let mut hash = Sha1::new();
+        hash.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hash.update(ip.octets()),
+            IpAddr::V6(ip) => hash.update(ip.octets()),
+        }
+        hash.finalize()[..8].to_vec()
+    }
+}
+
+/// Peers announced to us, per infohash.
+#[derive(Debug, Default)]
+struct PeerStore
+{
+    peers: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+}
+
+impl PeerStore
+{
+    fn add(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: Instant)
+    {
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|(addr, seen)| *addr != peer && now.duration_since(*seen) < PEER_TTL);
+        if peers.len() >= MAX_PEERS_PER_HASH
+        {
+            peers.remove(0);
+        }
+        peers.push((peer, now));
+    }
+    fn get(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr>
+    {
+        let Some(peers) = self.peers.get(info_hash) else {
+            return Vec::new();
+        };
+        let mut fresh: Vec<_> = peers.iter()
+            .filter(|(_, seen)| now.duration_since(*seen) < PEER_TTL)
+            .map(|(addr, _)| *addr)
+            .collect();
+        fastrand::shuffle(&mut fresh);
+        fresh.truncate(MAX_VALUES);
+        fresh
+    }
+}
+
+/// A KRPC message; `y` says whether it is a query (`q`), a response (`r`) or an error (`e`).
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Krpc
+{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Arguments>,
+    // `[code, message]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<Vec<ErrorPart>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+    pub t: ByteBuf,
+    pub y: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ErrorPart
+{
+    Code(i64),
+    Message(ByteBuf),
+}
+
+impl Krpc
+{
+    pub fn error(&self) -> Option<(i64, String)>
+    {
+        match self.e.as_deref()? {
+            [ErrorPart::Code(code), ErrorPart::Message(message)] => Some((*code, String::from_utf8_lossy(message).into_owned())),
+            _ => Some((0, "Malformed error".to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Arguments
+{
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Response
+{
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+}
+
+impl Response
+{
+    fn nodes(&self) -> Vec<(NodeId, SocketAddr)>
+    {
+        self.nodes.as_ref()
+            .and_then(|nodes| nodes_from_compact(nodes))
+            .unwrap_or_default()
+    }
+    fn values(&self) -> Vec<SocketAddr>
+    {
+        self.values.iter()
+            .flatten()
+            .filter_map(|value| peers::from_compact_v4(value))
+            .flatten()
+            .collect()
+    }
+}
+
+/// Where the node listens, whom it bootstraps from and where its routing table is kept.
+#[derive(Debug, Clone)]
+pub struct DhtConfig
+{
+    pub bind: SocketAddr,
+    pub bootstrap: Vec<SocketAddr>,
+    pub state: Option<PathBuf>,
+}
+
+impl Default for DhtConfig
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            bind: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            bootstrap: Vec::new(),
+            state: Some(Self::default_state()),
+        }
+    }
+}
+
+impl DhtConfig
+{
+    /// `~/.bittorrent-dht`, or the temp directory without a home.
+    pub fn default_state() -> PathBuf
+    {
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join(".bittorrent-dht")
+    }
+}
+
+/// The routing table as saved between runs: our id and the compact nodes.
+#[derive(Serialize, Deserialize)]
+struct SavedTable
+{
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+#[derive(Debug)]
+struct State
+{
+    table: RoutingTable,
+    tokens: Tokens,
+    peers: PeerStore,
+}
+
+/// What an iterative lookup found: the closest nodes that answered, with their tokens, and any peers.
+#[derive(Debug, Default)]
+struct Lookup
+{
+    nodes: Vec<(SocketAddr, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+struct Candidate
+{
+    addr: SocketAddr,
+    queried: bool,
+    responded: bool,
+    token: Option<Vec<u8>>,
+}
+
+/// A mainline DHT node (BEP 5).
+#[derive(Debug)]
+pub struct Dht
+{
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    state: Mutex<State>,
+    pending: Mutex<HashMap<[u8; 2], oneshot::Sender<Krpc>>>,
+    transaction: AtomicU16,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Dht
+{
+    fn drop(&mut self)
+    {
+        if let Some(task) = self.task.lock().expect("not poisoned").take()
+        {
+            task.abort();
+        }
+    }
+}
+
+impl Dht
+{
+    /// Binds, restores the saved routing table and bootstraps.
+    pub async fn start(config: DhtConfig) -> anyhow::Result<Arc<Self>>
+    {
+        let saved = config.state.as_deref()
+            .filter(|state| state.exists())
+            .and_then(|state| match Self::load(state) {
+                Ok(saved) => Some(saved),
+                Err(e) => {
+                    eprintln!("Ignoring saved DHT state: {:#}", e);
+                    None
+                }
+            });
+        let (id, saved_nodes) = saved.unwrap_or_else(|| (NodeId::random(), Vec::new()));
+
+        let dht = Self::bind(config.bind, id).await?;
+        let mut bootstrap: Vec<_> = saved_nodes.into_iter().map(|(_, addr)| addr).collect();
+        bootstrap.extend(config.bootstrap);
+        dht.bootstrap(&bootstrap).await?;
+        Ok(dht)
+    }
+    /// A node with an empty routing table.
+    pub async fn bind(addr: SocketAddr, id: NodeId) -> anyhow::Result<Arc<Self>>
+    {
+        let socket = Arc::new(UdpSocket::bind(addr).await.context("Binding DHT socket")?);
+        let dht = Arc::new(
+            Self
+            {
+                id,
+                socket: socket.clone(),
+                state: Mutex::new(State
+                {
+                    table: RoutingTable::new(id),
+                    tokens: Tokens::new(Instant::now()),
+                    peers: PeerStore::default(),
+                }),
+                pending: Mutex::new(HashMap::new()),
+                transaction: AtomicU16::new(fastrand::u16(..)),
+                task: Mutex::new(None),
+            }
+        );
+
+        // the task only holds a weak reference, so dropping the last `Dht` ends it
+        let node = Arc::downgrade(&dht);
+        let task = tokio::spawn(async move {
+            let mut buffer = vec![0u8; 2048];
+            loop {
+                let received = socket.recv_from(&mut buffer).await;
+                let Some(node) = node.upgrade() else {
+                    break;
+                };
+                match received {
+                    Ok((len, from)) => node.receive(&buffer[..len], from).await,
+                    // an ICMP error for an earlier query to a node that is gone
+                    Err(e) if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused | ErrorKind::Interrupted) => {}
+                    Err(e) => {
+                        eprintln!("DHT socket failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        *dht.task.lock().expect("not poisoned") = Some(task);
+        Ok(dht)
+    }
+    pub fn id(&self) -> NodeId
+    {
+        self.id
+    }
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr>
+    {
+        Ok(self.socket.local_addr()?)
+    }
+    pub fn routing_table_len(&self) -> usize
+    {
+        self.state().table.len()
+    }
+    /// Asks `nodes` for our own id, then walks towards it to fill the routing table.
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> anyhow::Result<()>
+    {
+        let target = self.id.0;
+        let queries = nodes.iter().map(|addr| self.find_node(*addr, target));
+        // nodes that answer land in the routing table
+        for (addr, result) in nodes.iter().zip(futures_util::future::join_all(queries).await)
+        {
+            if let Err(e) = result
+            {
+                eprintln!("DHT bootstrap node {} failed: {:#}", addr, e);
+            }
+        }
+        anyhow::ensure!(!self.state().table.is_empty(), "No DHT bootstrap node answered");
+        self.lookup(target, false).await;
+        Ok(())
+    }
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId>
+    {
+        let response = self.query(addr, "ping", Arguments::default()).await?;
+        NodeId::from_bytes(&response.id)
+    }
+    pub async fn find_node(&self, addr: SocketAddr, target: [u8; 20]) -> anyhow::Result<Vec<(NodeId, SocketAddr)>>
+    {
+        let arguments = Arguments
+        {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        Ok(self.query(addr, "find_node", arguments).await?.nodes())
+    }
+    /// Looks up peers for `info_hash`; with a port, we announce ourselves to the closest nodes too.
+    pub async fn get_peers(&self, info_hash: [u8; 20], announce_port: Option<u16>) -> Vec<SocketAddr>
+    {
+        let lookup = self.lookup(info_hash, true).await;
+        if let Some(port) = announce_port
+        {
+            let announces = lookup.nodes.iter()
+                .filter_map(|(addr, token)| Some((*addr, token.clone()?)))
+                .map(|(addr, token)| self.announce_peer(addr, info_hash, port, token));
+            for result in futures_util::future::join_all(announces).await
+            {
+                if let Err(e) = result
+                {
+                    eprintln!("DHT announce failed: {:#}", e);
+                }
+            }
+        }
+        let mut peers = lookup.peers;
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+    pub async fn announce_peer(&self, addr: SocketAddr, info_hash: [u8; 20], port: u16, token: Vec<u8>) -> anyhow::Result<()>
+    {
+        let arguments = Arguments
+        {
+            implied_port: Some(0),
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(port),
+            token: Some(ByteBuf::from(token)),
+            ..Default::default()
+        };
+        self.query(addr, "announce_peer", arguments).await?;
+        Ok(())
+    }
+    /// Repeats `get_peers` every few minutes, for as long as the receiver is kept.
+    pub fn search(self: &Arc<Self>, info_hash: [u8; 20], announce_port: Option<u16>) -> mpsc::Receiver<Vec<SocketAddr>>
+    {
+        let (found, peers) = mpsc::channel(4);
+        let dht = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let peers = dht.get_peers(info_hash, announce_port).await;
+                if found.send(peers).await.is_err()
+                {
+                    break;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(SEARCH_INTERVAL) => {}
+                    _ = found.closed() => break,
+                }
+            }
+        });
+        peers
+    }
+    pub fn save(&self, path: &Path) -> anyhow::Result<()>
+    {
+        let nodes: Vec<Node> = self.state().table.nodes().collect();
+        let saved = SavedTable
+        {
+            id: ByteBuf::from(self.id.0.to_vec()),
+            nodes: ByteBuf::from(nodes_to_compact(&nodes)),
+        };
+        std::fs::write(path, serde_bencode::to_bytes(&saved)?)
+            .with_context(|| format!("Writing DHT state to {}", path.display()))
+    }
+    pub fn load(path: &Path) -> anyhow::Result<(NodeId, Vec<(NodeId, SocketAddr)>)>
+    {
+        let bytes = std::fs::read(path).with_context(|| format!("Reading DHT state from {}", path.display()))?;
+        let saved: SavedTable = serde_bencode::from_bytes(&bytes).context("Parsing DHT state")?;
+        let nodes = nodes_from_compact(&saved.nodes).context("Malformed saved nodes")?;
+        Ok((NodeId::from_bytes(&saved.id)?, nodes))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State>
+    {
+        self.state.lock().expect("not poisoned")
+    }
+    /// Walks towards `target`, querying the closest unqueried nodes `ALPHA` at a time.
+    async fn lookup(&self, target: [u8; 20], get_peers: bool) -> Lookup
+    {
+        let mut candidates: BTreeMap<[u8; 20], Candidate> = self.state().table.closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id.distance(&target), Candidate { addr: node.addr, queried: false, responded: false, token: None }))
+            .collect();
+        let mut peers = Vec::new();
+
+        loop {
+            let round: Vec<_> = candidates.iter_mut()
+                .take(K)
+                .filter(|(_, candidate)| !candidate.queried)
+                .take(ALPHA)
+                .map(|(distance, candidate)| {
+                    candidate.queried = true;
+                    (*distance, candidate.addr)
+                })
+                .collect();
+            if round.is_empty()
+            {
+                break;
+            }
+
+            let queries = round.iter().map(|(_, addr)| {
+                let method = if get_peers { "get_peers" } else { "find_node" };
+                let arguments = Arguments
+                {
+                    info_hash: get_peers.then(|| ByteBuf::from(target.to_vec())),
+                    target: (!get_peers).then(|| ByteBuf::from(target.to_vec())),
+                    ..Default::default()
+                };
+                self.query(*addr, method, arguments)
+            });
+            for ((distance, _), result) in round.iter().zip(futures_util::future::join_all(queries).await)
+            {
+                let Ok(response) = result else {
+                    candidates.remove(distance);
+                    continue;
+                };
+                if let Some(candidate) = candidates.get_mut(distance)
+                {
+                    candidate.responded = true;
+                    candidate.token = response.token.as_ref().map(|token| token.to_vec());
+                }
+                peers.extend(response.values());
+                for (id, addr) in response.nodes()
+                {
+                    if id != self.id
+                    {
+                        candidates.entry(id.distance(&target))
+                            .or_insert(Candidate { addr, queried: false, responded: false, token: None });
+                    }
+                }
+            }
+        }
+
+        Lookup
+        {
+            nodes: candidates.into_values()
+                .filter(|candidate| candidate.responded)
+                .take(K)
+                .map(|candidate| (candidate.addr, candidate.token))
+                .collect(),
+            peers,
+        }
+    }
+    async fn query(&self, addr: SocketAddr, method: &str, mut arguments: Arguments) -> anyhow::Result<Response>
+    {
+        arguments.id = ByteBuf::from(self.id.0.to_vec());
+        let transaction = self.transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        let message = Krpc
+        {
+            a: Some(arguments),
+            q: Some(method.to_string()),
+            t: ByteBuf::from(transaction.to_vec()),
+            y: "q".to_string(),
+            ..Default::default()
+        };
+
+        let (answer, answered) = oneshot::channel();
+        self.pending.lock().expect("not poisoned").insert(transaction, answer);
+        let sent = self.socket.send_to(&serde_bencode::to_bytes(&message)?, addr).await;
+        let reply = match sent {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, answered).await.ok().and_then(Result::ok),
+            Err(_) => None,
+        };
+        let Some(reply) = reply else {
+            self.pending.lock().expect("not poisoned").remove(&transaction);
+            self.state().table.remove(addr);
+            anyhow::bail!("DHT node {} did not answer {}", addr, method);
+        };
+
+        if let Some((code, reason)) = reply.error()
+        {
+            anyhow::bail!("DHT node {} failed {}: {} {}", addr, method, code, reason);
+        }
+        let response = reply.r.with_context(|| format!("DHT node {} sent no response", addr))?;
+        let id = NodeId::from_bytes(&response.id)?;
+        self.state().table.insert(id, addr, Instant::now());
+        Ok(response)
+    }
+    async fn receive(&self, bytes: &[u8], from: SocketAddr)
+    {
+        let Ok(message) = serde_bencode::from_bytes::<Krpc>(bytes) else {
+            return;
+        };
+        match message.y.as_str() {
+            "q" => {
+                let reply = self.answer(&message, from);
+                if let Ok(reply) = serde_bencode::to_bytes(&reply)
+                {
+                    let _ = self.socket.send_to(&reply, from).await;
+                }
+            }
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+                    return;
+                };
+                let answer = self.pending.lock().expect("not poisoned").remove(&transaction);
+                if let Some(answer) = answer
+                {
+                    let _ = answer.send(message);
+                }
+            }
+            _ => {}
+        }
+    }
+    fn answer(&self, query: &Krpc, from: SocketAddr) -> Krpc
+    {
+        let mut reply = Krpc
+        {
+            t: query.t.clone(),
+            y: "r".to_string(),
+            ..Default::default()
+        };
+        match self.respond(query, from) {
+            Ok(response) => reply.r = Some(response),
+            Err((code, reason)) => {
+                reply.y = "e".to_string();
+                reply.e = Some(vec![ErrorPart::Code(code), ErrorPart::Message(ByteBuf::from(reason.as_bytes()))]);
+            }
+        }
+        reply
+    }
+    fn respond(&self, query: &Krpc, from: SocketAddr) -> Result<Response, (i64, &'static str)>
+    {
+        let arguments = query.a.as_ref().ok_or((PROTOCOL_ERROR, "Missing arguments"))?;
+        let id = NodeId::from_bytes(&arguments.id).map_err(|_| (PROTOCOL_ERROR, "Bad node id"))?;
+        let hash = |bytes: &Option<ByteBuf>| -> Result<[u8; 20], (i64, &'static str)> {
+            bytes.as_deref()
+                .and_then(|bytes| bytes.as_slice().try_into().ok())
+                .ok_or((PROTOCOL_ERROR, "Bad or missing hash"))
+        };
+
+        let now = Instant::now();
+        let mut state = self.state();
+        state.table.insert(id, from, now);
+        let mut response = Response
+        {
+            id: ByteBuf::from(self.id.0.to_vec()),
+            ..Default::default()
+        };
+        match query.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = hash(&arguments.target)?;
+                response.nodes = Some(ByteBuf::from(nodes_to_compact(&state.table.closest(&target, K))));
+            }
+            Some("get_peers") => {
+                let info_hash = hash(&arguments.info_hash)?;
+                response.token = Some(ByteBuf::from(state.tokens.token(from.ip(), now)));
+                let peers = state.peers.get(&info_hash, now);
+                if peers.is_empty()
+                {
+                    response.nodes = Some(ByteBuf::from(nodes_to_compact(&state.table.closest(&info_hash, K))));
+                } else {
+                    response.values = Some(peers.iter().map(|peer| ByteBuf::from(peers::to_compact_v4(&[*peer]))).collect());
+                }
+            }
+            Some("announce_peer") => {
+                let info_hash = hash(&arguments.info_hash)?;
+                let token = arguments.token.as_deref().ok_or((PROTOCOL_ERROR, "Missing token"))?;
+                if !state.tokens.is_valid(from.ip(), token, now)
+                {
+                    return Err((PROTOCOL_ERROR, "Bad token"));
+                }
+                let port = match arguments.implied_port {
+                    Some(1) => from.port(),
+                    _ => arguments.port.ok_or((PROTOCOL_ERROR, "Missing port"))?,
+                };
+                state.peers.add(info_hash, SocketAddr::new(from.ip(), port), now);
+            }
+            _ => return Err((METHOD_UNKNOWN, "Method Unknown")),
+        }
+        Ok(response)
+    }
+}
+
+/// Resolves `host:port` names, keeping the IPv4 addresses the DHT can use.
+pub async fn resolve(nodes: &[String]) -> Vec<SocketAddr>
+{
+    let mut addrs = Vec::new();
+    for node in nodes
+    {
+        match tokio::net::lookup_host(node.as_str()).await {
+            Ok(found) => addrs.extend(found.filter(SocketAddr::is_ipv4)),
+            Err(e) => eprintln!("Fail to resolve DHT node {}: {}", node, e),
+        }
+    }
+    addrs
+}
+
+
+#[cfg(test)]
+mod test_dht
+{
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use serde_bytes::ByteBuf;
+    use crate::dht::{Arguments, Dht, K, Krpc, NodeId, RoutingTable, STALE, TOKEN_ROTATION, Tokens};
+
+    #[test]
+    fn encodes_krpc_like_bep_5()
+    {
+        let ping = Krpc
+        {
+            a: Some(Arguments { id: ByteBuf::from(b"abcdefghij0123456789".to_vec()), ..Default::default() }),
+            q: Some("ping".to_string()),
+            t: ByteBuf::from(b"aa".to_vec()),
+            y: "q".to_string(),
+            ..Default::default()
+        };
+
+        let bytes = serde_bencode::to_bytes(&ping).unwrap();
+
+        assert_eq!(bytes, b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe");
+        let error: Krpc = serde_bencode::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(error.error(), Some((201, "A Generic Error Ocurred".to_string())));
+    }
+
+    #[test]
+    fn keeps_k_nodes_per_bucket()
+    {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        let now = Instant::now();
+        let far = |i: u8| {
+            let mut id = [0; 20];
+            id[0] = 0x80;
+            id[19] = i;
+            NodeId(id)
+        };
+        let addr = |i: u8| SocketAddr::from(([127, 0, 0, 1], 1000 + i as u16));
+
+        for i in 0..K as u8
+        {
+            assert!(table.insert(far(i), addr(i), now));
+        }
+        assert!(!table.insert(far(100), addr(100), now), "Bucket is full of good nodes");
+        assert!(table.insert(far(100), addr(100), now + STALE), "A stale node makes room");
+        assert!(!table.insert(NodeId([0; 20]), addr(0), now), "Our own id is not a node");
+
+        let mut near = [0; 20];
+        near[19] = 1;
+        table.insert(NodeId(near), addr(200), now);
+        assert_eq!(table.closest(&[0; 20], 1)[0].id, NodeId(near), "Closest by XOR distance");
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn accepts_tokens_for_two_rotations()
+    {
+        let now = Instant::now();
+        let mut tokens = Tokens::new(now);
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let token = tokens.token(ip, now);
+
+        assert!(tokens.is_valid(ip, &token, now));
+        assert!(!tokens.is_valid("10.0.0.2".parse().unwrap(), &token, now), "Token is bound to the IP");
+        assert!(tokens.is_valid(ip, &token, now + TOKEN_ROTATION), "Previous secret still counts");
+        assert!(!tokens.is_valid(ip, &token, now + 2 * TOKEN_ROTATION), "Token expired");
+    }
+
+    async fn swarm(size: usize) -> Vec<Arc<Dht>>
+    {
+        let mut nodes: Vec<Arc<Dht>> = Vec::new();
+        for _ in 0..size
+        {
+            let node = Dht::bind("127.0.0.1:0".parse().unwrap(), NodeId::random()).await.unwrap();
+            if let Some(first) = nodes.first()
+            {
+                node.bootstrap(&[first.local_addr().unwrap()]).await.unwrap();
+            }
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn finds_announced_peers()
+    {
+        let nodes = swarm(12).await;
+        let info_hash = [7; 20];
+
+        let announced = nodes[3].get_peers(info_hash, Some(51413)).await;
+        assert!(announced.is_empty(), "Nobody had peers yet");
+
+        let found = nodes[10].get_peers(info_hash, None).await;
+        assert_eq!(found, vec!["127.0.0.1:51413".parse().unwrap()], "Should find the announced peer");
+
+        let mut search = nodes[7].search(info_hash, None);
+        let found = tokio::time::timeout(Duration::from_secs(5), search.recv()).await.unwrap().unwrap();
+        assert_eq!(found.len(), 1, "Search should report the peer");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_tokens()
+    {
+        let nodes = swarm(2).await;
+
+        let error = nodes[1].announce_peer(nodes[0].local_addr().unwrap(), [1; 20], 1, b"nope".to_vec()).await.unwrap_err();
+
+        assert!(error.to_string().contains("203"), "Should be a protocol error: {}", error);
+    }
+
+    #[tokio::test]
+    async fn persists_routing_table()
+    {
+        let nodes = swarm(4).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht");
+
+        nodes[2].save(&path).unwrap();
+        let (id, saved) = Dht::load(&path).unwrap();
+
+        assert_eq!(id, nodes[2].id(), "Id should survive");
+        assert_eq!(saved.len(), nodes[2].routing_table_len(), "Every node should be saved");
+        assert!(saved.iter().any(|(id, _)| *id == nodes[0].id()), "Bootstrap node should be known");
+    }
+}
@@ -1,145 +1,418 @@
-use std::collections::BinaryHeap;
-use std::net::SocketAddrV4;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::iter::Zip;
+use std::path::{Path, PathBuf};
 use std::slice::Iter;
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Context;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use sha1::{Sha1, Digest};
-use tokio::task::JoinSet;
-use crate::peer::{Peer, PieceMessage};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio::sync::{mpsc, watch};
+use crate::dht::Dht;
+use crate::extension;
+use crate::extension::ExtensionRegistry;
+use crate::peer::Peer;
+use crate::pex::PexExtension;
+use crate::picker::{Picker, Progress};
+use crate::pipeline;
+use crate::resume;
+use crate::resume::ResumeState;
 use crate::piece::Piece;
-use crate::torrent::{File, Torrent};
-use crate::tracker::TrackerResponse;
+use crate::seed::{SeedTorrent, Seeder};
+use crate::storage::Storage;
+use crate::torrent::{File, Keys, Torrent};
+use crate::tracker::{TrackerResponse, TrackerSession, Trackers, TransferStats};
+use crate::tracker::peers::Peers;
 
+/// A finished download: the torrent's files, read back from where they were written.
 pub struct Downloaded
 {
-    bytes: Vec<u8>,
+    storage: Arc<Storage>,
     file: Vec<File>,
-
 }
 
-pub(crate) async fn all(torrent: &Torrent, peer_id: String) -> anyhow::Result<Downloaded>
+impl Downloaded
 {
-    let tracker_response = TrackerResponse::query(torrent, peer_id).await
-        .context("Query tracker for peer info")?;
-
-    let mut peer_list = {
-        let mut peer_list = Vec::new();
+    fn new(torrent: &Torrent, storage: Arc<Storage>) -> Self
+    {
+        let file = match &torrent.info.keys
+        {
+            Keys::SingleFile { length } => vec![File { length: *length, path: vec![torrent.info.name.clone()] }],
+            Keys::MultiFile { files } => files.clone(),
+        };
+        Self { storage, file }
+    }
+}
 
-        let info_hash = torrent.info_hash()?;
-        let mut stream = futures_util::stream::iter(tracker_response.peers.0.iter()).map(
-            |peer|
+// ut_pex batches waiting to be connected; more are dropped
+const PEX_BACKLOG: usize = 16;
+// finished pieces waiting to be written; more hold the connections up
+const VERIFIED_BACKLOG: usize = 4;
+const PROGRESS_BACKLOG: usize = 64;
+// how often new peers are connected while downloading
+const REFILL_INTERVAL: Duration = Duration::from_secs(5);
+// how long a download without tracker peers waits for the first DHT results
+pub(crate) const DHT_WAIT: Duration = Duration::from_secs(30);
+// how long a download whose peers lack what is missing waits for discovery to bring others
+const DISCOVERY_GRACE: Duration = Duration::from_secs(60);
 
-                Peer::new(*peer, info_hash)
-        ).buffer_unordered(5/*TODO user config**/);
-        while let Some(peer) = stream.next().await {
-            match peer {
-                Ok(peer) => peer_list.push(peer),
+/// Where new peers come from while downloading: tracker re-announces and DHT searches.
+struct PeerSources
+{
+    session: Option<TrackerSession>,
+    dht: Option<mpsc::Receiver<Vec<SocketAddr>>>,
+    stats: Arc<TransferStats>,
+}
 
-                Err(ref e) =>
-                    eprintln!("Fail to connect ot peer: {:?} with error: {}", peer, e)
+impl PeerSources
+{
+    fn new_peers(&mut self) -> Vec<SocketAddr>
+    {
+        let mut new_peers = self.session.as_mut().map(TrackerSession::new_peers).unwrap_or_default();
+        if let Some(dht) = &mut self.dht
+        {
+            while let Ok(peers) = dht.try_recv()
+            {
+                new_peers.extend(peers);
             }
         }
-        peer_list
-    };
-    let mut pieces = BinaryHeap::new();
-    let mut no_piece = Vec::new();
-    for piece_id in 0..torrent.info.pieces.0.len()
+        new_peers
+    }
+    async fn completed(&self)
     {
-        let piece = Piece::new(piece_id as u64, torrent, &peer_list);
-
-        if piece.peers().is_empty()
+        if let Some(session) = &self.session
         {
-            no_piece.push(piece)
-        } else {
-            pieces.push(piece)
+            session.completed().await;
+        }
+    }
+    async fn stop(self)
+    {
+        if let Some(session) = self.session
+        {
+            session.stop().await;
         }
     }
-    /// TODO!
-    assert!(no_piece.is_empty());
+}
+
+/// Downloads the torrent into `output`, laid out as `Storage` describes it, with up to
+/// `max_requests` blocks requested from each peer at once.
+pub(crate) async fn all(torrent: &Torrent, peer_id: String, output: &Path, extra_peers: &[SocketAddr], dht: Option<&Arc<Dht>>, max_requests: usize) -> anyhow::Result<Downloaded>
+{
+    let resume = ResumeState::path(output);
+    let (storage, have) = {
+        let (torrent, output, resume) = (torrent.clone(), output.to_path_buf(), resume.clone());
+        tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+            let storage = Storage::new(&torrent, &output)?;
+            let fresh = !storage.has_data();
+            storage.allocate()?;
+            let have = resume::restore(&torrent, &storage, &resume, fresh)?;
+            Ok((Arc::new(storage), have))
+        }).await??
+    };
+    if have.iter().all(|piece| *piece)
+    {
+        return Ok(Downloaded::new(torrent, storage));
+    }
 
-    while let Some(piece) = pieces.pop() {
-        let nblocks = ((piece.length() + Peer::BLOCK_MAX as usize - 1) / Peer::BLOCK_MAX as usize) as u32;
-        let mut pieces: Vec<u8> = Vec::with_capacity(nblocks as usize);
+    let left = (0..have.len()).filter(|index| !have[*index]).map(|index| torrent.piece_len(index)).sum();
+    let stats = Arc::new(TransferStats::new(left));
+    // peers may fetch what we already have from the port we advertise, and hear about new pieces
+    let seed = Arc::new(SeedTorrent::new(torrent.clone(), storage.clone(), have, stats.clone())?);
+    let seeder = Arc::new(Seeder::new());
+    seeder.add(seed.clone());
+    let listener = listen().await?;
+    let port = listener.local_addr()?.port();
+    let seeding = tokio::spawn(async move { seeder.listen(listener).await });
 
-        let peers_with_piece: Vec<_> = peer_list
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(i, peer)| piece.peers().contains(&i).then_some(peer))
-            .collect();
+    // a trackerless torrent, or dead trackers, can still find peers in the DHT or the ones we were given
+    let started = if Trackers::new(torrent).tiers().is_empty()
+    {
+        None
+    } else {
+        Some(TrackerSession::start(torrent, peer_id, port, stats.clone()).await)
+    };
+    let (session, mut tracker_response) = match started {
+        Some(Ok((session, response))) => (Some(session), response),
+        None => (None, TrackerResponse::default()),
+        Some(Err(e)) if dht.is_some() || !extra_peers.is_empty() => {
+            eprintln!("Continuing without trackers: {:#}", e);
+            (None, TrackerResponse::default())
+        }
+        Some(Err(e)) => {
+            seeding.abort();
+            return Err(e.context("Query tracker for peer info"));
+        }
+    };
+    tracker_response.merge(TrackerResponse { peers: Peers(extra_peers.to_vec()), ..Default::default() });
 
-        let (submit, tasks  ) = kanal::bounded_async::<u32>(nblocks as usize);
-        for block in 0..nblocks
+    let info_hash = torrent.info_hash()?;
+    let mut sources = PeerSources
+    {
+        session,
+        dht: dht.map(|dht| dht.search(info_hash, None)),
+        stats,
+    };
+    if tracker_response.peers.0.is_empty()
+    {
+        if let Some(search) = &mut sources.dht
         {
-            submit.send(block).await?;
+            if let Ok(Some(peers)) = tokio::time::timeout(DHT_WAIT, search.recv()).await
+            {
+                tracker_response.peers.0.extend(peers);
+            }
+        }
+    }
+
+    let downloaded = tokio::select! {
+        downloaded = download_all(torrent, &mut sources, &tracker_response.peers.0, &seed, port, &resume, max_requests) => downloaded,
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("Download interrupted")),
+    };
+    seeding.abort();
+    // trackers hear `stopped` whether the download worked or not
+    sources.stop().await;
+    downloaded?;
+    Ok(Downloaded::new(torrent, storage))
+}
+
+/// Our advertised port, or any free one when another client holds it.
+async fn listen() -> anyhow::Result<TcpListener>
+{
+    match TcpListener::bind(("0.0.0.0", extension::LISTEN_PORT)).await {
+        Ok(listener) => Ok(listener),
+        Err(e) => {
+            eprintln!("Port {} is unavailable ({}), listening on another one", extension::LISTEN_PORT, e);
+            TcpListener::bind(("0.0.0.0", 0)).await.context("Listening for peers")
         }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks as usize);
-        let mut participants =  futures_util::stream::futures_unordered::FuturesUnordered::new();
-        let piece_size = piece.length();
-        for peer in peers_with_piece
+    }
+}
+
+/// Downloads the pieces `seed` is missing into its storage. Each one is announced to the peers
+/// connected to us and saved to `resume` once it is on disk. Peers hear that we listen on `port`.
+async fn download_all(torrent: &Torrent, sources: &mut PeerSources, peers: &[SocketAddr], seed: &SeedTorrent, port: u16, resume: &Path, max_requests: usize) -> anyhow::Result<()>
+{
+    let info_hash = torrent.info_hash()?;
+    let storage = seed.storage();
+    let have = seed.have();
+    // peers that other peers told us about over ut_pex
+    let (found, mut discovered) = mpsc::channel(PEX_BACKLOG);
+    let mut tried: HashSet<SocketAddr> = peers.iter().copied().collect();
+    let peer_list = connect(peers, torrent, port, Some(&found), max_requests).await?;
+    anyhow::ensure!(!peer_list.is_empty(), "Could not connect to any peer");
+
+    let pieces: Vec<_> = (0..have.len())
+        .filter(|piece_id| !have[*piece_id])
+        .map(|piece_id| Piece::new(piece_id as u64, torrent, &peer_list))
+        .collect();
+    let (joined, joining) = mpsc::channel(1);
+    let (verified, mut pieces_done) = mpsc::channel(VERIFIED_BACKLOG);
+    let fetching = fetch(torrent, pieces, peer_list, joining, verified, Some(seed));
+
+    let stats = sources.stats.clone();
+    let storing = async {
+        while let Some((index, piece)) = pieces_done.recv().await
         {
-            participants.push(peer.participate(
-                piece.index() as u32,
-                piece_size as u32,
-                nblocks as u32,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone())
-            );
+            let len = piece.len();
+            let (writer, owned) = (storage.clone(), torrent.clone());
+            tokio::task::spawn_blocking(move || writer.write_piece(&owned, index, &piece)).await?
+                .with_context(|| format!("Writing piece {}", index))?;
+            stats.piece_done(len);
+            seed.piece_done(index as u32);
+            // losing the state only costs a rehash on restart
+            let (writer, state, path) = (storage.clone(), seed.have(), resume.to_path_buf());
+            let saved = tokio::task::spawn_blocking(move || ResumeState::capture(info_hash, &writer, &state)?.save(&path)).await?;
+            if let Err(e) = saved
+            {
+                eprintln!("Fail to save resume state: {:#}", e);
+            }
         }
+        anyhow::Ok(())
+    };
 
-        let mut bytes_recv = 0;
-        let mut all_blocks = vec![0u8; piece.length()];
+    // connects what the re-announces, the DHT and ut_pex bring until the download is over
+    let discovering = async {
         loop {
             tokio::select! {
-                joined = participants.next() =>
-                {
-                    match joined {
-                    None => { }, // the are no peers
-                    Some(Ok(_)) => {} // the peer gave up
-                    Some(Err(_)) => {} //The peer failed
-                }
-                    },
-                piece = done.recv() =>
-                {
-                    if let Some(piece) = &piece {
-                    let piece = PieceMessage::from_bytes(&piece.payload);
-                    bytes_recv += piece.block().len();
-                   all_blocks[piece.begin() as usize..].copy_from_slice(piece.block())
-                    }
-                else {
-
-                    anyhow::ensure!(piece_size == bytes_recv, "Not enough bytes");
-                    // have recieved every piece or no peers left
-                    break;
-                }
-                }
+                _ = tokio::time::sleep(REFILL_INTERVAL) => {}
+                _ = joined.closed() => return anyhow::Ok(()),
+            }
+            let mut new_peers = sources.new_peers();
+            while let Ok(found) = discovered.try_recv()
+            {
+                new_peers.extend(found);
+            }
+            new_peers.retain(|addr| tried.insert(*addr));
+            if new_peers.is_empty()
+            {
+                continue;
+            }
+            let peers = tokio::select! {
+                peers = connect(&new_peers, torrent, port, Some(&found), max_requests) => peers?,
+                _ = joined.closed() => return Ok(()),
+            };
+            if joined.send(peers).await.is_err()
+            {
+                return Ok(());
             }
         }
+    };
 
-        drop(done);
-        drop(finish);
-        drop(submit);
-
-        let mut sha = Sha1::new();
-        sha.update(all_blocks);
-        let hash:[u8;20] = sha.finalize().try_into().context("Trying to get hash from blocks")?;
-        anyhow::ensure!(hash == piece.hash(),"Got wrong hash from blocks");
+    let (fetched, stored, discovered) = tokio::join!(fetching, storing, discovering);
+    fetched?;
+    stored?;
+    discovered?;
+    sources.completed().await;
+    Ok(())
+}
 
+pub(crate) async fn one(torrent: &Torrent, piece_i: usize, peer_id: String) -> anyhow::Result<Vec<u8>>
+{
+    anyhow::ensure!(piece_i < torrent.info.pieces.0.len(), "Torrent has no piece {}", piece_i);
 
+    let tracker_response = TrackerResponse::query(torrent, peer_id).await
+        .context("Query tracker for peer info")?;
+    if let Some(warning) = &tracker_response.warning_message
+    {
+        eprintln!("Tracker warning: {}", warning);
     }
-    todo!()
+
+    let peer_list = connect(&tracker_response.peers.0, torrent, extension::LISTEN_PORT, None, pipeline::DEFAULT_MAX_REQUESTS).await?;
+    anyhow::ensure!(!peer_list.is_empty(), "Could not connect to any peer");
+    let piece = Piece::new(piece_i as u64, torrent, &peer_list);
+    anyhow::ensure!(!piece.peers().is_empty(), "No connected peer has piece {}", piece_i);
+
+    // nobody joins later
+    let (_, joining) = mpsc::channel(1);
+    let (verified, mut pieces_done) = mpsc::channel(1);
+    let (fetched, piece) = tokio::join!(fetch(torrent, vec![piece], peer_list, joining, verified, None), pieces_done.recv());
+    fetched.with_context(|| format!("Downloading piece {}", piece_i))?;
+    Ok(piece.context("Piece was not downloaded")?.1)
 }
 
-pub(crate) async fn download_piece(peers: &[SocketAddrV4], piece_hash: [u8; 20], piece_len: usize) -> anyhow::Result<Downloaded>
+/// Connects to `peers`, telling them we listen on `port`; with `pex`, they also share their neighbours over ut_pex.
+async fn connect(peers: &[SocketAddr], torrent: &Torrent, port: u16, pex: Option<&mpsc::Sender<Vec<SocketAddr>>>, max_requests: usize) -> anyhow::Result<Vec<Peer>>
 {
-    todo!()
+    let (info_hash, pieces) = (torrent.info_hash()?, torrent.info.pieces.0.len());
+    let mut peer_list = Vec::new();
+
+    let mut stream = futures_util::stream::iter(peers.iter()).map(
+        |peer|
+            {
+                let mut extensions = ExtensionRegistry::new().with_port(port);
+                if let Some(found) = pex
+                {
+                    extensions.register(Box::new(PexExtension::new(found.clone())));
+                }
+                Peer::new(*peer, info_hash, pieces, extensions, max_requests)
+            }
+    ).buffer_unordered(5/*TODO user config**/);
+    while let Some(peer) = stream.next().await {
+        match peer {
+            Ok(peer) => peer_list.push(peer),
+
+            Err(ref e) =>
+                eprintln!("Fail to connect ot peer: {:?} with error: {}", peer, e)
+        }
+    }
+    Ok(peer_list)
 }
 
-pub(crate) async fn download_piece_block(peer: &SocketAddrV4, piece_hash: [u8; 20], piece_len: usize) -> anyhow::Result<Downloaded>
+/// Downloads `pieces` from `peers`, and from the peers `joining` brings along the way, with
+/// every connection asking for blocks of any piece it has. The peers in `pieces` are counted
+/// by their position in `peers`. Each piece goes to `verified` once its hash matches. With `seed`,
+/// its choker ranks the peers by what they sent us. When no connected peer has what is missing,
+/// `joining` gets `DISCOVERY_GRACE` to bring one before the download fails.
+async fn fetch(
+    torrent: &Torrent,
+    pieces: Vec<Piece>,
+    peers: Vec<Peer>,
+    mut joining: mpsc::Receiver<Vec<Peer>>,
+    verified: mpsc::Sender<(usize, Vec<u8>)>,
+    seed: Option<&SeedTorrent>,
+) -> anyhow::Result<()>
 {
-    todo!()
-}
+    let mut pieces_left = pieces.len();
+    let picker = Picker::new(pieces, 0..peers.len());
+    let (progress, mut reported) = mpsc::channel(PROGRESS_BACKLOG);
+    let mut addrs: Vec<_> = peers.iter().map(Peer::addr).collect();
+    let (connected, connected_rx) = watch::channel(addrs.clone());
+
+    let run = |id: usize, mut peer: Peer| {
+        let (picker, progress, connected) = (&picker, progress.clone(), connected_rx.clone());
+        async move {
+            let downloaded = peer.download(id, picker, &progress, &connected, seed).await;
+            (id, peer.addr(), downloaded)
+        }
+    };
+    let mut next_id = peers.len();
+    let mut participants: FuturesUnordered<_> = peers.into_iter().enumerate().map(|(id, peer)| run(id, peer)).collect();
+    let mut discovering = true;
+    let mut starving_since: Option<Instant> = None;
+
+    while pieces_left > 0
+    {
+        let give_up = starving_since.map_or_else(Instant::now, |since| since + DISCOVERY_GRACE);
+        tokio::select! {
+            Some((id, addr, downloaded)) = participants.next() => {
+                picker.remove_peer(id);
+                addrs.retain(|connected| *connected != addr);
+                connected.send_replace(addrs.clone());
+                if let Err(e) = downloaded
+                {
+                    eprintln!("Peer dropped out: {:#}", e);
+                }
+            }
+            Some(progress) = reported.recv() => {
+                if let Progress::Piece { index, data } = progress
+                {
+                    let hash: [u8; 20] = Sha1::digest(&data).into();
+                    if hash == torrent.info.pieces.0[index as usize]
+                    {
+                        picker.passed(index);
+                        verified.send((index as usize, data)).await.context("Handing out piece")?;
+                        pieces_left -= 1;
+                    } else {
+                        // the picker asks for the piece again and drops the peer that sent it, once it knows which
+                        match picker.failed(index) {
+                            Some(_) => eprintln!("Piece {} failed its hash check, dropping the peer that sent it", index),
+                            None => eprintln!("Piece {} failed its hash check, asking a single peer for it", index),
+                        }
+                    }
+                }
+            }
+            joined = joining.recv(), if discovering => {
+                match joined {
+                    Some(joined) => {
+                        for peer in joined
+                        {
+                            picker.add_peer(next_id, peer.bitfield());
+                            addrs.push(peer.addr());
+                            participants.push(run(next_id, peer));
+                            next_id += 1;
+                        }
+                        connected.send_replace(addrs.clone());
+                    }
+                    None => discovering = false,
+                }
+            }
+            _ = tokio::time::sleep_until(give_up), if starving_since.is_some() => {}
+        }
 
+        if !participants.is_empty() && !picker.is_stalled()
+        {
+            starving_since = None;
+        } else if !discovering || starving_since.is_some_and(|since| since.elapsed() >= DISCOVERY_GRACE) {
+            anyhow::ensure!(!participants.is_empty(), "No peers left, {} pieces missing", pieces_left);
+            anyhow::bail!("No connected peer has {} of the pieces", picker.pieces_left());
+        } else {
+            // discovery may still bring a peer with what is missing
+            starving_since.get_or_insert_with(Instant::now);
+        }
+    }
+    Ok(())
+}
 
 impl<'a> IntoIterator for &'a Downloaded
 {
@@ -153,20 +426,17 @@ impl<'a> IntoIterator for &'a Downloaded
 
 pub struct DownloadedIter<'a>
 {
-    downloaded: &'a Downloaded,
-    files: Iter<'a, File>,
-    offset: usize,
+    files: Zip<Iter<'a, File>, Box<dyn Iterator<Item = &'a Path> + 'a>>,
 }
 
 impl<'a> DownloadedIter<'a>
 {
     pub fn new(downloaded: &'a Downloaded) -> Self
     {
+        let paths: Box<dyn Iterator<Item = &'a Path> + 'a> = Box::new(downloaded.storage.paths());
         Self
         {
-            downloaded,
-            files: downloaded.file.iter(),
-            offset: 0,
+            files: downloaded.file.iter().zip(paths),
         }
     }
 }
@@ -176,22 +446,22 @@ impl<'a> Iterator for DownloadedIter<'a>
     type Item = DownloadedFile<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let file = self.files.next()?;
-        let bytes = &self.downloaded.bytes[self.offset..][..file.length];
+        let (file, disk_path) = self.files.next()?;
         Some(
             DownloadedFile
             {
                 file,
-                bytes,
+                disk_path,
             }
         )
     }
 }
 
+/// One file of a finished download; its bytes stay on disk until asked for.
 pub struct DownloadedFile<'a>
 {
     file: &'a File,
-    bytes: &'a [u8],
+    disk_path: &'a Path,
 }
 
 impl<'a> DownloadedFile<'a>
@@ -200,8 +470,210 @@ impl<'a> DownloadedFile<'a>
     {
         &self.file.path
     }
-    pub fn bytes(&self) -> &[u8]
+    pub fn relative_path(&self) -> anyhow::Result<PathBuf>
+    {
+        self.file.relative_path()
+    }
+    pub fn disk_path(&self) -> &Path
+    {
+        self.disk_path
+    }
+    pub fn len(&self) -> usize
+    {
+        self.file.length
+    }
+    pub fn is_empty(&self) -> bool
+    {
+        self.file.length == 0
+    }
+    pub fn read(&self) -> anyhow::Result<Vec<u8>>
+    {
+        std::fs::read(self.disk_path).with_context(|| format!("Reading {}", self.disk_path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test_downloaded_iter
+{
+    use std::sync::Arc;
+    use crate::downloaded::Downloaded;
+    use crate::storage::Storage;
+    use crate::torrent::fixture::multi_file;
+
+    #[test]
+    fn reads_files_from_disk()
+    {
+        let data: Vec<u8> = (0..10).collect();
+        let torrent = multi_file(&[("a", 3), ("empty", 0), ("dir/b", 7)], &data, 16);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&torrent, dir.path()).unwrap();
+        storage.write(0, &data).unwrap();
+
+        let downloaded = Downloaded::new(&torrent, Arc::new(storage));
+        let files: Vec<_> = downloaded.into_iter().collect();
+
+        assert_eq!(files.len(), 3, "Wrong number of files");
+        assert_eq!(files[0].read().unwrap(), [0, 1, 2], "Wrong first file");
+        assert!(files[1].is_empty() && files[1].read().unwrap().is_empty(), "Empty file should have no bytes");
+        assert_eq!(files[2].read().unwrap(), [3, 4, 5, 6, 7, 8, 9], "Wrong last file");
+        assert_eq!(files[2].len(), 7);
+        assert_eq!(files[2].relative_path().unwrap(), std::path::Path::new("dir").join("b"));
+        assert_eq!(files[2].disk_path(), dir.path().join("root").join("dir").join("b"));
+    }
+}
+
+#[cfg(test)]
+mod test_download_from_seeder
+{
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use crate::downloaded::{all, connect, download_all, fetch, PeerSources};
+    use crate::extension;
+    use crate::piece::Piece;
+    use crate::pipeline;
+    use crate::resume::{restore, ResumeState};
+    use crate::seed::{SeedTorrent, Seeder};
+    use crate::storage::Storage;
+    use crate::torrent::fixture::single_file;
+    use crate::torrent::Torrent;
+    use crate::tracker::TransferStats;
+
+    // three pieces of two and a half blocks each, the last one short
+    const PIECE_LENGTH: usize = 40_000;
+
+    fn torrent(data: &[u8]) -> Torrent
+    {
+        single_file(data, PIECE_LENGTH)
+    }
+
+    /// Seeds `data` on loopback, offering only the pieces in `have`.
+    async fn seed(torrent: &Torrent, data: &[u8], have: Vec<bool>, stats: Arc<TransferStats>) -> (std::net::SocketAddr, tokio::task::JoinHandle<anyhow::Result<()>>, tempfile::TempDir)
+    {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), data).unwrap();
+        let storage = Storage::new(torrent, &dir.path().join("a")).unwrap();
+        let seeder = Arc::new(Seeder::new());
+        seeder.add(Arc::new(SeedTorrent::new(torrent.clone(), storage, have, stats).unwrap()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (addr, tokio::spawn(async move { seeder.listen(listener).await }), dir)
+    }
+
+    #[tokio::test]
+    async fn downloads_every_piece_over_loopback()
+    {
+        let data: Vec<u8> = (0..100_000).map(|_| fastrand::u8(..)).collect();
+        let torrent = torrent(&data);
+        let stats = Arc::new(TransferStats::new(0));
+        let (addr, seeding, _seeded) = seed(&torrent, &data, vec![true; 3], stats.clone()).await;
+
+        let output = tempfile::tempdir().unwrap();
+        let target = Storage::create(&torrent, &output.path().join("a")).unwrap();
+        let downloading = Arc::new(TransferStats::new(data.len()));
+        let target = SeedTorrent::new(torrent.clone(), target, vec![false; 3], downloading.clone()).unwrap();
+        let mut sources = PeerSources { session: None, dht: None, stats: downloading };
+        let resume = output.path().join("a.resume");
+        download_all(&torrent, &mut sources, &[addr], &target, extension::LISTEN_PORT, &resume, pipeline::DEFAULT_MAX_REQUESTS).await.unwrap();
+        seeding.abort();
+
+        assert!(std::fs::read(output.path().join("a")).unwrap() == data, "Downloaded bytes differ");
+        assert_eq!(stats.uploaded.load(std::sync::atomic::Ordering::Relaxed), data.len(), "Seeder counts what it sent");
+        assert_eq!(target.have(), vec![true; 3], "Every piece is offered once it is written");
+        assert_eq!(restore(&torrent, target.storage(), &resume, false).unwrap(), vec![true; 3], "State is saved as pieces arrive");
+    }
+
+    #[tokio::test]
+    async fn resumes_with_missing_pieces_only()
+    {
+        let data: Vec<u8> = (0..100_000).map(|_| fastrand::u8(..)).collect();
+        let torrent = torrent(&data);
+        let output = tempfile::tempdir().unwrap();
+        let target = Storage::create(&torrent, &output.path().join("a")).unwrap();
+        target.write_piece(&torrent, 0, &data[..PIECE_LENGTH]).unwrap();
+        let resume = output.path().join("a.resume");
+        ResumeState::capture(torrent.info_hash().unwrap(), &target, &[true, false, false]).unwrap().save(&resume).unwrap();
+
+        // the seeder lacks the first piece, so asking for it again would fail the download
+        let stats = Arc::new(TransferStats::new(0));
+        let (addr, seeding, _seeded) = seed(&torrent, &data, vec![false, true, true], stats.clone()).await;
+        let have = restore(&torrent, &target, &resume, false).unwrap();
+        let downloading = Arc::new(TransferStats::new(data.len() - PIECE_LENGTH));
+        let target = SeedTorrent::new(torrent.clone(), target, have, downloading.clone()).unwrap();
+        let mut sources = PeerSources { session: None, dht: None, stats: downloading };
+        download_all(&torrent, &mut sources, &[addr], &target, extension::LISTEN_PORT, &resume, pipeline::DEFAULT_MAX_REQUESTS).await.unwrap();
+        seeding.abort();
+
+        assert!(std::fs::read(output.path().join("a")).unwrap() == data, "Downloaded bytes differ");
+        assert_eq!(stats.uploaded.load(std::sync::atomic::Ordering::Relaxed), data.len() - PIECE_LENGTH, "Only missing pieces are sent");
+    }
+
+    #[tokio::test]
+    async fn downloads_trackerless_from_given_peers()
+    {
+        let data: Vec<u8> = (0..100_000).map(|_| fastrand::u8(..)).collect();
+        let torrent = torrent(&data);
+        assert!(torrent.announce.is_empty() && torrent.announce_list.is_empty(), "Fixture should have no trackers");
+        let (addr, seeding, _seeded) = seed(&torrent, &data, vec![true; 3], Arc::new(TransferStats::new(0))).await;
+
+        let output = tempfile::tempdir().unwrap();
+        let downloaded = all(&torrent, String::from("00112233445566778890"), &output.path().join("a"), &[addr], None, pipeline::DEFAULT_MAX_REQUESTS).await;
+        seeding.abort();
+
+        downloaded.unwrap();
+        assert!(std::fs::read(output.path().join("a")).unwrap() == data, "Downloaded bytes differ");
+    }
+
+    #[tokio::test]
+    async fn waits_for_discovery_when_peers_lack_pieces()
+    {
+        let data: Vec<u8> = (0..100_000).map(|_| fastrand::u8(..)).collect();
+        let torrent = torrent(&data);
+        let (partial, partial_seeding, _partial) = seed(&torrent, &data, vec![true, true, false], Arc::new(TransferStats::new(0))).await;
+        let (full, full_seeding, _full) = seed(&torrent, &data, vec![true; 3], Arc::new(TransferStats::new(0))).await;
+        let peers = connect(&[partial], &torrent, extension::LISTEN_PORT, None, pipeline::DEFAULT_MAX_REQUESTS).await.unwrap();
+        let pieces = (0..3).map(|index| Piece::new(index, &torrent, &peers)).collect();
+
+        let (joined, joining) = mpsc::channel(1);
+        let (verified, mut pieces_done) = mpsc::channel(3);
+        // the first peer runs out of pieces long before this one shows up
+        let discovering = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let peers = connect(&[full], &torrent, extension::LISTEN_PORT, None, pipeline::DEFAULT_MAX_REQUESTS).await.unwrap();
+            joined.send(peers).await.unwrap();
+        };
+        let (fetched, ()) = tokio::join!(fetch(&torrent, pieces, peers, joining, verified, None), discovering);
+        partial_seeding.abort();
+        full_seeding.abort();
+
+        fetched.unwrap();
+        let mut done = Vec::new();
+        while let Ok((index, _)) = pieces_done.try_recv()
+        {
+            done.push(index);
+        }
+        done.sort();
+        assert_eq!(done, vec![0, 1, 2], "The last piece comes from the late peer");
+    }
+
+    #[tokio::test]
+    async fn downloads_again_what_a_corrupt_peer_sent()
     {
-        self.bytes
+        let data: Vec<u8> = (0..1_000_000).map(|_| fastrand::u8(..)).collect();
+        let torrent = torrent(&data);
+        let pieces = torrent.info.pieces.0.len();
+        let garbage: Vec<u8> = data.iter().map(|byte| !byte).collect();
+        let lying = Arc::new(TransferStats::new(0));
+        let (corrupt, corrupt_seeding, _corrupt) = seed(&torrent, &garbage, vec![true; pieces], lying.clone()).await;
+        let (honest, honest_seeding, _honest) = seed(&torrent, &data, vec![true; pieces], Arc::new(TransferStats::new(0))).await;
+
+        let output = tempfile::tempdir().unwrap();
+        let downloaded = all(&torrent, String::from("00112233445566778890"), &output.path().join("a"), &[corrupt, honest], None, pipeline::DEFAULT_MAX_REQUESTS).await;
+        corrupt_seeding.abort();
+        honest_seeding.abort();
+
+        downloaded.unwrap();
+        assert!(lying.uploaded.load(std::sync::atomic::Ordering::Relaxed) > 0, "The corrupt peer should have sent blocks");
+        assert!(std::fs::read(output.path().join("a")).unwrap() == data, "Downloaded bytes differ");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,281 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+use crate::peer::{Message, MessageFramer, MessageTag};
+
+// the extended message id 0 is always the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+// outstanding requests we are willing to queue per peer
+pub const REQQ: usize = 250;
+// the port we try to listen on and advertise to trackers and peers
+pub const LISTEN_PORT: u16 = 6881;
+
+/// BEP 10 extended handshake; `m` maps extension names to the ids the sender wants to receive them on.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtendedHandshake
+{
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+    // the sender's listen port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+    // client name and version; bytes because not every client sends UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake
+{
+    /// The id the remote side wants `name` messages on; 0 means it disabled the extension.
+    pub fn extension_id(&self, name: &str) -> Option<u8>
+    {
+        self.m.get(name)
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != 0)
+    }
+    pub fn client(&self) -> Option<String>
+    {
+        self.v.as_ref().map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+    pub fn to_message(&self) -> anyhow::Result<Message>
+    {
+        Ok(message(HANDSHAKE_ID, &serde_bencode::to_bytes(self)?))
+    }
+    /// Parses the payload of an extended message with id 0, without the id byte.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>
+    {
+        serde_bencode::from_bytes(bytes).context("Parsing extended handshake")
+    }
+}
+
+/// An extended (tag 20) message: the extended message id, then the extension's payload.
+pub fn message(id: u8, payload: &[u8]) -> Message
+{
+    let mut bytes = Vec::with_capacity(1 + payload.len());
+    bytes.push(id);
+    bytes.extend_from_slice(payload);
+    Message
+    {
+        tag: MessageTag::Extended,
+        payload: bytes,
+    }
+}
+
+/// One extension riding on the BEP 10 protocol.
+pub trait Extension: Any + Send
+{
+    /// The key in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+    /// Adds extension specific keys to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+    /// Called once the remote extended handshake arrived.
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) {}
+    /// Handles a message the peer sent on our id for this extension and
+    /// returns the payloads to answer with.
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>;
+}
+
+/// The extensions of one connection. Local ids are handed out in registration order, starting at 1.
+pub struct ExtensionRegistry
+{
+    extensions: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+    port: u16,
+}
+
+impl fmt::Debug for ExtensionRegistry
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("ExtensionRegistry")
+            .field("extensions", &self.extensions.iter().map(|extension| extension.name()).collect::<Vec<_>>())
+            .field("remote", &self.remote)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl Default for ExtensionRegistry
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl ExtensionRegistry
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            extensions: Vec::new(),
+            remote: None,
+            port: LISTEN_PORT,
+        }
+    }
+    /// Advertises `port` instead of `LISTEN_PORT`, for when we listen somewhere else.
+    pub fn with_port(mut self, port: u16) -> Self
+    {
+        self.port = port;
+        self
+    }
+    /// Returns the local id the extension receives its messages on.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8
+    {
+        self.extensions.push(extension);
+        u8::try_from(self.extensions.len()).expect("at most 255 extensions")
+    }
+    /// Our extended handshake, with every registered extension in `m`.
+    pub fn handshake(&self) -> ExtendedHandshake
+    {
+        let mut handshake = ExtendedHandshake
+        {
+            m: self.extensions.iter()
+                .zip(1..)
+                .map(|(extension, id)| (String::from(extension.name()), id))
+                .collect(),
+            metadata_size: None,
+            p: Some(self.port),
+            reqq: Some(REQQ),
+            v: Some(ByteBuf::from(concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).as_bytes())),
+        };
+        for extension in &self.extensions
+        {
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+    pub fn remote(&self) -> Option<&ExtendedHandshake>
+    {
+        self.remote.as_ref()
+    }
+    /// Wraps `payload` for the remote side, if it supports the extension.
+    pub fn message(&self, name: &str, payload: &[u8]) -> Option<Message>
+    {
+        let id = self.remote.as_ref()?.extension_id(name)?;
+        Some(message(id, payload))
+    }
+    pub fn get_mut<T: Extension>(&mut self) -> Option<&mut T>
+    {
+        self.extensions.iter_mut()
+            .find_map(|extension| (extension.as_mut() as &mut dyn Any).downcast_mut())
+    }
+    /// Sends our extended handshake and waits for the peer's; other messages before it are dropped.
+    pub async fn exchange(&mut self, framed: &mut Framed<TcpStream, MessageFramer>) -> anyhow::Result<&ExtendedHandshake>
+    {
+        framed.send(self.handshake().to_message()?).await.context("Sending extended handshake")?;
+        while self.remote.is_none()
+        {
+            let msg = framed.next().await.context("Peer closed the connection")?
+                .context("Deriving message")?;
+            // a bitfield or have messages may come first
+            if msg.tag == MessageTag::Extended && msg.payload.first() == Some(&HANDSHAKE_ID)
+            {
+                self.dispatch(&msg.payload)?;
+            }
+        }
+        Ok(self.remote.as_ref().expect("set by the loop"))
+    }
+    /// Routes the payload of an extended message and returns what to send back.
+    pub fn dispatch(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Message>>
+    {
+        let (&id, payload) = payload.split_first().context("Extended message without id")?;
+        if id == HANDSHAKE_ID
+        {
+            let remote = ExtendedHandshake::from_bytes(payload)?;
+            for extension in &mut self.extensions
+            {
+                extension.on_handshake(&remote);
+            }
+            self.remote = Some(remote);
+            return Ok(Vec::new());
+        }
+
+        // ids we never handed out are ignored, as BEP 10 asks
+        let Some(extension) = self.extensions.get_mut(id as usize - 1) else {
+            return Ok(Vec::new());
+        };
+        let name = extension.name();
+        let replies = extension.on_message(payload)
+            .with_context(|| format!("Handling {} message", name))?;
+        Ok(replies.iter().filter_map(|reply| self.message(name, reply)).collect())
+    }
+}
+
+
+#[cfg(test)]
+mod test_extension_registry
+{
+    use crate::extension::{Extension, ExtensionRegistry, ExtendedHandshake};
+
+    #[derive(Default)]
+    struct Echo
+    {
+        remote_knows_us: bool,
+        seen: usize,
+    }
+
+    impl Extension for Echo
+    {
+        fn name(&self) -> &'static str
+        {
+            "echo"
+        }
+        fn on_handshake(&mut self, remote: &ExtendedHandshake)
+        {
+            self.remote_knows_us = remote.extension_id("echo").is_some();
+        }
+        fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>
+        {
+            self.seen += 1;
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[test]
+    fn encodes_handshake()
+    {
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::<Echo>::default());
+
+        let bytes = serde_bencode::to_bytes(&registry.handshake()).unwrap();
+
+        let expected = format!("d1:md4:echoi1ee1:pi6881e4:reqqi250e1:v{}:{} {}e",
+                               env!("CARGO_PKG_NAME").len() + 1 + env!("CARGO_PKG_VERSION").len(),
+                               env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        assert_eq!(String::from_utf8(bytes).unwrap(), expected);
+        assert_eq!(ExtensionRegistry::new().with_port(51413).handshake().p, Some(51413), "Should advertise the port we listen on");
+    }
+
+    #[test]
+    fn dispatches_by_local_id()
+    {
+        let mut registry = ExtensionRegistry::new();
+        let local_id = registry.register(Box::<Echo>::default());
+
+        let mut handshake = vec![0];
+        handshake.extend(b"d1:md4:echoi7ee1:v3:abce");
+        assert!(registry.dispatch(&handshake).unwrap().is_empty(), "Handshake needs no answer");
+        assert_eq!(registry.remote().unwrap().client().as_deref(), Some("abc"));
+
+        let replies = registry.dispatch(&[local_id, 42]).unwrap();
+        assert_eq!(replies.len(), 1, "Should echo back");
+        assert_eq!(replies[0].payload, vec![7, 42], "Reply should go on the remote id");
+
+        assert!(registry.dispatch(&[9, 1]).unwrap().is_empty(), "Unknown ids are ignored");
+        let echo = registry.get_mut::<Echo>().unwrap();
+        assert!(echo.remote_knows_us, "Extension should see the remote handshake");
+        assert_eq!(echo.seen, 1, "Only the known id should reach the extension");
+    }
+}
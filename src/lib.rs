@@ -3,6 +3,21 @@ pub mod peer;
 pub mod torrent;
 pub mod downloaded;
 pub mod piece;
+pub mod picker;
+pub mod udp_tracker;
+pub mod extension;
+pub mod metadata;
+pub mod magnet;
+pub mod pex;
+pub mod dht;
+pub mod storage;
+pub mod seed;
+pub mod choker;
+pub mod pipeline;
+pub mod resume;
+pub mod verify;
+pub mod create;
+pub mod decoder;
 
 pub mod cli
 {
@@ -23,9 +38,10 @@ pub mod cli
     #[derive(Subcommand, Debug, Clone)]
     pub enum Commands
     {
+        /// Decode a bencoded value to JSON; byte strings that are not UTF-8 show as 0x-prefixed hex.
         Decode
         {
-            value: String,
+            value: std::ffi::OsString,
         },
         Info
         {
@@ -51,28 +67,137 @@ pub mod cli
         {
             torrent: PathBuf,
             output: PathBuf,
+            /// Also look for peers in the DHT; trackerless torrents always do.
+            #[arg(long)]
+            dht: bool,
+            /// A DHT bootstrap node as host:port; may be repeated.
+            #[arg(long = "dht-node")]
+            dht_nodes: Vec<String>,
+            /// Blocks requested from one peer at once, at most; the peer's own limit still applies.
+            #[arg(long, default_value_t = crate::pipeline::DEFAULT_MAX_REQUESTS)]
+            max_requests: usize,
+        },
+        Scrape
+        {
+            #[arg(required = true)]
+            torrents: Vec<PathBuf>,
+            /// Seconds to wait for a UDP tracker before asking again, doubled on every retry.
+            /// The defaults give up within 30 seconds instead of following BEP 15's two hour schedule.
+            #[arg(long, default_value_t = 5)]
+            udp_timeout: u64,
+            #[arg(long, default_value_t = 2)]
+            udp_retries: u32,
+            /// Seconds after which a UDP tracker is given up on, whatever the retries left.
+            #[arg(long, default_value_t = 30)]
+            udp_deadline: u64,
+        },
+        /// Upload the data of a torrent, laid out as `download` writes it.
+        Seed
+        {
+            torrent: PathBuf,
+            data: PathBuf,
+            #[arg(long, default_value_t = 6881)]
+            port: u16,
+            /// Peers uploaded to at once, one of them picked at random.
+            #[arg(long, default_value_t = crate::choker::UPLOAD_SLOTS)]
+            upload_slots: usize,
+        },
+        /// Hash data laid out as `download` writes it against the torrent; fails on any bad piece.
+        #[clap(alias = "recheck")]
+        Verify
+        {
+            torrent: PathBuf,
+            path: PathBuf,
+            /// Print the report as JSON.
+            #[arg(long)]
+            json: bool,
+        },
+        /// Build a .torrent from a file or directory.
+        Create
+        {
+            path: PathBuf,
+            /// Where to write the torrent; defaults to `<name>.torrent`.
+            #[arg(short, long)]
+            output: Option<PathBuf>,
+            /// A tier of trackers, comma separated; may be repeated.
+            #[arg(short, long = "tracker")]
+            trackers: Vec<String>,
+            /// A power of two; picked from the total size when left out.
+            #[arg(long)]
+            piece_length: Option<usize>,
+            /// Also fetch the data from this URL; may be repeated.
+            #[arg(long = "web-seed")]
+            web_seeds: Vec<String>,
+            #[arg(long)]
+            private: bool,
+            #[arg(long)]
+            comment: Option<String>,
+            #[arg(long)]
+            source: Option<String>,
+            #[arg(long)]
+            name: Option<String>,
+            #[arg(long)]
+            created_by: Option<String>,
+            /// Leave out the creation date, so the same data always gives the same file.
+            #[arg(long)]
+            no_date: bool,
+        },
+        #[clap(name = "magnet_parse")]
+        MagnetParse
+        {
+            link: String,
+        },
+        #[clap(name = "magnet_handshake")]
+        MagnetHandshake
+        {
+            link: String,
+        },
+        #[clap(name = "magnet_info")]
+        MagnetInfo
+        {
+            link: String,
+        },
+        #[clap(name = "magnet_download")]
+        MagnetDownload
+        {
+            link: String,
+            output: PathBuf,
+            /// Blocks requested from one peer at once, at most; the peer's own limit still applies.
+            #[arg(long, default_value_t = crate::pipeline::DEFAULT_MAX_REQUESTS)]
+            max_requests: usize,
         },
     }
 }
 
 pub mod torrent_executor
 {
-    use std::net::SocketAddrV4;
+    use std::net::SocketAddr;
     use std::str::FromStr;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::Arc;
+    use std::time::Duration;
     use crate::cli::Commands;
-    use crate::peer::{Handshake, Message, MessageFramer, MessageTag, PeerRequest, PieceMessage};
-    use crate::tracker::{TrackerResponse, url_encode};
-    use crate::tracker::TrackerRequest;
+    use crate::create::TorrentBuilder;
+    use crate::dht;
+    use crate::downloaded;
+    use crate::dht::{Dht, DhtConfig, BOOTSTRAP_NODES};
+    use crate::extension::ExtensionRegistry;
+    use crate::magnet::Magnet;
+    use crate::metadata;
+    use crate::metadata::MetadataExtension;
+    use crate::peer::{Handshake, MessageFramer, Peer};
+    use crate::seed::{SeedTorrent, Seeder};
+    use crate::storage::Storage;
+    use crate::tracker::{scrape, TrackerRequest, TrackerResponse, TrackerSession, Trackers, TransferStats};
+    use crate::udp_tracker::Retry;
+    use crate::verify;
+    use crate::verify::{Report, Status};
     use anyhow::Context;
     use tokio::net::TcpStream;
-    use crate::decoder::decode_bencoded_value;
-    use futures_util::stream::StreamExt;
-    use futures_util::SinkExt;
-    use sha1::{Sha1, Digest};
-    use crate::peer::MessageTag::{Request};
+    use crate::decoder;
     use crate::torrent::{Keys, Torrent};
 
+    const PEER_ID: &str = "00112233445566778890";
+
     pub struct TorrentExecutor;
 
     impl TorrentExecutor
@@ -83,34 +208,25 @@ pub mod torrent_executor
             {
                 Commands::Decode { value } =>
                     {
-                        let decoded_value = decode_bencoded_value(&value)?;
-                        println!("{}", decoded_value.to_string());
+                        let decoded_value = decoder::decode(value.as_encoded_bytes())?;
+                        println!("{}", decoded_value.to_json());
                     }
                 Commands::Info { torrent } =>
                     {
                         let t = Torrent::try_from(&torrent)?;
-                        println!("Tracked url: {}", t.announce);
-                        let length = if let Keys::SingleFile { length } = t.info.keys
-                        {
-                            println!("Length {length}");
-                            length
-                        } else {
-                            todo!()
-                        };
-                        let hash = t.info_hash()?;
-
-                        println!("Info hash: {}", hex::encode(hash));
-                        println!("Piece length: {}", t.info.piece_length);
-                        println!("Piece hashes: ");
-                        t.info.pieces.0.iter().for_each(|data| println!("{}", hex::encode(data)));
+                        Self::print_info(&t)?;
                     }
                 Commands::Peers { torrent } =>
                     {
                         let t = Torrent::try_from(&torrent)?;
 
                         let peers = TorrentExecutor::get_peers(&t).await.context("Getting peers")?;
+                        if let Some(warning) = &peers.warning_message
+                        {
+                            eprintln!("Tracker warning: {}", warning);
+                        }
                         for peer in peers.peers.0 {
-                            println!("{}:{}", peer.ip(), peer.port());
+                            println!("{}", peer);
                         }
                     }
 
@@ -120,126 +236,363 @@ pub mod torrent_executor
                         println!("Tracked url: {}", &t.announce);
 
                         let hash = t.info_hash()?;
-                        let socket = SocketAddrV4::from_str(&peer).context("Deriving socket")?;
+                        let socket = SocketAddr::from_str(&peer).context("Deriving socket")?;
                         TorrentExecutor::handshake(hash, socket).await.context("Making handshake")?;
                     }
                 Commands::DownloadPiece { torrent, output, piece } =>
                     {
                         let torrent = Torrent::try_from(&torrent).context("Deriving torrent")?;
-                        let tracker_response = Self::get_peers(&torrent).await?;
+                        let bytes = torrent.download_piece(piece, String::from(PEER_ID)).await?;
 
-                        let peer = tracker_response.peers.0[0]; // Can connect to all peers or to randome one
-                        let peer = Self::handshake(torrent.info_hash()?, peer).await?;
-                        let mut framed = tokio_util::codec::Framed::new(peer, MessageFramer);
-                        let msg = framed.next().await.expect("Peer always sends first message")
-                            .context("Deriving message")?;
-                        assert_eq!(msg.tag, MessageTag::Bitfield, "First message should has bitfield tag");
-                        framed.send(
-                            Message
+                        tokio::fs::write(&output, bytes).await
+                            .with_context(|| format!("Writing piece to {}", output.display()))?;
+                        println!("Piece {} downloaded to {}.", piece, output.display());
+                    }
+                Commands::Download { torrent, output, dht, dht_nodes, max_requests } =>
+                    {
+                        let torrent = Torrent::try_from(&torrent).context("Deriving torrent")?;
+                        let trackerless = torrent.announce.is_empty() && torrent.announce_list.is_empty();
+                        let dht = match dht || trackerless || !dht_nodes.is_empty() {
+                            false => None,
+                            true => match Self::start_dht(&torrent, &dht_nodes).await {
+                                Ok(dht) => Some(dht),
+                                // the trackers can still bring peers
+                                Err(e) if !trackerless => {
+                                    eprintln!("Continuing without the DHT: {:#}", e);
+                                    None
+                                }
+                                Err(e) => return Err(e),
+                            },
+                        };
+                        if let Some(dht) = dht
+                        {
+                            let files = torrent.download_all_with_dht(String::from(PEER_ID), &output, &[], &dht, max_requests).await;
+                            if let Err(e) = dht.save(&DhtConfig::default_state())
                             {
-                                tag: MessageTag::Interested,
-                                payload: vec![],
+                                eprintln!("Fail to save the DHT routing table: {:#}", e);
                             }
-                        ).await.context("Sending 'interesting' message")?;
-                        let msg = framed.next().await.expect("Peer always sends first message")
-                            .context("Deriving message")?;
-
-                        assert_eq!(msg.tag, MessageTag::UnChoke, "Waiting for 'unchoke' message");
-                        assert!(torrent.info.pieces.0.len() > piece, "Can't get peice hash");
-
-                        let peice_hash = torrent.info.pieces.0[piece];
-                        let piece_size = match (piece, torrent.info.keys)
+                            files?;
+                        } else {
+                            torrent.download_all(String::from(PEER_ID), &output, max_requests).await?;
+                        }
+                        println!("Downloaded {} to {}.", torrent.info.name, output.display());
+                    }
+                Commands::Scrape { torrents, udp_timeout, udp_retries, udp_deadline } =>
+                    {
+                        // torrents sharing a tracker are scraped in one request
+                        type Group = Vec<(Torrent, [u8; 20])>;
+                        let mut by_tracker: Vec<(String, Group)> = Vec::new();
+                        for path in &torrents
                         {
-                            (p, Keys::SingleFile { length }) if p == torrent.info.pieces.0.len() + 1 => {
-                                length % torrent.info.length
+                            let t = Torrent::try_from(path)?;
+                            let info_hash = t.info_hash()?;
+                            let tracker = t.announce_list.iter().flatten().next().unwrap_or(&t.announce).clone();
+                            match by_tracker.iter_mut().find(|(url, _)| *url == tracker) {
+                                Some((_, group)) => group.push((t, info_hash)),
+                                None => by_tracker.push((tracker, vec![(t, info_hash)])),
                             }
-                            (_, Keys::SingleFile { length: _ }) => torrent.info.length,
+                        }
 
-                            _ => unimplemented!()
+                        let retry = Retry
+                        {
+                            timeout: Duration::from_secs(udp_timeout),
+                            retries: udp_retries,
+                            deadline: Duration::from_secs(udp_deadline),
                         };
-                        let nblocks = (piece_size + 2 ^ 14 - 1) / 2 ^ 14;
-                        let mut pieces: Vec<u8> = Vec::with_capacity(nblocks);
-                        for block in 0..nblocks
+                        for (tracker, group) in by_tracker
                         {
-                            let block_size = if block == nblocks {
-                                piece_size % 2 ^ 14
-                            } else {
-                                2 ^ 14
+                            let info_hashes: Vec<_> = group.iter().map(|(_, info_hash)| *info_hash).collect();
+                            let stats = match scrape(&tracker, &info_hashes, retry).await {
+                                Ok(stats) => stats,
+                                Err(e) => {
+                                    eprintln!("{}: {:#}", tracker, e);
+                                    continue;
+                                }
                             };
-                            let request = PeerRequest::new(piece as u32, (block * 2 ^ 14) as u32, block_size as u32);
-                            // TODO! add safe casting
-
-                            framed.send(
-                                Message
+                            for (t, info_hash) in &group
+                            {
+                                match stats.get(info_hash) {
+                                    Some(stats) => println!("{} {}: seeders {}, leechers {}, downloaded {}",
+                                                            hex::encode(info_hash), t.info.name,
+                                                            stats.complete, stats.incomplete, stats.downloaded),
+                                    None => println!("{} {}: unknown to {}", hex::encode(info_hash), t.info.name, tracker),
+                                }
+                            }
+                        }
+                    }
+                Commands::Seed { torrent, data, port, upload_slots } =>
+                    {
+                        let torrent = Torrent::try_from(&torrent).context("Deriving torrent")?;
+                        let storage = Storage::new(&torrent, &data)?;
+                        let have = {
+                            let (torrent, storage) = (torrent.clone(), storage.clone());
+                            tokio::task::spawn_blocking(move || storage.verify(&torrent)).await?
+                        };
+                        let missing: usize = have.iter().enumerate()
+                            .filter(|(_, have)| !**have)
+                            .map(|(piece, _)| torrent.piece_len(piece))
+                            .sum();
+                        println!("{} of {} pieces verified.", have.iter().filter(|have| **have).count(), have.len());
+
+                        let stats = Arc::new(TransferStats::new(missing));
+                        let seeder = Arc::new(Seeder::new());
+                        seeder.add(Arc::new(SeedTorrent::new(torrent.clone(), storage, have, stats.clone())?.with_upload_slots(upload_slots)));
+                        let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await
+                            .with_context(|| format!("Listening on port {}", port))?;
+                        println!("Seeding {} on port {}.", torrent.info.name, port);
+
+                        // peers may still find us without trackers
+                        let session = match TrackerSession::start(&torrent, String::from(PEER_ID), port, stats).await {
+                            Ok((session, _)) => Some(session),
+                            Err(e) => {
+                                eprintln!("Announcing to trackers failed: {:#}", e);
+                                None
+                            }
+                        };
+                        let seeded = tokio::select! {
+                            seeded = seeder.listen(listener) => seeded,
+                            _ = tokio::signal::ctrl_c() => Ok(()),
+                        };
+                        if let Some(session) = session
+                        {
+                            session.stop().await;
+                        }
+                        seeded?;
+                    }
+                Commands::Verify { torrent, path, json } =>
+                    {
+                        let torrent = Torrent::try_from(&torrent).context("Deriving torrent")?;
+                        let storage = Storage::new(&torrent, &path)?;
+                        let report = {
+                            let torrent = torrent.clone();
+                            tokio::task::spawn_blocking(move || verify::verify(&torrent, &storage)).await?
+                        };
+                        if json
+                        {
+                            println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+                        } else {
+                            Self::print_report(&report);
+                        }
+                        let failed = report.pieces.len() - report.count(Status::Complete);
+                        anyhow::ensure!(failed == 0, "{} of {} pieces failed verification", failed, report.pieces.len());
+                    }
+                Commands::Create { path, output, trackers, piece_length, web_seeds, private, comment, source, name, created_by, no_date } =>
+                    {
+                        let mut builder = TorrentBuilder::new(&path)
+                            .piece_length(piece_length)
+                            .private(private)
+                            .comment(comment)
+                            .source(source);
+                        for tier in trackers
+                        {
+                            builder = builder.tier(tier.split(',').map(str::trim).filter(|url| !url.is_empty()).map(String::from).collect());
+                        }
+                        for url in web_seeds
+                        {
+                            builder = builder.web_seed(url);
+                        }
+                        if let Some(name) = name
+                        {
+                            builder = builder.name(name);
+                        }
+                        if created_by.is_some()
+                        {
+                            builder = builder.created_by(created_by);
+                        }
+                        if no_date
+                        {
+                            builder = builder.creation_date(None);
+                        }
+                        let bytes = tokio::task::spawn_blocking(move || builder.build()).await??;
+                        let torrent = Torrent::try_from(bytes.clone())?;
+                        let output = output.unwrap_or_else(|| format!("{}.torrent", torrent.info.name).into());
+                        tokio::fs::write(&output, bytes).await
+                            .with_context(|| format!("Writing torrent to {}", output.display()))?;
+                        println!("Created {} with {} pieces of {} bytes.", output.display(), torrent.info.pieces.0.len(), torrent.info.piece_length);
+                        println!("Info hash: {}", hex::encode(torrent.info_hash()?));
+                    }
+                Commands::MagnetParse { link } =>
+                    {
+                        let magnet: Magnet = link.parse()?;
+                        for tracker in &magnet.trackers
+                        {
+                            println!("Tracker URL: {}", tracker);
+                        }
+                        println!("Info Hash: {}", hex::encode(magnet.info_hash));
+                    }
+                Commands::MagnetHandshake { link } =>
+                    {
+                        let magnet: Magnet = link.parse()?;
+                        let (peers, _) = Self::magnet_peers(&magnet).await?;
+                        let peer = *peers.first().context("No peers for magnet link")?;
+
+                        let (stream, remote) = Peer::handshake(Handshake::new(magnet.info_hash), peer).await.context("Making handshake")?;
+                        println!("Peer ID: {}", hex::encode(remote.peer_id()));
+                        anyhow::ensure!(remote.supports_extension_protocol(), "Peer does not support extensions");
+
+                        let mut framed = tokio_util::codec::Framed::new(stream, MessageFramer::new());
+                        let mut registry = ExtensionRegistry::new();
+                        registry.register(Box::new(MetadataExtension::fetching(magnet.info_hash)));
+                        let remote = registry.exchange(&mut framed).await?;
+                        if let Some(id) = remote.extension_id(metadata::NAME)
+                        {
+                            println!("Peer Metadata Extension ID: {}", id);
+                        }
+                    }
+                Commands::MagnetInfo { link } =>
+                    {
+                        let magnet: Magnet = link.parse()?;
+                        let (t, _) = Self::magnet_torrent(&magnet).await?;
+                        Self::print_info(&t)?;
+                    }
+                Commands::MagnetDownload { link, output, max_requests } =>
+                    {
+                        let magnet: Magnet = link.parse()?;
+                        let (torrent, dht) = Self::magnet_torrent(&magnet).await?;
+                        match dht {
+                            Some(dht) => {
+                                let files = torrent.download_all_with_dht(String::from(PEER_ID), &output, &magnet.peers, &dht, max_requests).await;
+                                if let Err(e) = dht.save(&DhtConfig::default_state())
                                 {
-                                    tag: Request,
-                                    payload: request.to_bytes().to_vec(),
+                                    eprintln!("Fail to save the DHT routing table: {:#}", e);
                                 }
-                            ).await.
-                                with_context(||
-                                    format!("request with index: {}, len: {}, begin: {}",
-                                            request.index(), request.length(), request.begin()
-                                    )
-                                )?;
-                            let msg = framed.next().await.expect("Peer always sends first message")
-                                .context("Deriving piece message")?;
-                            assert_eq!(msg.tag, MessageTag::Piece, "Waiting for the 'Piece message'");
-                            assert!(!msg.payload.is_empty(), "Shouldn't be empty");
-                            let piece = PieceMessage::from_bytes(msg.payload.as_slice());
-                            pieces.extend(piece.block());
+                                files?;
+                            }
+                            None => {
+                                torrent.download_all_with_peers(String::from(PEER_ID), &output, &magnet.peers, max_requests).await?;
+                            }
                         }
-                        let mut sha = Sha1::new();
-                        sha.update(pieces);
-                        let hash = sha.finalize();
-                        println!("{:?}", hash);
+                        println!("Downloaded {} to {}.", torrent.info.name, output.display());
                     }
-                Commands::Download { torrent, output } => {
-                    // let torrent = Torrent::read(output)?;
-                    // torrent.print_tree();
-                    // let files = torrent.download_all().await?;
-                    // tokio::fs::write(
-                    //     &output, files.iter().next().expect("always one file").bytes(),
-                    // ).await?;
-                    // torrent.download_some(vec![("/foo.txt", output)]).await?;
-                    // torrent.download_single(output).await?;
-                }
-                _ => { unimplemented!() }
             }
             Ok(())
         }
-        async fn get_peers(torrent: &Torrent) -> anyhow::Result<TrackerResponse>
+        fn print_info(t: &Torrent) -> anyhow::Result<()>
         {
-            if let Keys::SingleFile { length } = torrent.info.keys
+            println!("Tracked url: {}", t.announce);
+            for (i, tier) in t.announce_list.iter().enumerate()
             {
-                let tracker_request = TrackerRequest::new(String::from("011112012313"), length);
-
-                let url_params = serde_urlencoded::to_string(tracker_request).
-                    context("URL-tracker params")?;
-                let info_hash = torrent.info_hash()?;
-                let tracker_url = format!("{}?{}&info_hash={}", torrent.announce, url_params, &url_encode(&info_hash));
-
-
-                let response = reqwest::get(tracker_url).await.context("Fetch Tracker")?;
-                let response: TrackerResponse = serde_json::from_slice(&response.bytes().await
-                    .context("Fetch tracker response")?)
-                    .context("Serialising response bytes")?;
+                println!("Tier {}: {}", i, tier.join(", "));
+            }
+            match &t.info.keys
+            {
+                Keys::SingleFile { length } => println!("Length {length}"),
+                Keys::MultiFile { files } =>
+                    {
+                        println!("Length {}", t.len());
+                        println!("Files: ");
+                        for file in files
+                        {
+                            println!("{} ({})", file.path.join(std::path::MAIN_SEPARATOR_STR), file.length);
+                        }
+                    }
+            }
+            let hash = t.info_hash()?;
 
-                Ok(response)
-            } else {
-                unreachable!()
+            println!("Info hash: {}", hex::encode(hash));
+            println!("Piece length: {}", t.info.piece_length);
+            println!("Piece hashes: ");
+            t.info.pieces.0.iter().for_each(|data| println!("{}", hex::encode(data)));
+            Ok(())
+        }
+        fn print_report(report: &Report)
+        {
+            println!("Pieces: {} complete, {} missing, {} corrupt", report.count(Status::Complete), report.count(Status::Missing), report.count(Status::Corrupt));
+            for status in [Status::Missing, Status::Corrupt]
+            {
+                let pieces = report.pieces_with(status);
+                if !pieces.is_empty()
+                {
+                    let pieces: Vec<_> = pieces.iter().map(ToString::to_string).collect();
+                    println!("{:?} pieces: {}", status, pieces.join(", "));
+                }
+            }
+            println!("Files: ");
+            for file in &report.files
+            {
+                println!("{:?} {} ({})", file.status, file.path.display(), file.length);
             }
         }
-        async fn handshake(hash_info: [u8; 20], socket: SocketAddrV4) -> anyhow::Result<TcpStream>
+        /// Peers from the magnet link's trackers plus its `x.pe` peers. Without trackers, or when they
+        /// bring no peers, the DHT is asked too and returned so it can keep searching.
+        async fn magnet_peers(magnet: &Magnet) -> anyhow::Result<(Vec<SocketAddr>, Option<Arc<Dht>>)>
         {
-            let mut handshake = Handshake::new(hash_info);
-            let mut peer = tokio::net::TcpStream::connect(socket).await.context("Creating connection to peer")?;
-            let handshake_bytes = handshake.to_bytes_mut();
-            peer.write_all(&handshake_bytes).await.context("Writing to peer")?;
+            let mut peers = magnet.peers.clone();
+            let mut failure = None;
+            if !magnet.trackers.is_empty()
+            {
+                // the size is unknown until we have the metadata
+                let request = TrackerRequest::new(String::from(PEER_ID), 1);
+                match Trackers::from_tiers(magnet.tracker_tiers()).announce(magnet.info_hash, &request).await {
+                    Ok(response) => peers.extend(response.peers.0.into_iter().filter(|peer| !magnet.peers.contains(peer))),
+                    Err(e) => failure = Some(e.context("Query tracker for peer info")),
+                }
+            }
+            if peers.len() > magnet.peers.len()
+            {
+                return Ok((peers, None));
+            }
+            if let Some(e) = &failure
+            {
+                eprintln!("{:#}, asking the DHT", e);
+            }
 
-            let mut buffer = Vec::new();
-            peer.read_to_end(&mut buffer).await?;
-            let response_handshake = Handshake::from_bytes(&buffer).context("Getting back handshake")?;
-            println!("Handshake: {:?}", response_handshake);
+            let dht = match Self::bootstrap_dht(Vec::new()).await {
+                Ok(dht) => dht,
+                Err(e) if peers.is_empty() => return Err(failure.unwrap_or(e)),
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    return Ok((peers, None));
+                }
+            };
+            if let Ok(found) = tokio::time::timeout(downloaded::DHT_WAIT, dht.get_peers(magnet.info_hash, None)).await
+            {
+                peers.extend(found.into_iter().filter(|peer| !magnet.peers.contains(peer)));
+            }
+            anyhow::ensure!(!peers.is_empty(), "No peers for the magnet link from its trackers or the DHT");
+            Ok((peers, Some(dht)))
+        }
+        /// Fetches the info dictionary from the swarm and builds a torrent around it, along with
+        /// the DHT if it had to be asked for peers.
+        async fn magnet_torrent(magnet: &Magnet) -> anyhow::Result<(Torrent, Option<Arc<Dht>>)>
+        {
+            let (peers, dht) = Self::magnet_peers(magnet).await?;
+            let raw_info = metadata::fetch_any(&peers, magnet.info_hash).await?;
+            Ok((Torrent::from_info(raw_info, magnet.tracker_tiers())?, dht))
+        }
+        /// Bootstraps from `nodes`, else the torrent's `nodes`, else the well-known routers,
+        /// plus whatever the saved routing table remembers.
+        async fn start_dht(torrent: &Torrent, nodes: &[String]) -> anyhow::Result<Arc<Dht>>
+        {
+            let mut names = nodes.to_vec();
+            if names.is_empty()
+            {
+                names.extend(torrent.nodes.iter().map(ToString::to_string));
+            }
+            Self::bootstrap_dht(names).await
+        }
+        /// Bootstraps from `names`, else the well-known routers, plus the saved routing table.
+        async fn bootstrap_dht(mut names: Vec<String>) -> anyhow::Result<Arc<Dht>>
+        {
+            if names.is_empty()
+            {
+                names.extend(BOOTSTRAP_NODES.map(String::from));
+            }
+            let config = DhtConfig
+            {
+                bootstrap: dht::resolve(&names).await,
+                ..Default::default()
+            };
+            Dht::start(config).await.context("Starting the DHT")
+        }
+        async fn get_peers(torrent: &Torrent) -> anyhow::Result<TrackerResponse>
+        {
+            TrackerResponse::query(torrent, String::from(PEER_ID)).await
+        }
+        async fn handshake(hash_info: [u8; 20], socket: SocketAddr) -> anyhow::Result<TcpStream>
+        {
+            let (peer, remote) = Peer::handshake(Handshake::new(hash_info), socket).await?;
+            println!("Peer ID: {}", hex::encode(remote.peer_id()));
             Ok(peer)
         }
     }
@@ -265,7 +618,7 @@ pub mod hashes
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: Error {
-            if v.len() % 20 != 0
+            if !v.len().is_multiple_of(20)
             {
                 return Err(E::custom(format!("lenght is: {} .", v.len())));
             }
@@ -278,8 +631,8 @@ pub mod hashes
                 )
             )
         }
-        fn visit_borrowed_bytes<E>(self, _v: &'de [u8]) -> Result<Self::Value, E> where E: Error {
-            todo!()
+        fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E> where E: Error {
+            self.visit_bytes(v)
         }
     }
 
@@ -301,88 +654,3 @@ pub mod hashes
         }
     }
 }
-
-
-
-
-pub mod decoder {
-    use serde_json::Value;
-
-    fn decode(encoded_value: &str) -> anyhow::Result<(Value, &str)>
-    {
-        match encoded_value.chars().next() {
-            Some('l') => {
-                let mut result = &encoded_value[1..];
-                let mut vec = Vec::new();
-                loop {
-                    let (value, rest) = decode(result)?;
-                    vec.push(value);
-                    if rest.is_empty() || rest.chars().next().expect("Rest is not empty.") == 'e'
-                    {
-                        return Ok((vec.into(), result));
-                    }
-                    result = rest;
-                }
-            }
-            Some('d') => {
-                // d3:"key":value
-                let mut map = serde_json::map::Map::new();
-                let &(_, mut encoded) = &encoded_value.split_at(1);
-
-                loop {
-                    let (key, rest) = decode(encoded)?;
-                    if let Value::String(k) = key
-                    {
-                        let (value, rest) = decode(rest)?;
-                        map.insert(k, value);
-                        if rest.is_empty() || rest.chars().next().unwrap() == 'e'
-                        {
-                            return Ok((map.into(), rest));
-                        }
-                        encoded = rest;
-                    } else {
-                        panic!("Unhandled encoded value: {}", encoded_value)
-                    }
-                }
-            }
-            Some('i') =>
-                {
-                    let mut rest_str = "";
-                    if let Some(number) = encoded_value
-                        .split_once('e')
-                        .and_then(|(num, rest)|
-                            {
-                                rest_str = rest;
-
-                                num[1..].parse::<i64>().ok()
-                            })
-                    {
-                        return Ok((number.into(), rest_str));
-                    }
-                }
-            Some('0'..='9') =>
-                {
-                    if let Some((len, other)) = encoded_value.split_once(':')
-                    {
-                        if let Ok(number) = len.parse::<usize>() {
-
-                            // Example: "5:hello" -> "hello"
-                            let string = &other[..number];
-                            return Ok((string.into(), &other[number..]));
-                        }
-                    }
-                }
-
-            _ => {}
-        }
-        panic!("Unhandled encoded value: {}", encoded_value)
-    }
-
-    #[allow(dead_code)]
-    pub fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<Value> {
-        // If encoded_value starts with a digit, it's a number
-
-        let (value, _) = decode(encoded_value)?;
-        Ok(value)
-    }
-}
\ No newline at end of file
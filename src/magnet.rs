@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use anyhow::Context;
+
+/// A `magnet:?xt=urn:btih:...` link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet
+{
+    pub info_hash: [u8; 20],
+    // dn
+    pub name: Option<String>,
+    // tr
+    pub trackers: Vec<String>,
+    // x.pe
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet
+{
+    /// Every tracker as its own tier, so peers from all of them get merged.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>>
+    {
+        self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()
+    }
+}
+
+impl FromStr for Magnet
+{
+    type Err = anyhow::Error;
+
+    fn from_str(link: &str) -> Result<Self, Self::Err> {
+        let url = reqwest::Url::parse(link).context("Parsing magnet link")?;
+        anyhow::ensure!(url.scheme() == "magnet", "Not a magnet link: {}", link);
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs()
+        {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:")
+                    {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => peers.push(value.parse().with_context(|| format!("Invalid peer address {}", value))?),
+                _ => {}
+            }
+        }
+
+        Ok(
+            Self
+            {
+                info_hash: info_hash.context("Magnet link has no urn:btih infohash")?,
+                name,
+                trackers,
+                peers,
+            }
+        )
+    }
+}
+
+/// 40 hex digits or 32 base32 characters.
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]>
+{
+    match hash.len() {
+        40 => {
+            let bytes = hex::decode(hash).context("Infohash is not hex")?;
+            Ok(bytes.try_into().expect("guaranty to be 20"))
+        }
+        32 => base32_decode(hash),
+        length => anyhow::bail!("Infohash should be 40 hex or 32 base32 characters, got {}", length),
+    }
+}
+
+fn base32_decode(hash: &str) -> anyhow::Result<[u8; 20]>
+{
+    let mut bytes = [0u8; 20];
+    let mut buffer = 0_u64;
+    let mut bits = 0;
+    let mut i = 0;
+    for c in hash.bytes()
+    {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => anyhow::bail!("Infohash is not base32: {:?}", c as char),
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8
+        {
+            bits -= 8;
+            bytes[i] = (buffer >> bits) as u8;
+            i += 1;
+        }
+    }
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod test_magnet
+{
+    use crate::magnet::Magnet;
+
+    #[test]
+    fn parses_hex_link()
+    {
+        let magnet: Magnet = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Ft.org%2Fannounce&tr=udp%3A%2F%2Fu.org%3A80&x.pe=127.0.0.1%3A6881"
+            .parse().unwrap();
+
+        assert_eq!(hex::encode(magnet.info_hash), "ad42ce8109f54c99613ce38f9b4d87e70f24a165");
+        assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(magnet.trackers, vec!["http://t.org/announce".to_string(), "udp://u.org:80".to_string()]);
+        assert_eq!(magnet.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parses_base32_link()
+    {
+        let hex: Magnet = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165".parse().unwrap();
+        let base32: Magnet = "magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF".parse().unwrap();
+
+        assert_eq!(base32.info_hash, hex.info_hash);
+    }
+
+    #[test]
+    fn rejects_bad_links()
+    {
+        assert!("magnet:?dn=x".parse::<Magnet>().is_err(), "No infohash");
+        assert!("magnet:?xt=urn:btih:1234".parse::<Magnet>().is_err(), "Short infohash");
+        assert!("http://x/?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165".parse::<Magnet>().is_err(), "Wrong scheme");
+    }
+}
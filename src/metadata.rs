@@ -0,0 +1,352 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio_util::codec::Framed;
+use crate::extension::{Extension, ExtendedHandshake, ExtensionRegistry};
+use crate::peer::{Handshake, MessageFramer, MessageTag, Peer};
+use crate::torrent::skip_value;
+
+pub const NAME: &str = "ut_metadata";
+
+// metadata is exchanged in 16 KiB pieces
+pub const PIECE_SIZE: usize = 16 * 1024;
+// refuse anything a sane info dictionary would never reach
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataKind
+{
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+/// The bencoded header of a ut_metadata message; `Data` messages carry the piece right after it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetadataMessage
+{
+    pub msg_type: u8,
+    pub piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage
+{
+    pub fn request(piece: usize) -> Self
+    {
+        Self
+        {
+            msg_type: MetadataKind::Request as u8,
+            piece,
+            total_size: None,
+        }
+    }
+    pub fn reject(piece: usize) -> Self
+    {
+        Self
+        {
+            msg_type: MetadataKind::Reject as u8,
+            piece,
+            total_size: None,
+        }
+    }
+    pub fn kind(&self) -> Option<MetadataKind>
+    {
+        match self.msg_type {
+            0 => Some(MetadataKind::Request),
+            1 => Some(MetadataKind::Data),
+            2 => Some(MetadataKind::Reject),
+            _ => None,
+        }
+    }
+    /// Splits an extended message payload (without the id byte) into the header and the trailing data.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<(Self, &[u8])>
+    {
+        let end = skip_value(bytes, 0).context("ut_metadata header")?;
+        let header = serde_bencode::from_bytes(&bytes[..end]).context("Parsing ut_metadata header")?;
+        Ok((header, &bytes[end..]))
+    }
+}
+
+/// ut_metadata for one connection: downloads the info dictionary, or serves it once known.
+pub struct MetadataExtension
+{
+    info_hash: [u8; 20],
+    size: Option<usize>,
+    metadata: Vec<u8>,
+    received: Vec<bool>,
+    requested: bool,
+    complete: bool,
+}
+
+impl MetadataExtension
+{
+    pub fn fetching(info_hash: [u8; 20]) -> Self
+    {
+        Self
+        {
+            info_hash,
+            size: None,
+            metadata: Vec::new(),
+            received: Vec::new(),
+            requested: false,
+            complete: false,
+        }
+    }
+    /// Serves `metadata`, the raw info dictionary, to peers that ask.
+    pub fn serving(info_hash: [u8; 20], metadata: Vec<u8>) -> Self
+    {
+        Self
+        {
+            info_hash,
+            size: Some(metadata.len()),
+            received: vec![true; metadata.len().div_ceil(PIECE_SIZE)],
+            metadata,
+            requested: true,
+            complete: true,
+        }
+    }
+    /// The verified info dictionary, once every piece arrived.
+    pub fn metadata(&self) -> Option<&[u8]>
+    {
+        self.complete.then_some(self.metadata.as_slice())
+    }
+    /// Request payloads for every piece; empty until the size is known, and after the first call.
+    pub fn requests(&mut self) -> anyhow::Result<Vec<Vec<u8>>>
+    {
+        if self.requested || self.size.is_none()
+        {
+            return Ok(Vec::new());
+        }
+        self.requested = true;
+        (0..self.received.len())
+            .map(|piece| Ok(serde_bencode::to_bytes(&MetadataMessage::request(piece))?))
+            .collect()
+    }
+    fn piece_range(&self, piece: usize) -> Option<(usize, usize)>
+    {
+        let size = self.size?;
+        let begin = piece.checked_mul(PIECE_SIZE).filter(|&begin| begin < size)?;
+        Some((begin, PIECE_SIZE.min(size - begin)))
+    }
+    fn store(&mut self, piece: usize, data: &[u8]) -> anyhow::Result<()>
+    {
+        let (begin, length) = self.piece_range(piece)
+            .with_context(|| format!("Got metadata piece {} of {}", piece, self.received.len()))?;
+        anyhow::ensure!(data.len() == length, "Metadata piece {} has {} bytes, expected {}", piece, data.len(), length);
+        if self.complete || self.received[piece]
+        {
+            return Ok(());
+        }
+
+        self.received[piece] = true;
+        self.metadata[begin..][..length].copy_from_slice(data);
+        if self.received.iter().all(|&received| received)
+        {
+            let hash: [u8; 20] = Sha1::digest(&self.metadata).into();
+            anyhow::ensure!(hash == self.info_hash, "Metadata does not match the infohash");
+            self.complete = true;
+        }
+        Ok(())
+    }
+}
+
+impl Extension for MetadataExtension
+{
+    fn name(&self) -> &'static str
+    {
+        NAME
+    }
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake)
+    {
+        if self.complete
+        {
+            handshake.metadata_size = self.size;
+        }
+    }
+    fn on_handshake(&mut self, remote: &ExtendedHandshake)
+    {
+        // an implausible size is treated as none; fetch reports it
+        if let Some(size) = remote.metadata_size.filter(|&size| size > 0 && size <= MAX_SIZE)
+        {
+            if self.size.is_none()
+            {
+                self.size = Some(size);
+                self.metadata = vec![0; size];
+                self.received = vec![false; size.div_ceil(PIECE_SIZE)];
+            }
+        }
+    }
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>
+    {
+        let (header, data) = MetadataMessage::from_bytes(payload)?;
+        match header.kind() {
+            Some(MetadataKind::Request) => {
+                let Some((begin, length)) = self.piece_range(header.piece).filter(|_| self.complete) else {
+                    return Ok(vec![serde_bencode::to_bytes(&MetadataMessage::reject(header.piece))?]);
+                };
+                let mut reply = serde_bencode::to_bytes(&MetadataMessage
+                {
+                    msg_type: MetadataKind::Data as u8,
+                    piece: header.piece,
+                    total_size: self.size,
+                })?;
+                reply.extend_from_slice(&self.metadata[begin..][..length]);
+                Ok(vec![reply])
+            }
+            Some(MetadataKind::Data) => {
+                self.store(header.piece, data)?;
+                Ok(Vec::new())
+            }
+            Some(MetadataKind::Reject) if !self.complete => anyhow::bail!("Peer rejected metadata piece {}", header.piece),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Fetches the info dictionary from the first of `peers` that has it.
+pub(crate) async fn fetch_any(peers: &[SocketAddr], info_hash: [u8; 20]) -> anyhow::Result<Vec<u8>>
+{
+    for peer in peers
+    {
+        match tokio::time::timeout(TIMEOUT, fetch(*peer, info_hash)).await {
+            Ok(Ok(metadata)) => return Ok(metadata),
+            Ok(Err(e)) => eprintln!("Fail to get metadata from peer {}: {:#}", peer, e),
+            Err(_) => eprintln!("Fail to get metadata from peer {}: timed out", peer),
+        }
+    }
+    anyhow::bail!("No peer sent the metadata")
+}
+
+/// Connects to `peer` and downloads the info dictionary with BEP 9, checking it against `info_hash`.
+pub(crate) async fn fetch(peer: SocketAddr, info_hash: [u8; 20]) -> anyhow::Result<Vec<u8>>
+{
+    let (stream, remote) = Peer::handshake(Handshake::new(info_hash), peer).await?;
+    anyhow::ensure!(remote.supports_extension_protocol(), "Peer does not support the extension protocol");
+
+    let mut framed = Framed::new(stream, MessageFramer::new());
+    let mut registry = ExtensionRegistry::new();
+    registry.register(Box::new(MetadataExtension::fetching(info_hash)));
+
+    let remote = registry.exchange(&mut framed).await?;
+    remote.extension_id(NAME).context("Peer does not support ut_metadata")?;
+    let size = remote.metadata_size.context("Peer did not send metadata_size")?;
+    anyhow::ensure!(size > 0 && size <= MAX_SIZE, "Implausible metadata size {}", size);
+
+    let extension = registry.get_mut::<MetadataExtension>().expect("registered above");
+    let requests = extension.requests()?;
+    for (piece, request) in requests.iter().enumerate()
+    {
+        let msg = registry.message(NAME, request).expect("peer supports ut_metadata");
+        framed.send(msg).await
+            .with_context(|| format!("Requesting metadata piece {}", piece))?;
+    }
+
+    loop
+    {
+        if let Some(metadata) = registry.get_mut::<MetadataExtension>().and_then(|extension| extension.metadata())
+        {
+            return Ok(metadata.to_vec());
+        }
+
+        let msg = framed.next().await.context("Peer closed the connection")?
+            .context("Deriving message")?;
+        if msg.tag != MessageTag::Extended
+        {
+            continue;
+        }
+        for reply in registry.dispatch(&msg.payload)?
+        {
+            framed.send(reply).await.context("Answering extended message")?;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_metadata_message
+{
+    use sha1::{Digest, Sha1};
+    use crate::extension::{Extension, ExtendedHandshake};
+    use crate::metadata::{MetadataExtension, MetadataKind, MetadataMessage, PIECE_SIZE};
+
+    #[test]
+    fn splits_header_and_data()
+    {
+        let bytes = b"d8:msg_typei1e5:piecei2e10:total_sizei34000eexyz";
+
+        let (header, data) = MetadataMessage::from_bytes(bytes).unwrap();
+
+        assert_eq!(header.kind(), Some(MetadataKind::Data), "Wrong kind");
+        assert_eq!(header.piece, 2, "Wrong piece");
+        assert_eq!(header.total_size, Some(34000), "Wrong total size");
+        assert_eq!(data, b"xyz", "Wrong data");
+    }
+
+    #[test]
+    fn encodes_request()
+    {
+        let bytes = serde_bencode::to_bytes(&MetadataMessage::request(3)).unwrap();
+
+        assert_eq!(bytes, b"d8:msg_typei0e5:piecei3ee");
+    }
+
+    #[test]
+    fn fetches_from_serving_side()
+    {
+        let metadata: Vec<u8> = (0..PIECE_SIZE + 100).map(|i| i as u8).collect();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        let mut seeder = MetadataExtension::serving(info_hash, metadata.clone());
+        let mut leecher = MetadataExtension::fetching(info_hash);
+
+        let mut handshake = ExtendedHandshake::default();
+        seeder.extend_handshake(&mut handshake);
+        assert_eq!(handshake.metadata_size, Some(metadata.len()), "Seeder should advertise the size");
+        assert!(leecher.requests().unwrap().is_empty(), "No requests before the size is known");
+        leecher.on_handshake(&handshake);
+
+        let requests = leecher.requests().unwrap();
+        assert_eq!(requests.len(), 2, "Should request both pieces");
+        for request in requests
+        {
+            for reply in seeder.on_message(&request).unwrap()
+            {
+                assert!(leecher.on_message(&reply).unwrap().is_empty());
+            }
+        }
+
+        assert_eq!(leecher.metadata(), Some(metadata.as_slice()), "Metadata should be complete");
+    }
+
+    #[test]
+    fn rejects_while_fetching()
+    {
+        let mut leecher = MetadataExtension::fetching([0; 20]);
+        let request = serde_bencode::to_bytes(&MetadataMessage::request(0)).unwrap();
+
+        let replies = leecher.on_message(&request).unwrap();
+
+        let (header, _) = MetadataMessage::from_bytes(&replies[0]).unwrap();
+        assert_eq!(header.kind(), Some(MetadataKind::Reject), "Should reject without metadata");
+        let reject = serde_bencode::to_bytes(&MetadataMessage::reject(0)).unwrap();
+        assert!(leecher.on_message(&reject).is_err(), "A reject should fail the fetch");
+    }
+
+    #[test]
+    fn refuses_corrupt_metadata()
+    {
+        let mut leecher = MetadataExtension::fetching([0; 20]);
+        leecher.on_handshake(&ExtendedHandshake { metadata_size: Some(3), ..Default::default() });
+
+        let mut data = b"d8:msg_typei1e5:piecei0e10:total_sizei3ee".to_vec();
+        data.extend(b"abc");
+
+        assert!(leecher.on_message(&data).is_err(), "Hash mismatch should fail");
+        assert!(leecher.metadata().is_none());
+    }
+}
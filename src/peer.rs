@@ -1,226 +1,607 @@
-use std::marker::PhantomData;
-use std::net::SocketAddrV4;
-use std::slice::from_raw_parts;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use anyhow::{ Context};
 use tokio_util::codec::{Decoder, Framed};
 use tokio_util::codec::Encoder;
 use bytes::{BytesMut, Buf};
 use futures_util::{SinkExt, StreamExt};
-use kanal::{AsyncReceiver, AsyncSender};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use crate::peer::MessageTag::{ Request};
+use tokio::sync::{mpsc, watch};
+use crate::extension::ExtensionRegistry;
+use crate::pex;
+use crate::picker::{Picker, Progress};
+use crate::pipeline::Pipeline;
+use crate::pex::PexExtension;
+use crate::seed::SeedTorrent;
 
 
-#[derive(Debug)]
-pub struct PieceMessage
+/// Everything that can be wrong with a message on the peer wire.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WireError
 {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: [u8],
+    #[error("Unknown message tag {0}")]
+    UnknownTag(u8),
+    #[error("{what} needs {expected} bytes, got {got}")]
+    Length { what: &'static str, expected: usize, got: usize },
+    #[error("{what} needs at least {min} bytes, got {got}")]
+    TooShort { what: &'static str, min: usize, got: usize },
+    #[error("Frame of length {len} is larger than {max}")]
+    FrameTooLarge { len: usize, max: usize },
+}
 
+fn exact<const N: usize>(what: &'static str, bytes: &[u8]) -> Result<[u8; N], WireError>
+{
+    bytes.try_into().map_err(|_| WireError::Length { what, expected: N, got: bytes.len() })
+}
+
+fn be_u32(bytes: &[u8]) -> u32
+{
+    u32::from_be_bytes(bytes[..4].try_into().expect("guaranty to be 4"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceMessage
+{
+    index: u32,
+    begin: u32,
+    block: Vec<u8>,
 }
 
 impl PieceMessage {
+    pub fn new(index: u32, begin: u32, block: Vec<u8>) -> Self
+    {
+        Self { index, begin, block }
+    }
     pub fn index(&self) -> u32
     {
-        u32::from_be_bytes(self.index)
+        self.index
     }
     pub fn begin(&self) -> u32
     {
-        u32::from_be_bytes(self.begin)
+        self.begin
     }
     pub fn block(&self) -> &[u8]
     {
         &self.block
     }
-    pub fn from_bytes(bytes: &[u8]) -> &Self
+    pub fn into_block(self) -> Vec<u8>
     {
-        let ptr = (&bytes[..]) as *const [u8] as *const Self;
-        unsafe { &*ptr }
+        self.block
+    }
+    /// Parses the payload of a `Piece` message: index, begin and the rest is the block.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError>
+    {
+        if bytes.len() < 8
+        {
+            return Err(WireError::TooShort { what: "Piece", min: 8, got: bytes.len() });
+        }
+        Ok(Self::new(be_u32(&bytes[0..4]), be_u32(&bytes[4..8]), bytes[8..].to_vec()))
+    }
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut bytes = Vec::with_capacity(8 + self.block.len());
+        bytes.extend(self.index.to_be_bytes());
+        bytes.extend(self.begin.to_be_bytes());
+        bytes.extend_from_slice(&self.block);
+        bytes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PeerRequest
 {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
+    index: u32,
+    begin: u32,
+    length: u32,
 }
 
 impl PeerRequest {
     pub fn new(index: u32, begin: u32, length: u32) -> Self
     {
-        Self
-        {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
-        }
+        Self { index, begin, length }
+    }
+    /// Parses the 12 byte payload of a `Request` or `Cancel` message.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError>
+    {
+        let bytes: [u8; 12] = exact("Request", bytes)?;
+        Ok(Self::new(be_u32(&bytes[0..4]), be_u32(&bytes[4..8]), be_u32(&bytes[8..12])))
     }
     pub fn index(&self) -> u32
     {
-        u32::from_be_bytes(self.index)
+        self.index
     }
     pub fn begin(&self) -> u32
     {
-        u32::from_be_bytes(self.begin)
+        self.begin
     }
     pub fn length(&self) -> u32
     {
-        u32::from_be_bytes(self.length)
+        self.length
+    }
+    pub fn to_bytes(&self) -> [u8; 12]
+    {
+        let mut bytes = [0u8; 12];
+        bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+}
+
+/// A checked peer wire message; `Message` is its raw tag and payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage
+{
+    Choke,
+    UnChoke,
+    Interested,
+    NonInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(PeerRequest),
+    Piece(PieceMessage),
+    Cancel(PeerRequest),
+    // the extended message id followed by its payload
+    Extended(Vec<u8>),
+}
+
+impl TryFrom<Message> for PeerMessage
+{
+    type Error = WireError;
+
+    fn try_from(msg: Message) -> Result<Self, Self::Error>
+    {
+        let empty = |what, message| match msg.payload.is_empty() {
+            true => Ok(message),
+            false => Err(WireError::Length { what, expected: 0, got: msg.payload.len() }),
+        };
+        match msg.tag {
+            MessageTag::Choke => empty("Choke", Self::Choke),
+            MessageTag::UnChoke => empty("Unchoke", Self::UnChoke),
+            MessageTag::Interested => empty("Interested", Self::Interested),
+            MessageTag::NonInterested => empty("Not interested", Self::NonInterested),
+            MessageTag::Have => Ok(Self::Have(u32::from_be_bytes(exact("Have", &msg.payload)?))),
+            MessageTag::Bitfield => Ok(Self::Bitfield(msg.payload)),
+            MessageTag::Request => Ok(Self::Request(PeerRequest::from_bytes(&msg.payload)?)),
+            MessageTag::Piece => Ok(Self::Piece(PieceMessage::from_bytes(&msg.payload)?)),
+            MessageTag::Cancel => Ok(Self::Cancel(PeerRequest::from_bytes(&msg.payload)?)),
+            MessageTag::Extended if msg.payload.is_empty() => Err(WireError::TooShort { what: "Extended", min: 1, got: 0 }),
+            MessageTag::Extended => Ok(Self::Extended(msg.payload)),
+        }
+    }
+}
+
+impl From<PeerMessage> for Message
+{
+    fn from(msg: PeerMessage) -> Self
+    {
+        let (tag, payload) = match msg {
+            PeerMessage::Choke => (MessageTag::Choke, vec![]),
+            PeerMessage::UnChoke => (MessageTag::UnChoke, vec![]),
+            PeerMessage::Interested => (MessageTag::Interested, vec![]),
+            PeerMessage::NonInterested => (MessageTag::NonInterested, vec![]),
+            PeerMessage::Have(index) => (MessageTag::Have, index.to_be_bytes().to_vec()),
+            PeerMessage::Bitfield(bitfield) => (MessageTag::Bitfield, bitfield),
+            PeerMessage::Request(request) => (MessageTag::Request, request.to_bytes().to_vec()),
+            PeerMessage::Piece(piece) => (MessageTag::Piece, piece.to_bytes()),
+            PeerMessage::Cancel(request) => (MessageTag::Cancel, request.to_bytes().to_vec()),
+            PeerMessage::Extended(payload) => (MessageTag::Extended, payload),
+        };
+        Message { tag, payload }
+    }
+}
+
+/// Why a connection was dropped: the peer broke the protocol.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PeerError
+{
+    #[error("Peer closed the connection")]
+    Closed,
+    #[error("Not a BitTorrent handshake")]
+    WrongProtocol,
+    #[error("Peer serves infohash {0}")]
+    InfoHashMismatch(String),
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+    #[error("Malformed message: {0}")]
+    Malformed(#[from] WireError),
+    #[error("Bitfield after other messages")]
+    LateBitfield,
+    #[error("Bitfield of {got} bytes, the torrent needs {expected}")]
+    BitfieldLength { expected: usize, got: usize },
+    #[error("Bitfield sets bits past the last piece")]
+    BitfieldSpareBits,
+    #[error("Torrent has no piece {0}")]
+    InvalidPiece(u32),
+    #[error("Block {begin} of piece {index} has {got} bytes, requested {expected}")]
+    WrongBlockLength { index: u32, begin: u32, expected: u32, got: usize },
+    #[error("Sent piece {0}, which failed its hash check")]
+    CorruptPiece(u32),
+}
+
+/// What a message means to the download.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Event
+{
+    Nothing,
+    Choked,
+    Unchoked,
+    Requested(PeerRequest),
+    Cancelled(PeerRequest),
+    Block { index: u32, begin: u32, block: Vec<u8> },
+}
+
+/// The BEP 3 state of one connection, from our side and the peer's.
+#[derive(Debug)]
+pub(crate) struct PeerState
+{
+    pub(crate) am_choking: bool,
+    pub(crate) am_interested: bool,
+    pub(crate) peer_choking: bool,
+    pub(crate) peer_interested: bool,
+    // a bitfield is only allowed as the first message
+    started: bool,
+    // what `Have` and `Bitfield` are checked against
+    pieces: usize,
+}
+
+impl PeerState
+{
+    /// For a torrent of `pieces` pieces.
+    pub(crate) fn new(pieces: usize) -> Self
+    {
+        Self
+        {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            started: false,
+            pieces,
+        }
     }
-    pub fn to_bytes(&self) -> &[u8]
+    /// Applies one non-extended message; `Have` and `Bitfield` go into `bitfield`.
+    pub(crate) fn on_message(&mut self, msg: Message, bitfield: &mut Bitfield) -> Result<Event, PeerError>
     {
-        unsafe {
-            from_raw_parts(
-                self as *const Self as *const u8,
-                std::mem::size_of::<Self>(),
-            )
+        let started = std::mem::replace(&mut self.started, true);
+        match PeerMessage::try_from(msg)? {
+            PeerMessage::Choke => {
+                self.peer_choking = true;
+                Ok(Event::Choked)
+            }
+            PeerMessage::UnChoke => {
+                self.peer_choking = false;
+                Ok(Event::Unchoked)
+            }
+            PeerMessage::Interested => {
+                self.peer_interested = true;
+                Ok(Event::Nothing)
+            }
+            PeerMessage::NonInterested => {
+                self.peer_interested = false;
+                Ok(Event::Nothing)
+            }
+            PeerMessage::Have(index) if index as usize >= self.pieces => Err(PeerError::InvalidPiece(index)),
+            PeerMessage::Have(index) => {
+                bitfield.set_piece(index);
+                Ok(Event::Nothing)
+            }
+            PeerMessage::Bitfield(_) if started => Err(PeerError::LateBitfield),
+            PeerMessage::Bitfield(payload) => {
+                let expected = self.pieces.div_ceil(8);
+                if payload.len() != expected
+                {
+                    return Err(PeerError::BitfieldLength { expected, got: payload.len() });
+                }
+                let received = Bitfield::from_bytes(&payload);
+                if (self.pieces..expected * 8).any(|spare| received.has_piece(spare as u32))
+                {
+                    return Err(PeerError::BitfieldSpareBits);
+                }
+                *bitfield = received;
+                Ok(Event::Nothing)
+            }
+            // requests while we choke are dropped, as the peer should know
+            PeerMessage::Request(_) | PeerMessage::Cancel(_) if self.am_choking => Ok(Event::Nothing),
+            PeerMessage::Request(request) => Ok(Event::Requested(request)),
+            PeerMessage::Cancel(request) => Ok(Event::Cancelled(request)),
+            PeerMessage::Piece(piece) => Ok(Event::Block { index: piece.index(), begin: piece.begin(), block: piece.into_block() }),
+            // dispatched before it gets here
+            PeerMessage::Extended(_) => Ok(Event::Nothing),
         }
     }
 }
+
 #[derive(Debug)]
 pub(crate) struct Peer
 {
-    addr: SocketAddrV4,
+    addr: SocketAddr,
     stream: Framed<TcpStream, MessageFramer>,
-    bitfield: Bitfield
+    bitfield: Bitfield,
+    extensions: ExtensionRegistry,
+    pipeline: Pipeline,
+    state: PeerState,
+    last_sent: Instant,
+    remote: Handshake,
 }
 
 impl Peer {
- pub const BLOCK_MAX: u32 = 2 << 14;
-    pub async fn new(socket: SocketAddrV4, hash_info: [u8;20]) -> anyhow::Result<Self>
+ pub const BLOCK_MAX: u32 = 1 << 14;
+    // peers drop connections silent for two minutes
+    pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+    // how long connecting waits for an unchoke before leaving it to the download
+    pub const UNCHOKE_WAIT: Duration = Duration::from_secs(10);
+    /// `max_requests` caps the blocks requested at once; the peer's `reqq` may lower it.
+    /// `pieces` is the torrent's piece count, which the peer's `Have` and `Bitfield` must fit.
+    pub async fn new(socket: SocketAddr, hash_info: [u8;20], pieces: usize, extensions: ExtensionRegistry, max_requests: usize) -> anyhow::Result<Self>
+    {
+        Self::connect(socket, hash_info, pieces, extensions, max_requests, Self::UNCHOKE_WAIT).await
+    }
+    async fn connect(socket: SocketAddr, hash_info: [u8;20], pieces: usize, extensions: ExtensionRegistry, max_requests: usize, unchoke_wait: Duration) -> anyhow::Result<Self>
     {
-       let tcp_stream =  Peer::handshake(hash_info,&socket).await?;
-        let (framed,bitfield) = Peer::create_connection(tcp_stream).await?;
-      Ok(
-          Self
+        let (tcp_stream, remote) =  Peer::handshake(Handshake::new(hash_info), socket).await?;
+        let mut peer = Self
         {
             addr: socket,
-            stream: framed,
-            bitfield
+            stream: Framed::new(tcp_stream, MessageFramer::for_pieces(pieces)),
+            bitfield: Bitfield::new(pieces),
+            extensions,
+            pipeline: Pipeline::new(max_requests),
+            state: PeerState::new(pieces),
+            last_sent: Instant::now(),
+            remote,
+        };
+        if peer.remote.supports_extension_protocol()
+        {
+            let handshake = peer.extensions.handshake().to_message()?;
+            peer.send(handshake).await.context("Sending extended handshake")?;
         }
-      )
+        peer.create_connection(unchoke_wait).await?;
+        Ok(peer)
     }
-    async fn handshake(hash_info: [u8; 20], socket: &SocketAddrV4) -> anyhow::Result<TcpStream>
+    /// Connects and exchanges handshakes, returning the remote side's handshake once it is for our torrent.
+    pub(crate) async fn handshake(handshake: Handshake, socket: SocketAddr) -> anyhow::Result<(TcpStream, Handshake)>
     {
-        let mut handshake = Handshake::new(hash_info);
-        let mut peer = TcpStream::connect(socket).await.context("Creating connection to peer")?;
-        let handshake_bytes = handshake.to_bytes_mut();
-        peer.write_all(&handshake_bytes).await.context("Writing to peer")?;
+        let exchange = async {
+            let mut peer = TcpStream::connect(socket).await.context("Creating connection to peer")?;
+            peer.write_all(&handshake.to_bytes()).await.context("Writing to peer")?;
 
-        let mut buffer = Vec::new();
-        peer.read_to_end(&mut buffer).await?;
-        let response_handshake = Handshake::from_bytes(&buffer).context("Getting back handshake")?;
-        println!("Handshake: {:?}", response_handshake);
-        Ok(peer)
+            let remote = Handshake::read(&mut peer).await.context("Getting back handshake")?;
+            if remote.info_hash != handshake.info_hash
+            {
+                return Err(PeerError::InfoHashMismatch(hex::encode(remote.info_hash)).into());
+            }
+            Ok((peer, remote))
+        };
+        tokio::time::timeout(Handshake::TIMEOUT, exchange).await.map_err(|_| PeerError::HandshakeTimeout)?
     }
 
-    async fn create_connection(tcp_stream: TcpStream) -> anyhow::Result<(Framed<TcpStream,MessageFramer>, Bitfield)>
+    /// Declares interest and waits a while for the peer to unchoke us, taking in its pieces on
+    /// the way. A peer that still chokes us after `wait` is kept; `download` waits for it.
+    async fn create_connection(&mut self, wait: Duration) -> anyhow::Result<()>
     {
-        let mut framed = Framed::new(tcp_stream, MessageFramer);
-        let msg = framed.next().await.expect("Peer always sends first message")
-            .context("Deriving message")?;
-        anyhow::ensure!(msg.tag == MessageTag::Bitfield, "Should has bitfield tag");
-        let bitfield = Bitfield::from_bytes(&msg.payload);
-        framed.send(
+        self.state.am_interested = true;
+        self.send(
             Message
             {
                 tag: MessageTag::Interested,
                 payload: vec![],
             }
         ).await.context("Sending 'interesting' message")?;
-        let msg = framed.next().await.expect("Peer always sends first message")
-            .context("Deriving message")?;
-
-        anyhow::ensure!(msg.tag == MessageTag::UnChoke, "Should have message tag 'Unchoke'");
-        Ok((framed, bitfield))
+        let deadline = tokio::time::Instant::now() + wait;
+        while self.state.peer_choking
+        {
+            match tokio::time::timeout_at(deadline, self.next_event()).await {
+                Ok(event) => event?,
+                Err(_) => break,
+            };
+        }
+        Ok(())
+    }
+    async fn send(&mut self, msg: Message) -> anyhow::Result<()>
+    {
+        self.stream.send(msg).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+    /// Sends a keep-alive if we said nothing for `KEEP_ALIVE_INTERVAL`.
+    async fn keep_alive(&mut self) -> anyhow::Result<()>
+    {
+        if self.last_sent.elapsed() >= Self::KEEP_ALIVE_INTERVAL
+        {
+            self.stream.send(KeepAlive).await.context("Sending keep-alive")?;
+            self.last_sent = Instant::now();
+        }
+        Ok(())
+    }
+    /// The next message that is not for an extension; extended messages are answered on the way,
+    /// and keep-alives go out while we wait.
+    async fn next_message(&mut self) -> anyhow::Result<Message>
+    {
+        loop {
+            let idle = Self::KEEP_ALIVE_INTERVAL.saturating_sub(self.last_sent.elapsed());
+            let msg = tokio::select! {
+                msg = self.stream.next() => msg,
+                _ = tokio::time::sleep(idle) => {
+                    self.keep_alive().await?;
+                    continue;
+                }
+            };
+            let msg = msg.ok_or(PeerError::Closed)?.context("Deriving message")?;
+            if msg.tag != MessageTag::Extended
+            {
+                return Ok(msg);
+            }
+            for reply in self.extensions.dispatch(&msg.payload)?
+            {
+                self.send(reply).await.context("Answering extended message")?;
+            }
+        }
+    }
+    async fn next_event(&mut self) -> anyhow::Result<Event>
+    {
+        let msg = self.next_message().await?;
+        Ok(self.state.on_message(msg, &mut self.bitfield)?)
+    }
+    /// Tells the peer which of `connected` joined or left since last time, if it speaks ut_pex.
+    pub(crate) async fn exchange_peers(&mut self, connected: &[SocketAddr]) -> anyhow::Result<()>
+    {
+        let Some(pex) = self.extensions.get_mut::<PexExtension>() else {
+            return Ok(());
+        };
+        let others: Vec<_> = connected.iter().copied().filter(|addr| *addr != self.addr).collect();
+        let Some(payload) = pex.update(&others, Instant::now())? else {
+            return Ok(());
+        };
+        if let Some(msg) = self.extensions.message(pex::NAME, &payload)
+        {
+            self.send(msg).await.context("Sending ut_pex message")?;
+        }
+        Ok(())
     }
-    pub(crate) async fn participate(
+    /// Asks for blocks from `picker` until the download is over, keeping the pipeline full
+    /// across piece boundaries, and reports finished pieces to `progress`. Blocks still in
+    /// flight on failure go back to the picker; `id` is how the picker knows this peer.
+    /// `seed`'s choker hears how fast the peer serves us.
+    pub(crate) async fn download(
         &mut self,
-        piece_i: u32,
-        piece_size: u32,
-        nblocks: u32,
-        sender: AsyncSender<u32>,
-        reciever: AsyncReceiver<u32>,
-        finish: tokio::sync::mpsc::Sender<Message>
+        id: usize,
+        picker: &Picker,
+        progress: &mpsc::Sender<Progress>,
+        connected: &watch::Receiver<Vec<SocketAddr>>,
+        seed: Option<&SeedTorrent>,
     ) -> anyhow::Result<()>
     {
-        while let Ok(block) = reciever.recv().await {
-
-            let md = piece_size % Peer::BLOCK_MAX;
-            let block_size = match block == nblocks {
-                true if md != 0 => md,
-                _ => Peer::BLOCK_MAX
-            };
-
-            let request = PeerRequest::new(piece_i, block as u32 * Peer::BLOCK_MAX, block_size);
-
-            self.stream.send(
-                Message
-                {
-                    tag: Request,
-                    payload: request.to_bytes().to_vec(),
-                }
-            ).await.
-                with_context(||
-                    format!("request with index: {}, len: {}, begin: {}",
-                            request.index(), request.length(), request.begin()
-                    )
-                )?;
-            let msg = self.stream.next().await.expect("Peer always sends first message")
-                .context("Deriving piece message")?;
-
-            anyhow::ensure!(msg.tag == MessageTag::Piece, "Waiting for the 'Piece message'");
-            anyhow::ensure!(!msg.payload.is_empty(), "Shouldn't be empty");
-
-            finish.send(msg).await?;
-
+        let downloaded = self.pipelined(id, picker, progress, connected, seed).await;
+        if let Some(seed) = seed
+        {
+            seed.set_waiting(self.addr.ip(), false);
+        }
+        if let Err(e) = downloaded
+        {
+            // hand the blocks back so another peer can pick them up
+            picker.give_back(self.pipeline.drain());
+            // the peer id starts with the client's name and version, e.g. `-qB4650-`
+            let client = String::from_utf8_lossy(&self.remote.peer_id[..8]).into_owned();
+            return Err(e.context(format!("Peer {} ({})", self.addr, client)));
         }
-
         Ok(())
-
     }
 
-     async fn download(&mut self, piece_i: u32, block_i: u32, block_size: u32 ) -> anyhow::Result<Vec<u8>>
+    async fn pipelined(
+        &mut self,
+        id: usize,
+        picker: &Picker,
+        progress: &mpsc::Sender<Progress>,
+        connected: &watch::Receiver<Vec<SocketAddr>>,
+        seed: Option<&SeedTorrent>,
+    ) -> anyhow::Result<()>
     {
-         let request = PeerRequest::new(piece_i, (block_i * Self::BLOCK_MAX), block_size);
-            // TODO! add safe casting
-
-            self.stream.send(
-                Message
+        let mut work = picker.subscribe();
+        let mut pex_due = Instant::now();
+        loop {
+            if pex_due <= Instant::now()
+            {
+                let connected = connected.borrow().clone();
+                if let Err(e) = self.exchange_peers(&connected).await
                 {
-                    tag: Request,
-                    payload: request.to_bytes().to_vec(),
+                    eprintln!("Fail to exchange peers with {}: {:#}", self.addr, e);
                 }
-            ).await.
-                with_context(||
-                    format!("request with index: {}, len: {}, begin: {}",
-                            request.index(), request.length(), request.begin()
-                    )
-                )?;
-            let msg = self.stream.next().await.expect("Peer always sends first message")
-                .context("Deriving piece message")?;
-            anyhow::ensure!(msg.tag == MessageTag::Piece, " Should be a 'Piece' message.");
-            anyhow::ensure!(!msg.payload.is_empty(), "Piece is empty");
-
+                pex_due = Instant::now() + pex::INTERVAL;
+            }
 
-        let pieces = PieceMessage::from_bytes(&msg.payload);
-        anyhow::ensure!(
-            pieces.index() == piece_i &&
-            pieces.begin() == block_i &&
-            pieces.block().len() == block_size as usize,
-            "Wrong return data");
+            work.borrow_and_update();
+            if let Some(index) = picker.banned(id)
+            {
+                return Err(PeerError::CorruptPiece(index).into());
+            }
+            self.pipeline.set_remote_max(self.extensions.remote().and_then(|remote| remote.reqq));
+            while !self.state.peer_choking && self.pipeline.wants_more()
+            {
+                let Some(request) = picker.pick(id, &self.bitfield) else {
+                    break;
+                };
+                self.request(request).await?;
+            }
+            if let Some(seed) = seed
+            {
+                seed.set_waiting(self.addr.ip(), !self.pipeline.is_empty());
+            }
 
-        Ok(Vec::from(&pieces.block))
+            let event = if !self.state.peer_choking && self.pipeline.is_empty()
+            {
+                // nothing to ask for until blocks come back or the peer gets new pieces
+                picker.idle(id);
+                progress.send(Progress::Idle).await?;
+                tokio::select! {
+                    changed = work.changed() => {
+                        changed?;
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(pex_due.into()) => continue,
+                    event = self.next_event() => event,
+                }
+            } else {
+                self.next_event().await
+            };
+            match event.context("Deriving piece message")? {
+                Event::Block { index, begin, block } => {
+                    // a block cancelled earlier may still arrive
+                    let Some(request) = self.pipeline.received(index, begin, Instant::now()) else {
+                        continue;
+                    };
+                    if block.len() != request.length() as usize
+                    {
+                        picker.give_back(vec![request]);
+                        return Err(PeerError::WrongBlockLength { index, begin, expected: request.length(), got: block.len() }.into());
+                    }
+                    if let Some(seed) = seed
+                    {
+                        seed.record_download(self.addr.ip(), block.len());
+                    }
+                    if let Some(data) = picker.received(id, request, &block)
+                    {
+                        progress.send(Progress::Piece { index, data }).await?;
+                    }
+                }
+                // a choking peer drops our requests; others may serve them until it unchokes us
+                Event::Choked => picker.give_back(self.pipeline.drain()),
+                // we never unchoke a peer we download from, so there is nothing to serve
+                Event::Unchoked | Event::Requested(_) | Event::Cancelled(_) | Event::Nothing => {}
+            }
+        }
+    }
 
+    async fn request(&mut self, request: PeerRequest) -> anyhow::Result<()>
+    {
+        self.send(
+            Message
+            {
+                tag: MessageTag::Request,
+                payload: request.to_bytes().to_vec(),
+            }
+        ).await.
+            with_context(||
+                format!("request with index: {}, len: {}, begin: {}",
+                        request.index(), request.length(), request.begin()
+                )
+            )?;
+        self.pipeline.sent(request);
+        Ok(())
+    }
+    pub fn addr(&self) -> SocketAddr
+    {
+        self.addr
     }
     pub fn has_piece(&self,piece_i: u32) -> bool
     {
         self.bitfield.has_piece(piece_i)
     }
+    pub(crate) fn bitfield(&self) -> &Bitfield
+    {
+        &self.bitfield
+    }
 }
 
 #[derive(Debug)]
@@ -230,6 +611,11 @@ pub(crate) struct Bitfield
 }
 impl Bitfield
 {
+    /// No pieces of `pieces`.
+    pub(crate) fn new(pieces: usize) -> Self
+    {
+        Self { payload: vec![0u8; pieces.div_ceil(8)] }
+    }
     pub(crate) fn from_bytes(payload: &[u8]) -> Self
     {
         Self
@@ -237,6 +623,28 @@ impl Bitfield
             payload: Vec::from(payload)
         }
     }
+    /// The first piece goes into the high bit of the first byte.
+    pub(crate) fn from_have(have: &[bool]) -> Self
+    {
+        let mut payload = vec![0u8; have.len().div_ceil(8)];
+        for (piece_i, _) in have.iter().enumerate().filter(|(_, have)| **have)
+        {
+            payload[piece_i / 8] |= 0x80 >> (piece_i % 8);
+        }
+        Self { payload }
+    }
+    pub(crate) fn payload(&self) -> &[u8]
+    {
+        &self.payload
+    }
+    /// Marks a piece announced with `Have`; pieces past the end are ignored, never grown into.
+    pub(crate) fn set_piece(&mut self, piece_i: u32)
+    {
+        if let Some(byte) = self.payload.get_mut((piece_i / u8::BITS) as usize)
+        {
+            *byte |= 0x80 >> (piece_i % u8::BITS);
+        }
+    }
     pub(crate) fn has_piece(&self, piece_i: u32) -> bool
     {
         let byte = piece_i / u8::BITS;
@@ -246,22 +654,10 @@ impl Bitfield
         };
         (byte & 1_u8.rotate_right(bit + 1)) != 0
     }
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_
-    {
-        self.payload.iter().enumerate().flat_map(|(byte_i, byte)|
-            {
-                (0..u8::BITS).filter_map(move |bit_i|
-                    {
-                        let piece_i = byte_i * u8::BITS as usize + bit_i as usize;
-                        let mask = 1_u8.rotate_right(bit_i + 1);
-                        let mask = mask >> 1;
-                      (byte & mask != 0).then(||piece_i)
-                    })
-            })
-    }}
+}
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake
 {
     length: u8,
@@ -274,47 +670,82 @@ pub struct Handshake
 impl Handshake
 {
     const SIZE: usize = 68;
+    const PROTOCOL: &'static [u8; 19] = b"BitTorrent protocol";
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
 
     pub fn new(info_hash: [u8; 20]) -> Self
     {
         Self
         {
             length: 19,
-            bit_torrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            bit_torrent: *Self::PROTOCOL,
+            // advertise the BEP 10 extension protocol (bit 20 from the right)
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0],
             info_hash,
             peer_id: *b"00112233445566778890",
         }
     }
-    pub fn to_bytes_mut(&mut self) -> &mut [u8]
+    pub fn supports_extension_protocol(&self) -> bool
     {
-        unsafe {
-            std::slice::from_raw_parts_mut(
-                self as *mut Self as *mut u8,
-                std::mem::size_of::<Self>(),
-            )
-        }
+        self.reserved[5] & 0x10 != 0
+    }
+    pub fn info_hash(&self) -> [u8; 20]
+    {
+        self.info_hash
+    }
+    pub fn peer_id(&self) -> [u8; 20]
+    {
+        self.peer_id
+    }
+    pub fn reserved(&self) -> [u8; 8]
+    {
+        self.reserved
     }
-    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>
+    /// Reads exactly one handshake, rejecting anything but the BitTorrent protocol
+    /// before waiting for the rest of it.
+    pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Self>
     {
-        if bytes.len() != Self::SIZE
+        let mut bytes = [0u8; Self::SIZE];
+        stream.read_exact(&mut bytes[..1]).await.context("Reading protocol length")?;
+        if bytes[0] as usize != Self::PROTOCOL.len()
         {
-            return Err(anyhow::Error::msg("Should be 68 length bytes array"));
+            return Err(PeerError::WrongProtocol.into());
         }
+        stream.read_exact(&mut bytes[1..20]).await.context("Reading protocol")?;
+        if &bytes[1..20] != Self::PROTOCOL
+        {
+            return Err(PeerError::WrongProtocol.into());
+        }
+        stream.read_exact(&mut bytes[20..]).await.context("Reading handshake")?;
+        Ok(Self::from_bytes(&bytes)?)
+    }
+    pub fn to_bytes(&self) -> [u8; Self::SIZE]
+    {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0] = self.length;
+        bytes[1..20].copy_from_slice(&self.bit_torrent);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError>
+    {
+        let bytes: [u8; Self::SIZE] = exact("Handshake", bytes)?;
         let handshake = Self
         {
             length: bytes[0],
-            bit_torrent: bytes[1..20].try_into().context("Creating bit torrent array")?,
-            reserved: bytes[20..28].try_into().context("Creating reserved array")?,
-            info_hash: bytes[28..48].try_into().context("Creating info hash array")?,
-            peer_id: bytes[48..68].try_into().context("Creating peer id array")?,
+            bit_torrent: bytes[1..20].try_into().expect("guaranty to be 19"),
+            reserved: bytes[20..28].try_into().expect("guaranty to be 8"),
+            info_hash: bytes[28..48].try_into().expect("guaranty to be 20"),
+            peer_id: bytes[48..68].try_into().expect("guaranty to be 20"),
         };
         Ok(handshake)
     }
 }
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageTag
 {
@@ -327,6 +758,29 @@ pub enum MessageTag
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
+}
+
+impl TryFrom<u8> for MessageTag
+{
+    type Error = WireError;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error>
+    {
+        Ok(match tag {
+            0 => Self::Choke,
+            1 => Self::UnChoke,
+            2 => Self::Interested,
+            3 => Self::NonInterested,
+            4 => Self::Have,
+            5 => Self::Bitfield,
+            6 => Self::Request,
+            7 => Self::Piece,
+            8 => Self::Cancel,
+            20 => Self::Extended,
+            tag => return Err(WireError::UnknownTag(tag)),
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -337,10 +791,53 @@ pub struct Message
 }
 
 
+/// The zero-length message that keeps an idle connection open.
 #[derive(Debug)]
-pub struct MessageFramer;
+pub struct KeepAlive;
 
-const MAX: usize = 2 ^ 16;
+/// Length-prefixed peer wire frames: four bytes of length, the tag, then the payload.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageFramer
+{
+    max_frame: usize,
+}
+
+// room for the bencoded header of a ut_metadata piece, and for a generous extended handshake or ut_pex message
+const EXTENSION_HEADROOM: usize = 8 * 1024;
+
+impl MessageFramer
+{
+    // a block with its tag, index and begin, plus extension headroom
+    pub const DEFAULT_MAX_FRAME: usize = Peer::BLOCK_MAX as usize + 13 + EXTENSION_HEADROOM;
+
+    /// For connections whose torrent is not known yet, as when fetching metadata.
+    pub fn new() -> Self
+    {
+        Self { max_frame: Self::DEFAULT_MAX_FRAME }
+    }
+    /// Fits every message of a torrent with `pieces` pieces, the bitfield included.
+    pub fn for_pieces(pieces: usize) -> Self
+    {
+        Self::with_max_frame((Peer::BLOCK_MAX as usize + 13).max(pieces.div_ceil(8) + 1) + EXTENSION_HEADROOM)
+    }
+    /// Frames (tag and payload) longer than `max_frame` are refused both ways.
+    pub fn with_max_frame(max_frame: usize) -> Self
+    {
+        Self { max_frame }
+    }
+    fn too_large(&self, len: usize) -> std::io::Error
+    {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, WireError::FrameTooLarge { len, max: self.max_frame })
+    }
+}
+
+impl Default for MessageFramer
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
 
 impl Decoder for MessageFramer {
     type Item = Message;
@@ -350,74 +847,45 @@ impl Decoder for MessageFramer {
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
-            // Not enough data to read length marker.
-            return Ok(None);
-        }
-
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
-
-        if length == 0
-        {
-            src.advance(4); // heartbeat messages
-            return self.decode(src);
-        }
-        if src.len() < 5 { // if not enough for tag + len
-            return Ok(None);
-        }
-
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if length > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
-            ));
-        }
-
+        loop {
+            if src.len() < 4 {
+                // Not enough data to read length marker.
+                return Ok(None);
+            }
 
-        if src.len() < 4 + length {
-            // The full string has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+            // Read length marker.
+            let length = be_u32(&src[..4]) as usize;
+            if length == 0
+            {
+                src.advance(4); // heartbeat messages
+                continue;
+            }
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
-        }
+            // Check that the length is not too large to avoid a denial of
+            // service attack where the server runs out of memory.
+            if length > self.max_frame {
+                return Err(self.too_large(length));
+            }
 
-        if src[4] > 8
+            if src.len() < 4 + length {
+                // The full frame has not yet arrived.
+                //
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + length - src.len());
 
-        {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid message Tag. Expected a value in the range [0, 8], found: {}", src[5]),
-            ));
-        }
-        let message_tag: MessageTag = unsafe { std::mem::transmute(src[4]) };
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
 
-        let data = if src.len() > 5 {
-            let vec = src[4..4 + length - 1].to_vec();
+            let tag = MessageTag::try_from(src[4])
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let payload = src[5..4 + length].to_vec();
             src.advance(4 + length);
-            vec
-        } else {
-            vec![]
-        };
 
-        Ok(
-            Some(
-                Message
-                {
-                    tag: message_tag,
-                    payload: data,
-                }
-            )
-        )
+            return Ok(Some(Message { tag, payload }));
+        }
     }
 }
 
@@ -427,25 +895,28 @@ impl Encoder<Message> for MessageFramer {
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a message if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1 > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len() + 1),
-            ));
+        let length = 1 + item.payload.len();
+        if length > self.max_frame {
+            return Err(self.too_large(length));
         }
 
-        // Convert the length into a byte array.
+        // Reserve space in the buffer.
+        dst.reserve(4 + length);
+
+        // Write the length, tag and payload to the buffer.
         // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
+        dst.extend_from_slice(&(length as u32).to_be_bytes());
+        dst.extend_from_slice(&[item.tag as u8]);
+        dst.extend_from_slice(&item.payload);
+        Ok(())
+    }
+}
 
-        // Reserve space in the buffer.
-        dst.reserve(4 + 1 + item.payload.len());
+impl Encoder<KeepAlive> for MessageFramer {
+    type Error = std::io::Error;
 
-        let tag: u8 = unsafe { std::mem::transmute(item.tag) };
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.extend_from_slice(&[tag]);
-        dst.extend_from_slice(item.payload.as_slice());
+    fn encode(&mut self, _: KeepAlive, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&0u32.to_be_bytes());
         Ok(())
     }
 }
@@ -485,6 +956,400 @@ mod test_handhaske_conversion
         assert_eq!(handshake.length, 19, "Wrong len");
         assert_eq!(handshake.bit_torrent, *b"BitTorrent protocol", "Wrong bitorrent");
         assert_eq!(handshake.peer_id, *b"00112233445566778890", "Wrong peer id");
-        assert_eq!(handshake.reserved, [0; 8], "Wrong reserved");
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0], "Wrong reserved");
+        assert!(handshake.supports_extension_protocol(), "Extension bit should be set");
+    }
+}
+
+#[cfg(test)]
+mod test_peer_state
+{
+    use crate::peer::{Bitfield, Event, Message, MessageTag, PeerError, PeerState, WireError};
+
+    fn message(tag: MessageTag, payload: &[u8]) -> Message
+    {
+        Message { tag, payload: payload.to_vec() }
+    }
+
+    #[test]
+    fn tracks_choke_and_interest()
+    {
+        let mut state = PeerState::new(1);
+        let mut bitfield = Bitfield::new(1);
+
+        assert_eq!(state.on_message(message(MessageTag::UnChoke, &[]), &mut bitfield), Ok(Event::Unchoked));
+        assert!(!state.peer_choking);
+        state.on_message(message(MessageTag::Interested, &[]), &mut bitfield).unwrap();
+        assert!(state.peer_interested);
+        assert_eq!(state.on_message(message(MessageTag::Choke, &[]), &mut bitfield), Ok(Event::Choked));
+        assert!(state.peer_choking);
+        let request = crate::peer::PeerRequest::new(0, 0, 4);
+        assert_eq!(state.on_message(message(MessageTag::Request, &request.to_bytes()), &mut bitfield), Ok(Event::Nothing), "We choke the peer");
+        state.am_choking = false;
+        assert_eq!(state.on_message(message(MessageTag::Request, &request.to_bytes()), &mut bitfield), Ok(Event::Requested(request)));
+    }
+
+    #[test]
+    fn updates_bitfield()
+    {
+        let mut state = PeerState::new(10);
+        let mut bitfield = Bitfield::new(10);
+
+        state.on_message(message(MessageTag::Bitfield, &[0x80, 0]), &mut bitfield).unwrap();
+        state.on_message(message(MessageTag::Have, &9u32.to_be_bytes()), &mut bitfield).unwrap();
+
+        assert!(bitfield.has_piece(0) && bitfield.has_piece(9), "Bitfield and have");
+        assert!(!bitfield.has_piece(1) && !bitfield.has_piece(8));
+        assert_eq!(
+            state.on_message(message(MessageTag::Bitfield, &[0xff]), &mut bitfield),
+            Err(PeerError::LateBitfield)
+        );
+    }
+
+    #[test]
+    fn rejects_pieces_outside_the_torrent()
+    {
+        let mut bitfield = Bitfield::new(10);
+
+        assert_eq!(PeerState::new(10).on_message(message(MessageTag::Have, &10u32.to_be_bytes()), &mut bitfield), Err(PeerError::InvalidPiece(10)));
+        assert_eq!(PeerState::new(10).on_message(message(MessageTag::Have, &u32::MAX.to_be_bytes()), &mut bitfield), Err(PeerError::InvalidPiece(u32::MAX)));
+        assert_eq!(
+            PeerState::new(10).on_message(message(MessageTag::Bitfield, &[0xff; 3]), &mut bitfield),
+            Err(PeerError::BitfieldLength { expected: 2, got: 3 })
+        );
+        assert_eq!(
+            PeerState::new(10).on_message(message(MessageTag::Bitfield, &[0xff, 0xe0]), &mut bitfield),
+            Err(PeerError::BitfieldSpareBits)
+        );
+        assert_eq!(bitfield.payload(), &[0, 0], "Nothing was applied");
+        PeerState::new(10).on_message(message(MessageTag::Bitfield, &[0xff, 0xc0]), &mut bitfield).unwrap();
+        assert!(bitfield.has_piece(9));
+    }
+
+    #[test]
+    fn parses_blocks()
+    {
+        let mut state = PeerState::new(2);
+        let mut bitfield = Bitfield::new(2);
+
+        let event = state.on_message(message(MessageTag::Piece, &[0, 0, 0, 1, 0, 0, 0, 2, 7, 8]), &mut bitfield);
+
+        assert_eq!(event, Ok(Event::Block { index: 1, begin: 2, block: vec![7, 8] }));
+    }
+
+    #[test]
+    fn rejects_malformed_messages()
+    {
+        let mut state = PeerState::new(1);
+        let mut bitfield = Bitfield::new(1);
+
+        for (tag, payload, error) in [
+            (MessageTag::Choke, &[1u8][..], WireError::Length { what: "Choke", expected: 0, got: 1 }),
+            (MessageTag::Have, &[0, 1], WireError::Length { what: "Have", expected: 4, got: 2 }),
+            (MessageTag::Request, &[0; 11], WireError::Length { what: "Request", expected: 12, got: 11 }),
+            (MessageTag::Piece, &[0; 7], WireError::TooShort { what: "Piece", min: 8, got: 7 }),
+        ]
+        {
+            assert_eq!(state.on_message(message(tag, payload), &mut bitfield), Err(PeerError::Malformed(error)));
+        }
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test_wire_messages
+{
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+    use crate::peer::{Handshake, Message, MessageFramer, MessageTag, PeerMessage, PeerRequest, PieceMessage, WireError};
+
+    fn random_bytes(max: usize) -> Vec<u8>
+    {
+        (0..fastrand::usize(..=max)).map(|_| fastrand::u8(..)).collect()
+    }
+
+    fn random_request() -> PeerRequest
+    {
+        PeerRequest::new(fastrand::u32(..), fastrand::u32(..), fastrand::u32(..))
+    }
+
+    /// One random message of every variant.
+    fn random_messages() -> Vec<PeerMessage>
+    {
+        let mut extended = random_bytes(64);
+        extended.insert(0, fastrand::u8(..));
+        vec![
+            PeerMessage::Choke,
+            PeerMessage::UnChoke,
+            PeerMessage::Interested,
+            PeerMessage::NonInterested,
+            PeerMessage::Have(fastrand::u32(..)),
+            PeerMessage::Bitfield(random_bytes(64)),
+            PeerMessage::Request(random_request()),
+            PeerMessage::Piece(PieceMessage::new(fastrand::u32(..), fastrand::u32(..), random_bytes(64))),
+            PeerMessage::Cancel(random_request()),
+            PeerMessage::Extended(extended),
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_variant()
+    {
+        for _ in 0..200
+        {
+            for msg in random_messages()
+            {
+                let raw = Message::from(msg.clone());
+                assert_eq!(MessageTag::try_from(raw.tag as u8), Ok(raw.tag), "Tag survives as a byte");
+
+                let mut framer = MessageFramer::new();
+                let mut bytes = BytesMut::new();
+                framer.encode(raw, &mut bytes).unwrap();
+                let decoded = framer.decode(&mut bytes).unwrap().expect("A whole frame");
+                assert_eq!(PeerMessage::try_from(decoded), Ok(msg));
+                assert!(bytes.is_empty(), "Frame is consumed");
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_handshake()
+    {
+        let mut bytes = Handshake::new([3; 20]).to_bytes();
+        bytes[48..].copy_from_slice(&[9; 20]);
+
+        let handshake = Handshake::from_bytes(&bytes).unwrap();
+
+        assert_eq!(handshake.to_bytes(), bytes);
+        assert_eq!(handshake.peer_id(), [9; 20]);
+        assert_eq!(Handshake::from_bytes(&bytes[..67]), Err(WireError::Length { what: "Handshake", expected: 68, got: 67 }));
+    }
+
+    #[test]
+    fn rejects_unknown_tags()
+    {
+        for tag in (9..20).chain(21..=u8::MAX)
+        {
+            assert_eq!(MessageTag::try_from(tag), Err(WireError::UnknownTag(tag)));
+        }
+        let mut src = BytesMut::from(&[0, 0, 0, 1, 9][..]);
+        let error = MessageFramer::new().decode(&mut src).unwrap_err();
+        assert_eq!(error.get_ref().and_then(|e| e.downcast_ref()), Some(&WireError::UnknownTag(9)));
+    }
+
+    #[test]
+    fn survives_random_frames()
+    {
+        for _ in 0..2000
+        {
+            let mut src = BytesMut::from(random_bytes(48).as_slice());
+            if fastrand::bool()
+            {
+                // a plausible length prefix gets further into the decoder
+                let len = src.len().saturating_sub(4) as u32;
+                let prefix = 4.min(src.len());
+                src[..prefix].copy_from_slice(&len.to_be_bytes()[..prefix]);
+            }
+            let mut framer = MessageFramer::new();
+            while let Ok(Some(_)) = framer.decode(&mut src) {}
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_message_framer
+{
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+    use crate::peer::{KeepAlive, Message, MessageFramer, MessageTag, Peer, PeerMessage, PieceMessage, WireError};
+
+    fn encode(msg: PeerMessage) -> BytesMut
+    {
+        let mut dst = BytesMut::new();
+        MessageFramer::new().encode(Message::from(msg), &mut dst).unwrap();
+        dst
+    }
+
+    #[test]
+    fn payload_excludes_tag()
+    {
+        let mut src = BytesMut::from(&[0, 0, 0, 5, 4, 0, 0, 0, 7][..]);
+
+        let msg = MessageFramer::new().decode(&mut src).unwrap().unwrap();
+
+        assert_eq!(msg.tag, MessageTag::Have);
+        assert_eq!(msg.payload, [0, 0, 0, 7], "Whole payload, without the tag");
+        assert!(src.is_empty(), "Frame is consumed");
+    }
+
+    #[test]
+    fn consumes_tag_only_frames()
+    {
+        let mut src = encode(PeerMessage::UnChoke);
+        src.extend(encode(PeerMessage::Interested));
+        let mut framer = MessageFramer::new();
+
+        assert_eq!(framer.decode(&mut src).unwrap().unwrap().tag, MessageTag::UnChoke);
+        assert_eq!(framer.decode(&mut src).unwrap().unwrap().tag, MessageTag::Interested);
+        assert!(framer.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn waits_for_partial_frames()
+    {
+        let bytes = encode(PeerMessage::Piece(PieceMessage::new(1, 0, vec![9; 100])));
+        let mut framer = MessageFramer::new();
+        let mut src = BytesMut::new();
+
+        for (i, byte) in bytes.iter().enumerate()
+        {
+            assert!(framer.decode(&mut src).unwrap().is_none(), "Only {} of {} bytes", i, bytes.len());
+            src.extend_from_slice(&[*byte]);
+        }
+        let msg = framer.decode(&mut src).unwrap().unwrap();
+
+        assert_eq!(PeerMessage::try_from(msg), Ok(PeerMessage::Piece(PieceMessage::new(1, 0, vec![9; 100]))));
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames_and_keep_alives()
+    {
+        let messages = [
+            PeerMessage::Bitfield(vec![0xff, 0x80]),
+            PeerMessage::Have(3),
+            PeerMessage::Piece(PieceMessage::new(0, Peer::BLOCK_MAX, vec![1; Peer::BLOCK_MAX as usize])),
+        ];
+        let mut framer = MessageFramer::new();
+        let mut src = BytesMut::new();
+        framer.encode(KeepAlive, &mut src).unwrap();
+        for msg in &messages
+        {
+            src.extend(encode(msg.clone()));
+            framer.encode(KeepAlive, &mut src).unwrap();
+        }
+
+        let mut decoded = Vec::new();
+        while let Some(msg) = framer.decode(&mut src).unwrap()
+        {
+            decoded.push(PeerMessage::try_from(msg).unwrap());
+        }
+
+        assert_eq!(decoded, messages, "Keep-alives are skipped");
+        assert!(src.is_empty(), "Trailing keep-alive is consumed");
+    }
+
+    #[test]
+    fn refuses_oversized_frames()
+    {
+        let mut framer = MessageFramer::with_max_frame(16);
+        let mut src = BytesMut::from(&17u32.to_be_bytes()[..]);
+
+        let error = framer.decode(&mut src).unwrap_err();
+        assert_eq!(error.get_ref().and_then(|e| e.downcast_ref()), Some(&WireError::FrameTooLarge { len: 17, max: 16 }));
+
+        let mut dst = BytesMut::new();
+        let error = framer.encode(Message::from(PeerMessage::Bitfield(vec![0; 16])), &mut dst).unwrap_err();
+        assert_eq!(error.get_ref().and_then(|e| e.downcast_ref()), Some(&WireError::FrameTooLarge { len: 17, max: 16 }));
+        assert!(dst.is_empty(), "Nothing is written");
+
+        let largest = PeerMessage::Piece(PieceMessage::new(0, 0, vec![0; Peer::BLOCK_MAX as usize]));
+        assert!(MessageFramer::new().encode(Message::from(largest), &mut dst).is_ok(), "A full block fits by default");
+    }
+
+    #[test]
+    fn fits_the_bitfield_of_large_torrents()
+    {
+        // 64 KiB of bitfield, four times a block
+        let pieces = 512 * 1024;
+        let bitfield = PeerMessage::Bitfield(vec![0xff; pieces / 8]);
+        let mut dst = BytesMut::new();
+
+        assert!(MessageFramer::new().encode(Message::from(bitfield.clone()), &mut dst).is_err(), "Too large without the piece count");
+        let mut framer = MessageFramer::for_pieces(pieces);
+        framer.encode(Message::from(bitfield.clone()), &mut dst).unwrap();
+        let decoded = framer.decode(&mut dst).unwrap().unwrap();
+        assert_eq!(PeerMessage::try_from(decoded).unwrap(), bitfield);
+
+        let largest = PeerMessage::Piece(PieceMessage::new(0, 0, vec![0; Peer::BLOCK_MAX as usize]));
+        assert!(MessageFramer::for_pieces(10).encode(Message::from(largest), &mut dst).is_ok(), "A full block always fits");
+    }
+}
+
+
+#[cfg(test)]
+mod test_handshake_io
+{
+    use tokio::io::AsyncWriteExt;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use crate::extension::ExtensionRegistry;
+    use crate::peer::{Handshake, Peer, PeerError};
+
+    /// A peer that answers every handshake with one for `info_hash` and keeps the connection open.
+    async fn remote(info_hash: [u8; 20]) -> std::net::SocketAddr
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            Handshake::read(&mut stream).await.unwrap();
+            stream.write_all(&Handshake::new(info_hash).to_bytes()).await.unwrap();
+            tokio::time::sleep(Handshake::TIMEOUT).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn reads_handshake_without_waiting_for_close()
+    {
+        let addr = remote([7; 20]).await;
+
+        let (_, handshake) = Peer::handshake(Handshake::new([7; 20]), addr).await.unwrap();
+
+        assert_eq!(handshake.peer_id(), *b"00112233445566778890", "Wrong peer id");
+        assert_eq!(handshake.reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0], "Wrong reserved bits");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_infohash()
+    {
+        let addr = remote([8; 20]).await;
+
+        let error = Peer::handshake(Handshake::new([7; 20]), addr).await.unwrap_err();
+
+        assert_eq!(error.downcast_ref(), Some(&PeerError::InfoHashMismatch(hex::encode([8; 20]))));
+    }
+
+    #[tokio::test]
+    async fn keeps_peers_that_do_not_unchoke()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            Handshake::read(&mut stream).await.unwrap();
+            stream.write_all(&Handshake::new([7; 20]).to_bytes()).await.unwrap();
+            // a bitfield with the only piece, and never an unchoke
+            stream.write_all(&[0, 0, 0, 2, 5, 0x80]).await.unwrap();
+            tokio::time::sleep(Handshake::TIMEOUT).await;
+        });
+
+        let peer = Peer::connect(addr, [7; 20], 1, ExtensionRegistry::new(), 4, Duration::from_millis(100)).await.unwrap();
+
+        assert!(peer.state.peer_choking, "Still choked, the download waits for the unchoke");
+        assert!(peer.has_piece(0), "Pieces are taken in while waiting");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocols()
+    {
+        let mut bytes = Handshake::new([1; 20]).to_bytes().to_vec();
+        bytes[5] = b'X';
+        let error = Handshake::read(&mut bytes.as_slice()).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&PeerError::WrongProtocol));
+
+        let error = Handshake::read(&mut b"\x13BitTorrent".as_slice()).await.unwrap_err();
+        assert!(error.downcast_ref::<PeerError>().is_none(), "Short read is an I/O error");
+    }
+}
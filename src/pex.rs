@@ -0,0 +1,250 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::mpsc;
+use crate::extension::{Extension, ExtendedHandshake};
+use crate::tracker::peers;
+
+pub const NAME: &str = "ut_pex";
+
+// BEP 11: at most one message a minute, with at most 50 added and 50 dropped peers
+pub const INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+// how often we listen to a peer; leaves room for a peer's clock to run a bit fast
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// flags of `added.f`, one byte per added peer
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_OUTGOING: u8 = 0x10;
+
+/// A ut_pex message: compact peers connected and disconnected since the previous one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage
+{
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    pub added6_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage
+{
+    /// Every added peer is flagged as a connection we made.
+    pub fn new(added: &[SocketAddr], dropped: &[SocketAddr]) -> Self
+    {
+        let added4 = peers::to_compact_v4(added);
+        let added6 = peers::to_compact_v6(added);
+        Self
+        {
+            added_flags: ByteBuf::from(vec![FLAG_OUTGOING; added4.len() / 6]),
+            added: ByteBuf::from(added4),
+            added6_flags: ByteBuf::from(vec![FLAG_OUTGOING; added6.len() / 18]),
+            added6: ByteBuf::from(added6),
+            dropped: ByteBuf::from(peers::to_compact_v4(dropped)),
+            dropped6: ByteBuf::from(peers::to_compact_v6(dropped)),
+        }
+    }
+    pub fn added(&self) -> anyhow::Result<Vec<SocketAddr>>
+    {
+        let mut added = peers::from_compact_v4(&self.added).context("Malformed added peers")?;
+        added.extend(peers::from_compact_v6(&self.added6).context("Malformed added6 peers")?);
+        Ok(added)
+    }
+    pub fn dropped(&self) -> anyhow::Result<Vec<SocketAddr>>
+    {
+        let mut dropped = peers::from_compact_v4(&self.dropped).context("Malformed dropped peers")?;
+        dropped.extend(peers::from_compact_v6(&self.dropped6).context("Malformed dropped6 peers")?);
+        Ok(dropped)
+    }
+}
+
+/// ut_pex for one connection. Peers it learns about go to `found`, the download's connection pool.
+pub struct PexExtension
+{
+    found: mpsc::Sender<Vec<SocketAddr>>,
+    enabled: bool,
+    // what the remote side currently knows from us
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexExtension
+{
+    pub fn new(found: mpsc::Sender<Vec<SocketAddr>>) -> Self
+    {
+        Self
+        {
+            found,
+            enabled: false,
+            sent: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+    /// The delta against what we sent before, once per `INTERVAL` and only if something changed.
+    pub fn update(&mut self, connected: &[SocketAddr], now: Instant) -> anyhow::Result<Option<Vec<u8>>>
+    {
+        if !self.enabled || self.last_sent.is_some_and(|last| now.duration_since(last) < INTERVAL)
+        {
+            return Ok(None);
+        }
+
+        let added: Vec<_> = connected.iter()
+            .filter(|addr| !self.sent.contains(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        let dropped: Vec<_> = self.sent.iter()
+            .filter(|addr| !connected.contains(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty()
+        {
+            return Ok(None);
+        }
+
+        self.last_sent = Some(now);
+        self.sent.extend(&added);
+        for addr in &dropped
+        {
+            self.sent.remove(addr);
+        }
+        Ok(Some(serde_bencode::to_bytes(&PexMessage::new(&added, &dropped))?))
+    }
+}
+
+impl Extension for PexExtension
+{
+    fn name(&self) -> &'static str
+    {
+        NAME
+    }
+    fn on_handshake(&mut self, remote: &ExtendedHandshake)
+    {
+        self.enabled = remote.extension_id(NAME).is_some();
+    }
+    fn on_message(&mut self, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>
+    {
+        let now = Instant::now();
+        // a peer flooding us gets ignored rather than dropped
+        if self.last_received.is_some_and(|last| now.duration_since(last) < MIN_RECEIVE_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_received = Some(now);
+
+        let message: PexMessage = serde_bencode::from_bytes(payload).context("Parsing ut_pex message")?;
+        let mut added = message.added()?;
+        added.retain(|addr| addr.port() != 0 && !addr.ip().is_unspecified());
+        added.truncate(MAX_PEERS_PER_MESSAGE);
+        if !added.is_empty()
+        {
+            // a full pool has enough to do; these peers are simply not needed
+            let _ = self.found.try_send(added);
+        }
+        Ok(Vec::new())
+    }
+}
+
+
+#[cfg(test)]
+mod test_pex
+{
+    use std::net::SocketAddr;
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+    use crate::extension::{Extension, ExtendedHandshake};
+    use crate::pex::{PexExtension, PexMessage, FLAG_OUTGOING, INTERVAL, MAX_PEERS_PER_MESSAGE, NAME};
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr>
+    {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    fn enabled() -> (PexExtension, mpsc::Receiver<Vec<SocketAddr>>)
+    {
+        let (found, discovered) = mpsc::channel(4);
+        let mut pex = PexExtension::new(found);
+        let mut remote = ExtendedHandshake::default();
+        remote.m.insert(NAME.to_string(), 3);
+        pex.on_handshake(&remote);
+        (pex, discovered)
+    }
+
+    #[test]
+    fn round_trips_message()
+    {
+        let added = addrs(&["1.2.3.4:5", "[::1]:6"]);
+        let dropped = addrs(&["7.7.7.7:7"]);
+
+        let bytes = serde_bencode::to_bytes(&PexMessage::new(&added, &dropped)).unwrap();
+        let message: PexMessage = serde_bencode::from_bytes(&bytes).unwrap();
+
+        assert_eq!(message.added().unwrap(), added, "Wrong added");
+        assert_eq!(message.dropped().unwrap(), dropped, "Wrong dropped");
+        assert_eq!(message.added_flags.as_slice(), [FLAG_OUTGOING], "One flag per IPv4 peer");
+        assert_eq!(message.added6_flags.as_slice(), [FLAG_OUTGOING], "One flag per IPv6 peer");
+    }
+
+    #[test]
+    fn sends_deltas_once_per_interval()
+    {
+        let (mut pex, _discovered) = enabled();
+        let start = Instant::now();
+
+        let first = pex.update(&addrs(&["1.1.1.1:1", "2.2.2.2:2"]), start).unwrap().unwrap();
+        let first: PexMessage = serde_bencode::from_bytes(&first).unwrap();
+        assert_eq!(first.added().unwrap().len(), 2, "Everyone is new at first");
+
+        assert!(pex.update(&addrs(&["3.3.3.3:3"]), start).unwrap().is_none(), "Too early");
+
+        let second = pex.update(&addrs(&["2.2.2.2:2", "3.3.3.3:3"]), start + INTERVAL).unwrap().unwrap();
+        let second: PexMessage = serde_bencode::from_bytes(&second).unwrap();
+        assert_eq!(second.added().unwrap(), addrs(&["3.3.3.3:3"]), "Only the new peer");
+        assert_eq!(second.dropped().unwrap(), addrs(&["1.1.1.1:1"]), "Only the gone peer");
+    }
+
+    #[test]
+    fn stays_quiet_without_remote_support()
+    {
+        let (found, _discovered) = mpsc::channel(1);
+        let mut pex = PexExtension::new(found);
+        pex.on_handshake(&ExtendedHandshake::default());
+
+        assert!(pex.update(&addrs(&["1.1.1.1:1"]), Instant::now()).unwrap().is_none());
+    }
+
+    #[test]
+    fn limits_accepted_peers()
+    {
+        let (mut pex, mut discovered) = enabled();
+        let many: Vec<SocketAddr> = addrs(&["0.0.0.0:1", "1.1.1.1:0"]).into_iter()
+            .chain((0..2 * MAX_PEERS_PER_MESSAGE).map(|i| SocketAddr::from(([10, 0, 0, i as u8], 6881))))
+            .collect();
+        let bytes = serde_bencode::to_bytes(&PexMessage::new(&many, &[])).unwrap();
+
+        pex.on_message(&bytes).unwrap();
+        pex.on_message(&bytes).unwrap();
+
+        let found = discovered.try_recv().unwrap();
+        assert_eq!(found.len(), MAX_PEERS_PER_MESSAGE, "Should cap the peers per message");
+        assert!(found.iter().all(|addr| addr.port() != 0 && !addr.ip().is_unspecified()), "Should skip bogus peers");
+        assert!(discovered.try_recv().is_err(), "Should ignore a message right after another");
+    }
+}
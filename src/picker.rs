@@ -0,0 +1,418 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::watch;
+use crate::peer::{Bitfield, Peer, PeerRequest};
+use crate::piece::Piece;
+
+/// What a connection reports to the download it belongs to.
+#[derive(Debug)]
+pub(crate) enum Progress
+{
+    /// Every block of the piece arrived; the hash is not checked yet.
+    Piece { index: u32, data: Vec<u8> },
+    /// The connection has nothing to ask for.
+    Idle,
+}
+
+/// A piece some blocks were asked for.
+#[derive(Debug)]
+struct Active
+{
+    piece: Piece,
+    // the only peer that may ask for the blocks of a piece that failed its hash check before
+    owner: Option<usize>,
+    senders: HashSet<usize>,
+    // blocks nobody has in flight, the next one last
+    unrequested: Vec<u32>,
+    received: Vec<bool>,
+    left: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct State
+{
+    pending: BinaryHeap<Piece>,
+    active: BTreeMap<u32, Active>,
+    // complete pieces waiting for their hash check
+    checking: BTreeMap<u32, Active>,
+    // pieces that failed their hash check with blocks from several peers
+    parole: HashSet<u32>,
+    // peers that sent a corrupt piece on their own, with the piece
+    banned: HashMap<usize, u32>,
+    peers: HashSet<usize>,
+    // peers with nothing in flight that found nothing to ask for
+    idle: HashSet<usize>,
+    in_flight: usize,
+}
+
+/// Hands out the blocks of a download to its connections, so each one keeps requesting
+/// across piece boundaries instead of waiting for a piece to finish.
+///
+/// A connection first gets the blocks left of pieces already started, then starts the
+/// next piece it has, in the order of the piece heap. Blocks a connection gives back go
+/// to whoever asks next; connections waiting for work learn of them through `subscribe`.
+///
+/// A piece that fails its hash check is downloaded again. When one peer sent all of it, that
+/// peer is banned; otherwise the next try goes to a single peer, so a second failure names it.
+#[derive(Debug)]
+pub(crate) struct Picker
+{
+    state: Mutex<State>,
+    work: watch::Sender<()>,
+}
+
+impl Picker
+{
+    /// `pieces` counts its peers by their id, which is `peers` here and `add_peer` later.
+    pub(crate) fn new(pieces: impl IntoIterator<Item = Piece>, peers: impl IntoIterator<Item = usize>) -> Self
+    {
+        Self
+        {
+            state: Mutex::new(State
+            {
+                pending: pieces.into_iter().collect(),
+                active: BTreeMap::new(),
+                checking: BTreeMap::new(),
+                parole: HashSet::new(),
+                banned: HashMap::new(),
+                peers: peers.into_iter().collect(),
+                idle: HashSet::new(),
+                in_flight: 0,
+            }),
+            work: watch::channel(()).0,
+        }
+    }
+    fn state(&self) -> std::sync::MutexGuard<'_, State>
+    {
+        self.state.lock().expect("not poisoned")
+    }
+    /// Wakes up when blocks are given back.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()>
+    {
+        self.work.subscribe()
+    }
+    pub(crate) fn add_peer(&self, id: usize, bitfield: &Bitfield)
+    {
+        let mut state = self.state();
+        state.peers.insert(id);
+        let pending = std::mem::take(&mut state.pending);
+        state.pending = pending.into_iter()
+            .map(|mut piece| {
+                if bitfield.has_piece(piece.index() as u32)
+                {
+                    piece.add_peer(id);
+                }
+                piece
+            })
+            .collect();
+    }
+    pub(crate) fn remove_peer(&self, id: usize)
+    {
+        let mut state = self.state();
+        state.peers.remove(&id);
+        state.idle.remove(&id);
+        for active in state.active.values_mut().filter(|active| active.owner == Some(id))
+        {
+            active.owner = None;
+        }
+    }
+    /// The next block to ask peer `id` for, out of the pieces in `bitfield`.
+    pub(crate) fn pick(&self, id: usize, bitfield: &Bitfield) -> Option<PeerRequest>
+    {
+        let mut state = self.state();
+        if state.banned.contains_key(&id)
+        {
+            return None;
+        }
+        let started = state.active.iter_mut()
+            .find(|(index, active)| {
+                !active.unrequested.is_empty()
+                    && active.owner.is_none_or(|owner| owner == id)
+                    && bitfield.has_piece(**index)
+            })
+            .map(|(index, active)| (*index, active.data.len(), active.unrequested.pop().expect("not empty")));
+        let (index, length, block) = match started {
+            Some(started) => started,
+            None => {
+                // pieces the peer lacks go back on the heap as they were
+                let mut skipped = Vec::new();
+                let mut next = None;
+                while let Some(piece) = state.pending.pop()
+                {
+                    if bitfield.has_piece(piece.index() as u32)
+                    {
+                        next = Some(piece);
+                        break;
+                    }
+                    skipped.push(piece);
+                }
+                state.pending.extend(skipped);
+                let piece = next?;
+                let (index, length) = (piece.index() as u32, piece.length());
+                let blocks = length.div_ceil(Peer::BLOCK_MAX as usize) as u32;
+                let mut unrequested: Vec<u32> = (0..blocks).rev().collect();
+                let Some(block) = unrequested.pop() else {
+                    // an empty piece has nothing to ask for
+                    state.pending.push(piece);
+                    return None;
+                };
+                let owner = state.parole.contains(&index).then_some(id);
+                state.active.insert(index, Active
+                {
+                    piece,
+                    owner,
+                    senders: HashSet::new(),
+                    unrequested,
+                    received: vec![false; blocks as usize],
+                    left: blocks as usize,
+                    data: vec![0; length],
+                });
+                (index, length, block)
+            }
+        };
+        state.in_flight += 1;
+        state.idle.remove(&id);
+        let begin = block * Peer::BLOCK_MAX;
+        Some(PeerRequest::new(index, begin, (length as u32 - begin).min(Peer::BLOCK_MAX)))
+    }
+    /// Peer `id` has nothing in flight and nothing to ask for.
+    pub(crate) fn idle(&self, id: usize)
+    {
+        self.state().idle.insert(id);
+    }
+    /// Requests that will not be answered, for other connections to pick up.
+    pub(crate) fn give_back(&self, requests: Vec<PeerRequest>)
+    {
+        if requests.is_empty()
+        {
+            return;
+        }
+        let mut state = self.state();
+        for request in requests
+        {
+            state.in_flight -= 1;
+            if let Some(active) = state.active.get_mut(&request.index())
+            {
+                active.unrequested.push(request.begin() / Peer::BLOCK_MAX);
+            }
+        }
+        self.work.send_replace(());
+    }
+    /// Takes in the block peer `id` sent for `request`; returns the piece once its last block is in,
+    /// for the caller to check with `passed` or `failed`. Blocks of banned peers are given back.
+    pub(crate) fn received(&self, id: usize, request: PeerRequest, block: &[u8]) -> Option<Vec<u8>>
+    {
+        let mut state = self.state();
+        if state.banned.contains_key(&id)
+        {
+            drop(state);
+            self.give_back(vec![request]);
+            return None;
+        }
+        state.in_flight -= 1;
+        let index = request.index();
+        let active = state.active.get_mut(&index)?;
+        let block_i = (request.begin() / Peer::BLOCK_MAX) as usize;
+        if std::mem::replace(&mut active.received[block_i], true)
+        {
+            return None;
+        }
+        active.data[request.begin() as usize..][..block.len()].copy_from_slice(block);
+        active.senders.insert(id);
+        active.left -= 1;
+        if active.left > 0
+        {
+            return None;
+        }
+        let mut active = state.active.remove(&index)?;
+        let data = std::mem::take(&mut active.data);
+        state.checking.insert(index, active);
+        Some(data)
+    }
+    /// Piece `index` matches its hash.
+    pub(crate) fn passed(&self, index: u32)
+    {
+        let mut state = self.state();
+        state.checking.remove(&index);
+        state.parole.remove(&index);
+    }
+    /// Piece `index` does not match its hash and is downloaded again; returns the peer banned for it.
+    pub(crate) fn failed(&self, index: u32) -> Option<usize>
+    {
+        let mut state = self.state();
+        let active = state.checking.remove(&index)?;
+        let mut senders = active.senders.iter().copied();
+        let sender = senders.next().filter(|_| senders.next().is_none());
+        if let Some(sender) = sender
+        {
+            state.banned.insert(sender, index);
+        } else {
+            state.parole.insert(index);
+        }
+        state.pending.push(active.piece);
+        drop(state);
+        self.work.send_replace(());
+        sender
+    }
+    /// The corrupt piece peer `id` was banned for, if it was.
+    pub(crate) fn banned(&self, id: usize) -> Option<u32>
+    {
+        self.state().banned.get(&id).copied()
+    }
+    /// Pieces not handed out by `received` yet.
+    pub(crate) fn pieces_left(&self) -> usize
+    {
+        let state = self.state();
+        state.pending.len() + state.active.len()
+    }
+    /// Every connection is idle with pieces left, so none of them has what is missing.
+    pub(crate) fn is_stalled(&self) -> bool
+    {
+        let state = self.state();
+        let work_left = !state.pending.is_empty() || !state.active.is_empty();
+        work_left && state.in_flight == 0 && !state.peers.is_empty() && state.idle.len() == state.peers.len()
+    }
+}
+
+
+#[cfg(test)]
+mod test_picker
+{
+    use crate::peer::{Bitfield, Peer, PeerRequest};
+    use crate::picker::Picker;
+    use crate::piece::Piece;
+    use crate::torrent::fixture::single_file;
+
+    const BLOCK: u32 = Peer::BLOCK_MAX;
+
+    /// Two pieces of two blocks, then a piece of half a block.
+    fn picker(data: &[u8]) -> Picker
+    {
+        let torrent = single_file(data, 2 * BLOCK as usize);
+        Picker::new((0..3).map(|index| Piece::new(index, &torrent, &[])), [0, 1])
+    }
+
+    fn data() -> Vec<u8>
+    {
+        (0..(4 * BLOCK + BLOCK / 2) as usize).map(|_| fastrand::u8(..)).collect()
+    }
+
+    #[test]
+    fn requests_across_pieces()
+    {
+        let picker = picker(&data());
+        let all = Bitfield::from_have(&[true; 3]);
+
+        let requests: Vec<_> = std::iter::from_fn(|| picker.pick(0, &all)).collect();
+
+        let mut blocks: Vec<_> = requests.iter().map(|request| (request.index(), request.begin(), request.length())).collect();
+        blocks.sort();
+        assert_eq!(blocks, vec![
+            (0, 0, BLOCK), (0, BLOCK, BLOCK),
+            (1, 0, BLOCK), (1, BLOCK, BLOCK),
+            (2, 0, BLOCK / 2),
+        ]);
+    }
+
+    #[test]
+    fn starts_only_pieces_the_peer_has()
+    {
+        let picker = picker(&data());
+        let second = Bitfield::from_have(&[false, true, false]);
+
+        let requests: Vec<_> = std::iter::from_fn(|| picker.pick(0, &second)).collect();
+
+        assert_eq!(requests.len(), 2, "Both blocks of piece 1");
+        assert!(requests.iter().all(|request| request.index() == 1));
+    }
+
+    #[test]
+    fn hands_given_back_blocks_to_others()
+    {
+        let picker = picker(&data());
+        let all = Bitfield::from_have(&[true; 3]);
+        let first = picker.pick(0, &all).unwrap();
+        let mut work = picker.subscribe();
+        work.borrow_and_update();
+
+        picker.give_back(vec![first]);
+
+        assert!(work.has_changed().unwrap(), "Waiting connections are woken");
+        assert_eq!(picker.pick(1, &all), Some(first), "Started pieces go first");
+    }
+
+    #[test]
+    fn assembles_pieces()
+    {
+        let data = data();
+        let picker = picker(&data);
+        let all = Bitfield::from_have(&[true; 3]);
+        let requests: Vec<PeerRequest> = std::iter::from_fn(|| picker.pick(0, &all)).collect();
+
+        let mut pieces = Vec::new();
+        for request in requests.iter().rev()
+        {
+            let start = (request.index() * 2 * BLOCK + request.begin()) as usize;
+            if let Some(piece) = picker.received(0, *request, &data[start..][..request.length() as usize])
+            {
+                pieces.push(piece);
+            }
+        }
+
+        assert_eq!(pieces.concat().len(), data.len());
+        assert_eq!(pieces.len(), 3, "Every piece once its last block is in");
+        assert_eq!(picker.pieces_left(), 0);
+    }
+
+    #[test]
+    fn bans_the_only_sender_of_a_corrupt_piece()
+    {
+        let picker = picker(&data());
+        let first = Bitfield::from_have(&[true, false, false]);
+        let requests: Vec<_> = std::iter::from_fn(|| picker.pick(0, &first)).collect();
+        let piece = requests.iter().filter_map(|request| picker.received(0, *request, &vec![0; request.length() as usize])).next();
+        assert!(piece.is_some(), "Piece 0 is complete");
+
+        assert_eq!(picker.failed(0), Some(0), "Peer 0 sent every block");
+
+        assert_eq!(picker.banned(0), Some(0));
+        assert_eq!(picker.pick(0, &first), None, "Banned peers get nothing");
+        assert_eq!(picker.pick(1, &first).map(|request| request.index()), Some(0), "The piece is asked for again");
+    }
+
+    #[test]
+    fn asks_a_single_peer_after_a_shared_piece_fails()
+    {
+        let picker = picker(&data());
+        let first = Bitfield::from_have(&[true, false, false]);
+        let (a, b) = (picker.pick(0, &first).unwrap(), picker.pick(1, &first).unwrap());
+        picker.received(0, a, &vec![0; a.length() as usize]);
+        picker.received(1, b, &vec![0; b.length() as usize]).unwrap();
+
+        assert_eq!(picker.failed(0), None, "Nobody is banned for a piece from two peers");
+
+        assert!(picker.pick(1, &first).is_some(), "Peer 1 starts the piece again");
+        assert_eq!(picker.pick(0, &first), None, "The rest of the piece is left to peer 1");
+        picker.remove_peer(1);
+        assert!(picker.pick(0, &first).is_some(), "Anyone may finish it once peer 1 is gone");
+    }
+
+    #[test]
+    fn stalls_when_no_peer_has_what_is_left()
+    {
+        let picker = picker(&data());
+        let first = Bitfield::from_have(&[true, false, false]);
+        let requests: Vec<_> = std::iter::from_fn(|| picker.pick(0, &first)).collect();
+        picker.idle(1);
+        assert!(!picker.is_stalled(), "Blocks are still in flight");
+
+        picker.give_back(requests);
+        picker.idle(0);
+        assert!(picker.is_stalled());
+
+        picker.remove_peer(1);
+        picker.add_peer(2, &Bitfield::from_have(&[true; 3]));
+        assert!(!picker.is_stalled(), "A new peer may have the rest");
+    }
+}
@@ -17,14 +17,9 @@ impl Piece
 {
     pub fn new(piece_i: u64, torrent: &Torrent, peers: &[Peer]) -> Self
     {
-        let piece_size = match piece_i as usize == torrent.info.pieces.0.len() {
-            true if torrent.len() % torrent.info.piece_length == 0 =>
-                torrent.info.piece_length,
-            true => torrent.len() % torrent.info.piece_length,
-            _ => torrent.info.piece_length
-        };
+        let piece_size = torrent.piece_len(piece_i as usize);
         let peers = peers.iter().enumerate().filter_map(
-            |(peer_i, peer)| peer.has_piece(piece_i as u32).then_some(piece_i as usize)).collect();
+            |(peer_i, peer)| peer.has_piece(piece_i as u32).then_some(peer_i)).collect();
 
         Self
         {
@@ -39,6 +34,11 @@ impl Piece
     {
         &self.peers
     }
+    /// Counts one more peer with the piece, by its id.
+    pub(crate) fn add_peer(&mut self, peer: usize)
+    {
+        self.peers.insert(peer);
+    }
     pub fn length(&self) -> usize
     {
         self.length
@@ -47,10 +47,6 @@ impl Piece
     {
         self.piece_i
     }
-    pub fn hash(&self) -> [u8;20]
-    {
-        self.info_hash
-    }
 }
 
 impl Ord for Piece
@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+use crate::peer::{Peer, PeerRequest};
+
+// what a fresh connection starts with, before we know its rate
+pub const INITIAL_DEPTH: usize = 4;
+pub const MIN_DEPTH: usize = 2;
+pub const DEFAULT_MAX_REQUESTS: usize = 64;
+// keep this much of the peer's measured rate requested ahead
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The block requests one connection has in flight and how many it may have.
+///
+/// The depth follows the measured download rate, so a fast or distant peer always has
+/// `QUEUE_TIME` worth of blocks queued; it never exceeds our maximum or the peer's `reqq`.
+#[derive(Debug)]
+pub(crate) struct Pipeline
+{
+    max: usize,
+    remote_max: Option<usize>,
+    depth: usize,
+    outstanding: Vec<PeerRequest>,
+    window_start: Option<Instant>,
+    window_bytes: usize,
+}
+
+impl Pipeline
+{
+    pub(crate) fn new(max: usize) -> Self
+    {
+        let max = max.max(1);
+        Self
+        {
+            max,
+            remote_max: None,
+            depth: INITIAL_DEPTH.min(max),
+            outstanding: Vec::new(),
+            window_start: None,
+            window_bytes: 0,
+        }
+    }
+    /// The `reqq` from the peer's extended handshake.
+    pub(crate) fn set_remote_max(&mut self, reqq: Option<usize>)
+    {
+        self.remote_max = reqq;
+    }
+    fn limit(&self) -> usize
+    {
+        self.remote_max.map_or(self.max, |reqq| reqq.min(self.max)).max(1)
+    }
+    pub(crate) fn depth(&self) -> usize
+    {
+        self.depth.min(self.limit())
+    }
+    pub(crate) fn wants_more(&self) -> bool
+    {
+        self.outstanding.len() < self.depth()
+    }
+    pub(crate) fn is_empty(&self) -> bool
+    {
+        self.outstanding.is_empty()
+    }
+    pub(crate) fn sent(&mut self, request: PeerRequest)
+    {
+        self.outstanding.push(request);
+    }
+    /// Matches a `Piece` against what we asked for, in any order; `None` for blocks we did not request.
+    pub(crate) fn received(&mut self, index: u32, begin: u32, now: Instant) -> Option<PeerRequest>
+    {
+        let position = self.outstanding.iter().position(|request| request.index() == index && request.begin() == begin)?;
+        let request = self.outstanding.swap_remove(position);
+        self.measure(request.length() as usize, now);
+        Some(request)
+    }
+    /// Everything in flight, which the caller hands back or cancels.
+    pub(crate) fn drain(&mut self) -> Vec<PeerRequest>
+    {
+        std::mem::take(&mut self.outstanding)
+    }
+    fn measure(&mut self, bytes: usize, now: Instant)
+    {
+        let Some(start) = self.window_start else {
+            self.window_start = Some(now);
+            return;
+        };
+        self.window_bytes += bytes;
+        let elapsed = now.duration_since(start);
+        if elapsed < RATE_WINDOW
+        {
+            return;
+        }
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let blocks = (rate * QUEUE_TIME.as_secs_f64() / Peer::BLOCK_MAX as f64).ceil() as usize;
+        self.depth = blocks.clamp(MIN_DEPTH, self.max);
+        self.window_start = Some(now);
+        self.window_bytes = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod test_pipeline
+{
+    use std::time::{Duration, Instant};
+    use crate::peer::{Peer, PeerRequest};
+    use crate::pipeline::{Pipeline, INITIAL_DEPTH, MIN_DEPTH};
+
+    /// Receives `blocks` full blocks spread evenly over `over`.
+    fn receive(pipeline: &mut Pipeline, blocks: u32, over: Duration, start: Instant)
+    {
+        for block in 0..blocks
+        {
+            pipeline.sent(PeerRequest::new(0, block * Peer::BLOCK_MAX, Peer::BLOCK_MAX));
+        }
+        for block in 0..blocks
+        {
+            let now = start + over * (block + 1) / blocks;
+            pipeline.received(0, block * Peer::BLOCK_MAX, now).unwrap();
+        }
+    }
+
+    #[test]
+    fn matches_responses_out_of_order()
+    {
+        let mut pipeline = Pipeline::new(8);
+        let requests: Vec<_> = (0..3).map(|block| PeerRequest::new(1, block * Peer::BLOCK_MAX, Peer::BLOCK_MAX)).collect();
+        for request in &requests
+        {
+            pipeline.sent(*request);
+        }
+        let now = Instant::now();
+
+        assert_eq!(pipeline.received(1, 2 * Peer::BLOCK_MAX, now), Some(requests[2]));
+        assert_eq!(pipeline.received(1, 0, now), Some(requests[0]));
+        assert_eq!(pipeline.received(1, 0, now), None, "Already received");
+        assert_eq!(pipeline.received(2, Peer::BLOCK_MAX, now), None, "Never requested");
+        assert_eq!(pipeline.drain(), vec![requests[1]], "Only the middle block is left");
+        assert!(pipeline.is_empty());
+    }
+
+    #[test]
+    fn follows_measured_rate()
+    {
+        let start = Instant::now();
+        let mut pipeline = Pipeline::new(64);
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+
+        receive(&mut pipeline, 21, Duration::from_secs(2), start);
+        assert!((30..=33).contains(&pipeline.depth()), "Ten blocks a second keep three seconds queued, got {}", pipeline.depth());
+
+        receive(&mut pipeline, 2, Duration::from_secs(4), start + Duration::from_secs(2));
+        assert_eq!(pipeline.depth(), MIN_DEPTH, "A slow peer still gets a few");
+    }
+
+    #[test]
+    fn respects_limits()
+    {
+        let start = Instant::now();
+        let mut pipeline = Pipeline::new(16);
+        receive(&mut pipeline, 201, Duration::from_secs(2), start);
+        assert_eq!(pipeline.depth(), 16, "Our maximum");
+
+        pipeline.set_remote_max(Some(5));
+        assert_eq!(pipeline.depth(), 5, "The peer's reqq");
+        for block in 0..5
+        {
+            assert!(pipeline.wants_more());
+            pipeline.sent(PeerRequest::new(0, block, 1));
+        }
+        assert!(!pipeline.wants_more(), "Pipeline is full");
+    }
+}
@@ -0,0 +1,178 @@
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use crate::peer::Bitfield;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+
+/// One file as it was when the state was saved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileState
+{
+    length: u64,
+    // nanoseconds since the epoch
+    mtime: u64,
+}
+
+/// What a download had verified, saved next to its output so an interrupted download
+/// can pick up where it stopped.
+///
+/// It is only trusted while every file still has the size and modification time it had
+/// when the state was saved; otherwise the data is hashed again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState
+{
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+    have: ByteBuf,
+    files: Vec<FileState>,
+}
+
+impl ResumeState
+{
+    /// `<output>.resume`, next to the file or directory we download into.
+    pub fn path(output: &Path) -> PathBuf
+    {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+    /// The state of `storage` now, with `have` as the verified pieces.
+    pub fn capture(info_hash: [u8; 20], storage: &Storage, have: &[bool]) -> anyhow::Result<Self>
+    {
+        Ok(
+            Self
+            {
+                info_hash: ByteBuf::from(info_hash.to_vec()),
+                have: ByteBuf::from(Bitfield::from_have(have).payload().to_vec()),
+                files: files(storage)?,
+            }
+        )
+    }
+    pub fn load(path: &Path) -> anyhow::Result<Self>
+    {
+        let bytes = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        serde_bencode::from_bytes(&bytes).context("Parsing resume state")
+    }
+    /// Writes a temporary file first, so a crash never leaves half a state behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()>
+    {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".part");
+        std::fs::write(&temporary, serde_bencode::to_bytes(self)?)
+            .with_context(|| format!("Writing {}", Path::new(&temporary).display()))?;
+        std::fs::rename(&temporary, path).with_context(|| format!("Replacing {}", path.display()))
+    }
+    /// The verified pieces, if the state belongs to this torrent and the files were not touched since.
+    fn have(&self, info_hash: [u8; 20], storage: &Storage, pieces: usize) -> Option<Vec<bool>>
+    {
+        if self.info_hash.as_slice() != info_hash || self.have.len() != pieces.div_ceil(8)
+        {
+            return None;
+        }
+        if files(storage).ok()? != self.files
+        {
+            return None;
+        }
+        let bitfield = Bitfield::from_bytes(&self.have);
+        Some((0..pieces).map(|piece| bitfield.has_piece(piece as u32)).collect())
+    }
+}
+
+fn files(storage: &Storage) -> anyhow::Result<Vec<FileState>>
+{
+    storage.paths()
+        .map(|path| {
+            let metadata = std::fs::metadata(path).with_context(|| format!("Reading metadata of {}", path.display()))?;
+            let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+            Ok(FileState { length: metadata.len(), mtime: mtime.as_nanos() as u64 })
+        })
+        .collect()
+}
+
+/// Which pieces of `storage` are already verified: from the state at `path` when it is
+/// consistent, otherwise by hashing whatever data is there. `fresh` storage had no data
+/// before we created it, so there is nothing to hash.
+pub fn restore(torrent: &Torrent, storage: &Storage, path: &Path, fresh: bool) -> anyhow::Result<Vec<bool>>
+{
+    let pieces = torrent.info.pieces.0.len();
+    if fresh
+    {
+        return Ok(vec![false; pieces]);
+    }
+    let info_hash = torrent.info_hash()?;
+    if let Some(have) = ResumeState::load(path).ok().and_then(|state| state.have(info_hash, storage, pieces))
+    {
+        return Ok(have);
+    }
+    Ok(storage.verify(torrent))
+}
+
+
+#[cfg(test)]
+mod test_resume
+{
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::resume::{restore, ResumeState};
+    use crate::storage::Storage;
+    use crate::torrent::fixture::single_file;
+    use crate::torrent::Torrent;
+
+    /// One 8 byte file in pieces of 4.
+    fn torrent(data: &[u8]) -> Torrent
+    {
+        single_file(data, 4)
+    }
+
+    #[test]
+    fn names_state_next_to_output()
+    {
+        assert_eq!(ResumeState::path("out/file.iso".as_ref()), std::path::Path::new("out/file.iso.resume"));
+    }
+
+    #[test]
+    fn trusts_consistent_state()
+    {
+        let torrent = torrent(b"abcdefgh");
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&torrent, &dir.path().join("a")).unwrap();
+        let path = dir.path().join("a.resume");
+        // the data does not hash right, so a restored `true` can only come from the state
+        ResumeState::capture(torrent.info_hash().unwrap(), &storage, &[true, false]).unwrap().save(&path).unwrap();
+
+        assert_eq!(restore(&torrent, &storage, &path, false).unwrap(), vec![true, false]);
+
+        let other = self::torrent(b"hgfedcba");
+        assert_eq!(restore(&other, &storage, &path, false).unwrap(), vec![false, false], "Other infohash gets hashed");
+    }
+
+    #[test]
+    fn rehashes_touched_files()
+    {
+        let torrent = torrent(b"abcdefgh");
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&torrent, &dir.path().join("a")).unwrap();
+        let path = dir.path().join("a.resume");
+        ResumeState::capture(torrent.info_hash().unwrap(), &storage, &[false, false]).unwrap().save(&path).unwrap();
+
+        storage.write_piece(&torrent, 1, b"efgh").unwrap();
+        let file = std::fs::File::options().write(true).open(dir.path().join("a")).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1)).unwrap();
+
+        assert_eq!(restore(&torrent, &storage, &path, false).unwrap(), vec![false, true], "Finds the piece by hashing");
+    }
+
+    #[test]
+    fn skips_hashing_fresh_storage()
+    {
+        let torrent = torrent(b"abcdefgh");
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), b"abcdefgh").unwrap();
+        let storage = Storage::create(&torrent, &dir.path().join("a")).unwrap();
+
+        assert_eq!(restore(&torrent, &storage, &dir.path().join("a.resume"), true).unwrap(), vec![false, false]);
+        assert_eq!(restore(&torrent, &storage, &dir.path().join("a.resume"), false).unwrap(), vec![true, true], "No state, so hash");
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_util::codec::Framed;
+use crate::choker::{Choker, RECHOKE_INTERVAL, UPLOAD_SLOTS};
+use crate::extension::{ExtensionRegistry, REQQ};
+use crate::metadata::MetadataExtension;
+use crate::peer::{Bitfield, Handshake, KeepAlive, Message, MessageFramer, MessageTag, Peer, PeerError, PeerMessage, PeerRequest, PieceMessage};
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use crate::tracker::TransferStats;
+
+/// A torrent we upload: its data on disk and the pieces we verified.
+#[derive(Debug)]
+pub struct SeedTorrent
+{
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    storage: Arc<Storage>,
+    have: RwLock<Vec<bool>>,
+    haves: broadcast::Sender<u32>,
+    stats: Arc<TransferStats>,
+    choker: Mutex<Choker>,
+}
+
+impl SeedTorrent
+{
+    /// `storage` may be shared with a download that is still filling it in.
+    pub fn new(torrent: Torrent, storage: impl Into<Arc<Storage>>, have: Vec<bool>, stats: Arc<TransferStats>) -> anyhow::Result<Self>
+    {
+        anyhow::ensure!(have.len() == torrent.info.pieces.0.len(), "Have {} pieces, torrent has {}", have.len(), torrent.info.pieces.0.len());
+        let (haves, _) = broadcast::channel(64);
+        Ok(
+            Self
+            {
+                info_hash: torrent.info_hash()?,
+                torrent,
+                storage: storage.into(),
+                have: RwLock::new(have),
+                haves,
+                stats,
+                choker: Mutex::new(Choker::new(UPLOAD_SLOTS)),
+            }
+        )
+    }
+    /// How many peers we upload to at once, the optimistic unchoke included.
+    pub fn with_upload_slots(mut self, slots: usize) -> Self
+    {
+        self.choker = Mutex::new(Choker::new(slots));
+        self
+    }
+    pub fn info_hash(&self) -> [u8; 20]
+    {
+        self.info_hash
+    }
+    pub fn storage(&self) -> &Arc<Storage>
+    {
+        &self.storage
+    }
+    /// A snapshot of the pieces we have.
+    pub fn have(&self) -> Vec<bool>
+    {
+        self.have.read().expect("not poisoned").clone()
+    }
+    pub fn has_piece(&self, piece: u32) -> bool
+    {
+        self.have.read().expect("not poisoned").get(piece as usize).copied().unwrap_or(false)
+    }
+    /// Marks a verified piece as ours and tells every connected peer with `Have`.
+    pub fn piece_done(&self, piece: u32)
+    {
+        if let Some(have) = self.have.write().expect("not poisoned").get_mut(piece as usize)
+        {
+            *have = true;
+        }
+        // nobody may be connected
+        let _ = self.haves.send(piece);
+    }
+    /// Reassigns the upload slots; connections pick up their new state on their own.
+    pub fn rechoke(&self)
+    {
+        let seeding = self.have.read().expect("not poisoned").iter().all(|have| *have);
+        self.choker.lock().expect("not poisoned").rechoke(seeding, Instant::now());
+    }
+    /// Whether we wait on blocks we asked `ip` for; peers that leave us waiting lose their slot.
+    pub fn set_waiting(&self, ip: IpAddr, waiting: bool)
+    {
+        self.choker.lock().expect("not poisoned").set_waiting(ip, waiting, Instant::now());
+    }
+    /// Credits `ip` with a block it sent us, which ranks it while we are leeching.
+    pub fn record_download(&self, ip: IpAddr, bytes: usize)
+    {
+        self.choker.lock().expect("not poisoned").record_download(ip, bytes, Instant::now());
+    }
+    fn bitfield(&self) -> Bitfield
+    {
+        Bitfield::from_have(&self.have.read().expect("not poisoned"))
+    }
+    async fn read_block(&self, request: PeerRequest) -> anyhow::Result<Vec<u8>>
+    {
+        let offset = request.index() as usize * self.torrent.info.piece_length + request.begin() as usize;
+        let length = request.length() as usize;
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.read(offset, length)).await?
+    }
+}
+
+/// The upload side of one connection: whether the peer is choked and the requests it queued.
+/// The torrent's choker decides when `choking` changes.
+#[derive(Debug)]
+struct Upload
+{
+    id: u64,
+    choking: bool,
+    interested: bool,
+    queue: VecDeque<PeerRequest>,
+}
+
+impl Upload
+{
+    fn new(id: u64) -> Self
+    {
+        Self
+        {
+            id,
+            choking: true,
+            interested: false,
+            queue: VecDeque::new(),
+        }
+    }
+    /// Handles one non-extended message; an error means the peer broke the protocol.
+    fn on_message(&mut self, torrent: &SeedTorrent, msg: Message) -> anyhow::Result<Vec<Message>>
+    {
+        match msg.tag {
+            MessageTag::Interested | MessageTag::NonInterested => {
+                self.interested = msg.tag == MessageTag::Interested;
+                torrent.choker.lock().expect("not poisoned").set_interested(self.id, self.interested);
+            }
+            MessageTag::Request => {
+                let request = PeerRequest::from_bytes(&msg.payload).context("Malformed request")?;
+                anyhow::ensure!(torrent.has_piece(request.index()), "Requested piece {} we don't have", request.index());
+                let piece_len = torrent.torrent.piece_len(request.index() as usize);
+                anyhow::ensure!(
+                    request.length() > 0 && request.length() <= Peer::BLOCK_MAX &&
+                    request.begin() as usize + request.length() as usize <= piece_len,
+                    "Request {}+{} outside piece {}", request.begin(), request.length(), request.index());
+
+                // requests while choked, or beyond the queue we advertised, are dropped
+                if !self.choking && self.queue.len() < REQQ
+                {
+                    self.queue.push_back(request);
+                }
+            }
+            MessageTag::Cancel => {
+                let cancel = PeerRequest::from_bytes(&msg.payload).context("Malformed cancel")?;
+                self.queue.retain(|request| *request != cancel);
+            }
+            // we only upload here
+            _ => {}
+        }
+        Ok(Vec::new())
+    }
+    /// Applies the choker's decision; choking drops whatever the peer queued, as BEP 3 has it.
+    fn set_choking(&mut self, choking: bool) -> Option<Message>
+    {
+        if choking == self.choking
+        {
+            return None;
+        }
+        self.choking = choking;
+        if choking
+        {
+            self.queue.clear();
+            Some(Message { tag: MessageTag::Choke, payload: vec![] })
+        } else {
+            Some(Message { tag: MessageTag::UnChoke, payload: vec![] })
+        }
+    }
+}
+
+/// Keeps a connection in its torrent's choker for as long as it lives.
+struct ChokerSlot<'a>
+{
+    choker: &'a Mutex<Choker>,
+    id: u64,
+}
+
+impl Drop for ChokerSlot<'_>
+{
+    fn drop(&mut self)
+    {
+        self.choker.lock().expect("not poisoned").unregister(self.id);
+    }
+}
+
+/// Accepts peers for the torrents it knows and uploads to them.
+#[derive(Debug, Default)]
+pub struct Seeder
+{
+    torrents: RwLock<HashMap<[u8; 20], Arc<SeedTorrent>>>,
+}
+
+impl Seeder
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+    pub fn add(&self, torrent: Arc<SeedTorrent>)
+    {
+        self.torrents.write().expect("not poisoned").insert(torrent.info_hash(), torrent);
+    }
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<Arc<SeedTorrent>>
+    {
+        self.torrents.read().expect("not poisoned").get(info_hash).cloned()
+    }
+    /// Serves every incoming connection until the listener fails, rechoking every `RECHOKE_INTERVAL`.
+    pub async fn listen(self: &Arc<Self>, listener: TcpListener) -> anyhow::Result<()>
+    {
+        let port = listener.local_addr().context("Listening for peers")?.port();
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted.context("Accepting peer")?;
+                    let seeder = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = seeder.serve(stream, addr, port).await
+                        {
+                            eprintln!("Peer {} disconnected: {:#}", addr, e);
+                        }
+                    });
+                }
+                _ = rechoke.tick() => {
+                    let torrents: Vec<_> = self.torrents.read().expect("not poisoned").values().cloned().collect();
+                    for torrent in torrents
+                    {
+                        torrent.rechoke();
+                    }
+                }
+            }
+        }
+    }
+    async fn serve(&self, mut stream: TcpStream, addr: SocketAddr, port: u16) -> anyhow::Result<()>
+    {
+        let remote = tokio::time::timeout(Handshake::TIMEOUT, Handshake::read(&mut stream)).await
+            .map_err(|_| PeerError::HandshakeTimeout)?
+            .context("Reading handshake")?;
+        let torrent = self.get(&remote.info_hash())
+            .with_context(|| format!("{} asked for unknown torrent {}", addr, hex::encode(remote.info_hash())))?;
+        stream.write_all(&Handshake::new(torrent.info_hash()).to_bytes()).await.context("Writing handshake")?;
+
+        let mut framed = Framed::new(stream, MessageFramer::for_pieces(torrent.torrent.info.pieces.0.len()));
+        let mut extensions = ExtensionRegistry::new().with_port(port);
+        if !torrent.torrent.raw_info.is_empty()
+        {
+            extensions.register(Box::new(MetadataExtension::serving(torrent.info_hash(), torrent.torrent.raw_info.clone())));
+        }
+        // BEP 3 only allows the bitfield right after the handshake
+        let bitfield = torrent.bitfield().payload().to_vec();
+        framed.send(Message { tag: MessageTag::Bitfield, payload: bitfield }).await.context("Sending bitfield")?;
+        if remote.supports_extension_protocol()
+        {
+            framed.send(extensions.handshake().to_message()?).await.context("Sending extended handshake")?;
+        }
+
+        let (id, mut choked) = torrent.choker.lock().expect("not poisoned").register(addr.ip());
+        let _slot = ChokerSlot { choker: &torrent.choker, id };
+        let mut upload = Upload::new(id);
+        let mut haves = torrent.haves.subscribe();
+        // pushed back whenever something else goes out
+        let mut keep_alive = tokio::time::interval_at(tokio::time::Instant::now() + Peer::KEEP_ALIVE_INTERVAL, Peer::KEEP_ALIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = framed.next() => {
+                    let Some(msg) = msg else {
+                        return Ok(());
+                    };
+                    let msg = msg.context("Deriving message")?;
+                    let replies = match msg.tag {
+                        MessageTag::Extended => extensions.dispatch(&msg.payload)?,
+                        _ => upload.on_message(&torrent, msg)?,
+                    };
+                    for reply in replies
+                    {
+                        framed.send(reply).await.context("Answering peer")?;
+                        keep_alive.reset();
+                    }
+                }
+                changed = choked.changed() => {
+                    // the sender lives until `_slot` drops
+                    changed.context("Choker went away")?;
+                    let choking = *choked.borrow_and_update();
+                    if let Some(msg) = upload.set_choking(choking)
+                    {
+                        framed.send(msg).await.context("Sending choke state")?;
+                        keep_alive.reset();
+                    }
+                }
+                have = haves.recv() => {
+                    // a lagging receiver skips a few `Have`s; the peer will learn on the next connection
+                    if let Ok(piece) = have
+                    {
+                        framed.send(Message { tag: MessageTag::Have, payload: piece.to_be_bytes().to_vec() }).await
+                            .context("Sending have")?;
+                        keep_alive.reset();
+                    }
+                }
+                _ = std::future::ready(()), if !upload.queue.is_empty() => {
+                    let request = upload.queue.pop_front().expect("checked not empty");
+                    let block = torrent.read_block(request).await
+                        .with_context(|| format!("Reading block {}+{} of piece {}", request.begin(), request.length(), request.index()))?;
+                    let piece = PieceMessage::new(request.index(), request.begin(), block);
+                    let len = piece.block().len();
+                    framed.send(Message::from(PeerMessage::Piece(piece))).await.context("Sending piece")?;
+                    keep_alive.reset();
+                    torrent.stats.uploaded.fetch_add(len, Ordering::Relaxed);
+                    torrent.choker.lock().expect("not poisoned").record_upload(id, len);
+                }
+                _ = keep_alive.tick() => {
+                    framed.send(KeepAlive).await.context("Sending keep-alive")?;
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_upload
+{
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Framed;
+    use crate::peer::{Handshake, Message, MessageFramer, MessageTag, PeerRequest};
+    use crate::seed::{SeedTorrent, Seeder, Upload};
+    use crate::storage::Storage;
+    use crate::torrent::fixture::single_file;
+    use crate::tracker::TransferStats;
+
+    fn seed_torrent(have: Vec<bool>) -> (SeedTorrent, tempfile::TempDir)
+    {
+        let data = b"abcdefgh";
+        let torrent = single_file(data, 4);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), data).unwrap();
+        let storage = Storage::new(&torrent, &dir.path().join("a")).unwrap();
+
+        (SeedTorrent::new(torrent, storage, have, Arc::new(TransferStats::new(0))).unwrap(), dir)
+    }
+
+    fn message(tag: MessageTag, request: PeerRequest) -> Message
+    {
+        Message { tag, payload: request.to_bytes().to_vec() }
+    }
+
+    /// A connection registered with the torrent's choker, unchoked if a slot is free.
+    fn interested_upload(torrent: &SeedTorrent) -> Upload
+    {
+        let (id, _) = torrent.choker.lock().unwrap().register(Ipv4Addr::LOCALHOST.into());
+        let mut upload = Upload::new(id);
+        upload.on_message(torrent, Message { tag: MessageTag::Interested, payload: vec![] }).unwrap();
+        let choked = torrent.choker.lock().unwrap().is_choked(id);
+        upload.set_choking(choked);
+        upload
+    }
+
+    #[test]
+    fn queues_requests_while_unchoked()
+    {
+        let (torrent, _dir) = seed_torrent(vec![true, true]);
+        let (id, _) = torrent.choker.lock().unwrap().register(Ipv4Addr::LOCALHOST.into());
+        let mut upload = Upload::new(id);
+
+        upload.on_message(&torrent, message(MessageTag::Request, PeerRequest::new(0, 0, 4))).unwrap();
+        assert!(upload.queue.is_empty(), "Requests while choked are dropped");
+
+        upload.on_message(&torrent, Message { tag: MessageTag::Interested, payload: vec![] }).unwrap();
+        assert!(!torrent.choker.lock().unwrap().is_choked(id), "A free slot goes to the interested peer");
+        assert_eq!(upload.set_choking(false).unwrap().tag, MessageTag::UnChoke);
+
+        upload.on_message(&torrent, message(MessageTag::Request, PeerRequest::new(0, 0, 4))).unwrap();
+        upload.on_message(&torrent, message(MessageTag::Request, PeerRequest::new(1, 2, 2))).unwrap();
+        upload.on_message(&torrent, message(MessageTag::Cancel, PeerRequest::new(0, 0, 4))).unwrap();
+        assert_eq!(upload.queue, vec![PeerRequest::new(1, 2, 2)], "Cancel removes the request");
+
+        assert_eq!(upload.set_choking(true).unwrap().tag, MessageTag::Choke);
+        assert!(upload.queue.is_empty(), "Choking drops the queue");
+        assert!(upload.set_choking(true).is_none(), "Nothing to send without a change");
+    }
+
+    #[test]
+    fn shares_slots_between_connections()
+    {
+        let (torrent, _dir) = seed_torrent(vec![true, true]);
+        let torrent = torrent.with_upload_slots(1);
+
+        let first = interested_upload(&torrent);
+        let second = interested_upload(&torrent);
+
+        assert!(!first.choking, "First peer takes the only slot");
+        assert!(second.choking, "Second peer waits for a rechoke");
+    }
+
+    #[test]
+    fn rejects_bad_requests()
+    {
+        let (torrent, _dir) = seed_torrent(vec![true, false]);
+        let mut upload = interested_upload(&torrent);
+
+        assert!(upload.on_message(&torrent, message(MessageTag::Request, PeerRequest::new(1, 0, 4))).is_err(), "Piece we don't have");
+        assert!(upload.on_message(&torrent, message(MessageTag::Request, PeerRequest::new(0, 2, 4))).is_err(), "Past the piece end");
+        assert!(upload.on_message(&torrent, Message { tag: MessageTag::Request, payload: vec![1, 2] }).is_err(), "Malformed");
+    }
+
+    #[tokio::test]
+    async fn reads_blocks_and_tracks_haves()
+    {
+        let (torrent, _dir) = seed_torrent(vec![true, false]);
+        let mut haves = torrent.haves.subscribe();
+
+        assert_eq!(torrent.read_block(PeerRequest::new(1, 1, 3)).await.unwrap(), b"fgh");
+        assert_eq!(torrent.bitfield().payload(), [0x80], "Only the first piece");
+
+        torrent.piece_done(1);
+
+        assert!(torrent.has_piece(1));
+        assert_eq!(torrent.bitfield().payload(), [0xc0], "Both pieces");
+        assert_eq!(haves.recv().await.unwrap(), 1, "Connected peers hear about it");
+    }
+
+    #[tokio::test]
+    async fn sends_bitfield_before_extended_handshake()
+    {
+        let (torrent, _dir) = seed_torrent(vec![true, false]);
+        let info_hash = torrent.info_hash();
+        let seeder = Arc::new(Seeder::new());
+        seeder.add(Arc::new(torrent));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeding = tokio::spawn(async move { seeder.listen(listener).await });
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&Handshake::new(info_hash).to_bytes()).await.unwrap();
+        Handshake::read(&mut stream).await.unwrap();
+        let mut framed = Framed::new(stream, MessageFramer::new());
+        let first = framed.next().await.unwrap().unwrap();
+        let second = framed.next().await.unwrap().unwrap();
+        seeding.abort();
+
+        assert_eq!(first.tag, MessageTag::Bitfield, "Only the bitfield may follow the handshake");
+        assert_eq!(first.payload, [0x80]);
+        assert_eq!(second.tag, MessageTag::Extended);
+    }
+}
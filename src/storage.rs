@@ -0,0 +1,265 @@
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use crate::torrent::{Keys, Torrent};
+use crate::verify;
+use crate::verify::Status;
+
+/// One file of the torrent and where its bytes start in the torrent.
+#[derive(Debug, Clone)]
+struct FileSpan
+{
+    path: PathBuf,
+    offset: usize,
+    length: usize,
+}
+
+/// A torrent's data on disk, addressed by torrent byte offsets.
+#[derive(Debug, Clone)]
+pub struct Storage
+{
+    files: Vec<FileSpan>,
+    len: usize,
+}
+
+impl Storage
+{
+    /// The layout `download` writes: `path` itself for a single-file torrent,
+    /// `path/<name>/...` for a multi-file one.
+    pub fn new(torrent: &Torrent, path: &Path) -> anyhow::Result<Self>
+    {
+        let files = match &torrent.info.keys
+        {
+            Keys::SingleFile { length } => vec![(path.to_path_buf(), *length)],
+            Keys::MultiFile { files } => {
+                let root = path.join(&torrent.info.name);
+                files.iter()
+                    .map(|file| Ok((root.join(file.relative_path()?), file.length)))
+                    .collect::<anyhow::Result<_>>()?
+            }
+        };
+        Ok(Self::from_files(files))
+    }
+    /// Files with their lengths, laid end to end in the given order.
+    pub fn from_files(files: Vec<(PathBuf, usize)>) -> Self
+    {
+        let mut offset = 0;
+        let files: Vec<_> = files.into_iter()
+            .map(|(path, length)| {
+                let span = FileSpan { path, offset, length };
+                offset += length;
+                span
+            })
+            .collect();
+        Self { files, len: offset }
+    }
+    /// Like `new`, and creates every file at its full length; existing data is kept.
+    pub fn create(torrent: &Torrent, path: &Path) -> anyhow::Result<Self>
+    {
+        let storage = Self::new(torrent, path)?;
+        storage.allocate()?;
+        Ok(storage)
+    }
+    /// Creates every file at its full length; existing data is kept.
+    pub fn allocate(&self) -> anyhow::Result<()>
+    {
+        for file in &self.files
+        {
+            if let Some(parent) = file.path.parent().filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("Creating directory {}", parent.display()))?;
+            }
+            let handle = OpenOptions::new().create(true).truncate(false).write(true).open(&file.path)
+                .with_context(|| format!("Creating {}", file.path.display()))?;
+            // sparse where the file system allows it
+            if handle.metadata()?.len() != file.length as u64
+            {
+                handle.set_len(file.length as u64)
+                    .with_context(|| format!("Sizing {}", file.path.display()))?;
+            }
+        }
+        Ok(())
+    }
+    /// Whether any file already holds data, i.e. there can be something worth hashing.
+    pub fn has_data(&self) -> bool
+    {
+        self.files.iter().any(|file| std::fs::metadata(&file.path).is_ok_and(|metadata| metadata.len() > 0))
+    }
+    /// The files in torrent order, where they are on disk.
+    pub fn paths(&self) -> impl Iterator<Item = &Path>
+    {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+    /// The files in torrent order with the torrent bytes each one holds.
+    pub fn layout(&self) -> impl Iterator<Item = (&Path, std::ops::Range<usize>)>
+    {
+        self.files.iter().map(|file| (file.path.as_path(), file.offset..file.offset + file.length))
+    }
+    /// The parts of the files that `offset..offset + length` covers: the file,
+    /// the offset inside it and the range of the buffer.
+    fn spans(&self, offset: usize, length: usize) -> anyhow::Result<Vec<(&FileSpan, u64, std::ops::Range<usize>)>>
+    {
+        let end = offset.checked_add(length).filter(|&end| end <= self.len)
+            .with_context(|| format!("Range {}+{} is outside the torrent", offset, length))?;
+        Ok(
+            self.files.iter()
+                .filter_map(|file| {
+                    let begin = offset.max(file.offset);
+                    let stop = end.min(file.offset + file.length);
+                    (begin < stop).then(|| (file, (begin - file.offset) as u64, begin - offset..stop - offset))
+                })
+                .collect()
+        )
+    }
+    /// Reads `length` bytes at torrent offset `offset`, across file boundaries.
+    pub fn read(&self, offset: usize, length: usize) -> anyhow::Result<Vec<u8>>
+    {
+        let mut bytes = vec![0u8; length];
+        for (file, at, range) in self.spans(offset, length)?
+        {
+            let handle = File::open(&file.path)
+                .with_context(|| format!("Opening {}", file.path.display()))?;
+            read_at(&handle, &mut bytes[range], at)
+                .with_context(|| format!("Reading {}", file.path.display()))?;
+        }
+        Ok(bytes)
+    }
+    /// Writes `bytes` at torrent offset `offset`, across file boundaries; the files must exist.
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> anyhow::Result<()>
+    {
+        for (file, at, range) in self.spans(offset, bytes.len())?
+        {
+            let handle = OpenOptions::new().write(true).open(&file.path)
+                .with_context(|| format!("Opening {}", file.path.display()))?;
+            write_at(&handle, &bytes[range], at)
+                .with_context(|| format!("Writing {}", file.path.display()))?;
+        }
+        Ok(())
+    }
+    pub fn write_piece(&self, torrent: &Torrent, index: usize, piece: &[u8]) -> anyhow::Result<()>
+    {
+        anyhow::ensure!(piece.len() == torrent.piece_len(index), "Piece {} has {} bytes, expected {}", index, piece.len(), torrent.piece_len(index));
+        self.write(index * torrent.info.piece_length, piece)
+    }
+    pub fn read_piece(&self, torrent: &Torrent, index: usize) -> anyhow::Result<Vec<u8>>
+    {
+        self.read(index * torrent.info.piece_length, torrent.piece_len(index))
+    }
+    /// Which pieces on disk match their hash; missing or short files make pieces missing.
+    pub fn verify(&self, torrent: &Torrent) -> Vec<bool>
+    {
+        verify::pieces(torrent, self).into_iter().map(|status| status == Status::Complete).collect()
+    }
+}
+
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()>
+{
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()>
+{
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()>
+{
+    while !buf.is_empty()
+    {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()>
+{
+    while !buf.is_empty()
+    {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            written => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test_storage
+{
+    use crate::storage::Storage;
+    use crate::torrent::fixture::multi_file;
+    use crate::torrent::Torrent;
+
+    /// Two files, `a` with 3 bytes and `dir/b` with 5, in pieces of 4.
+    fn torrent(data: &[u8]) -> Torrent
+    {
+        multi_file(&[("a", 3), ("dir/b", 5)], data, 4)
+    }
+
+    #[test]
+    fn reads_across_files_and_verifies()
+    {
+        let data = b"abcdefgh";
+        let torrent = torrent(data);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("root/dir")).unwrap();
+        std::fs::write(dir.path().join("root/a"), &data[..3]).unwrap();
+        std::fs::write(dir.path().join("root/dir/b"), b"dXfgh").unwrap();
+
+        let storage = Storage::new(&torrent, dir.path()).unwrap();
+
+        assert_eq!(storage.read(1, 4).unwrap(), b"bcdX", "Should read across the file boundary");
+        assert_eq!(storage.verify(&torrent), vec![true, false], "Second piece is corrupt");
+        assert!(storage.read(6, 4).is_err(), "Past the end");
+    }
+
+    #[test]
+    fn writes_pieces_in_any_order()
+    {
+        let data = b"abcdefgh";
+        let torrent = torrent(data);
+        let dir = tempfile::tempdir().unwrap();
+
+        let storage = Storage::create(&torrent, dir.path()).unwrap();
+        assert_eq!(std::fs::metadata(dir.path().join("root/dir/b")).unwrap().len(), 5, "Created at full length");
+        assert_eq!(storage.verify(&torrent), vec![false, false], "Nothing written yet");
+
+        storage.write_piece(&torrent, 1, &data[4..]).unwrap();
+        storage.write_piece(&torrent, 0, &data[..4]).unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("root/a")).unwrap(), b"abc");
+        assert_eq!(std::fs::read(dir.path().join("root/dir/b")).unwrap(), b"defgh");
+        assert!(storage.write_piece(&torrent, 1, b"xyz").is_err(), "Short piece");
+        assert!(storage.write(7, b"xy").is_err(), "Past the end");
+
+        let again = Storage::create(&torrent, dir.path()).unwrap();
+        assert_eq!(again.verify(&torrent), vec![true, true], "Creating again keeps the data");
+    }
+
+    #[test]
+    fn reports_missing_files()
+    {
+        let torrent = torrent(b"abcdefgh");
+        let dir = tempfile::tempdir().unwrap();
+
+        let storage = Storage::new(&torrent, dir.path()).unwrap();
+
+        assert_eq!(storage.verify(&torrent), vec![false, false]);
+    }
+}
@@ -1,7 +1,13 @@
+use std::net::SocketAddr;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use serde::de::{Error, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use sha1::{Sha1, Digest};
+use crate::dht::Dht;
 use crate::downloaded;
 use crate::downloaded::Downloaded;
 use crate::hashes::Hashes;
@@ -10,8 +16,18 @@ use crate::hashes::Hashes;
 #[derive(Deserialize, Debug, Clone)]
 pub struct Torrent
 {
+    #[serde(default)]
     pub announce: String,
+    /// BEP 12 tracker tiers; when present it takes precedence over `announce`.
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
+    /// BEP 5 DHT bootstrap nodes of a trackerless torrent, as `[host, port]` pairs.
+    #[serde(default)]
+    pub nodes: Vec<DhtNode>,
+    /// The `info` dictionary exactly as it appeared in the .torrent file.
+    #[serde(skip)]
+    pub raw_info: Vec<u8>,
 }
 
 impl Torrent
@@ -24,14 +40,57 @@ impl Torrent
             Keys::MultiFile {files} => {let mut sum  = 0; files.iter().for_each(|file|sum += file.length); sum}
         }
     }
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+    /// The length of piece `index`; only the last one may be short, and pieces past the data are empty.
+    pub fn piece_len(&self, index: usize) -> usize
+    {
+        index.checked_mul(self.info.piece_length)
+            .map_or(0, |start| self.len().saturating_sub(start).min(self.info.piece_length))
+    }
+    /// Hashes the original `info` bytes, so keys `Info` doesn't model still count.
+    /// Torrents built in code have no original bytes and fall back to re-encoding `info`.
     pub fn info_hash(&self) -> anyhow::Result<[u8; 20]>
     {
-        let re_encoded = serde_bencode::to_bytes(&self.info)?;
+        let re_encoded;
+        let info = if self.raw_info.is_empty() {
+            re_encoded = serde_bencode::to_bytes(&self.info)?;
+            &re_encoded
+        } else {
+            &self.raw_info
+        };
 
         let mut hash = Sha1::new();
-        hash.update(re_encoded);
+        hash.update(info);
         Ok(hash.finalize().into())
     }
+    /// A torrent from a bare info dictionary, e.g. one fetched with BEP 9.
+    pub fn from_info(raw_info: Vec<u8>, announce_list: Vec<Vec<String>>) -> anyhow::Result<Self>
+    {
+        let info = serde_bencode::from_bytes(&raw_info).context("Parse info dictionary")?;
+        let torrent = Self
+        {
+            announce: announce_list.iter().flatten().next().cloned().unwrap_or_default(),
+            announce_list,
+            info,
+            nodes: Vec::new(),
+            raw_info,
+        };
+        torrent.check_pieces()?;
+        Ok(torrent)
+    }
+    /// Every piece the length calls for needs exactly one hash.
+    fn check_pieces(&self) -> anyhow::Result<()>
+    {
+        anyhow::ensure!(self.info.piece_length > 0, "Piece length should not be zero");
+        let expected = self.len().div_ceil(self.info.piece_length);
+        anyhow::ensure!(
+            self.info.pieces.0.len() == expected,
+            "Torrent has {} piece hashes for {} pieces", self.info.pieces.0.len(), expected);
+        Ok(())
+    }
     pub fn read(file: impl AsRef<Path>) -> anyhow::Result<Self>
     {
         let f = std::fs::read(file).context("Read torrent file")?;
@@ -39,8 +98,6 @@ impl Torrent
     }
     pub fn print_tree(&self)
     {
-        fn print_subtree() {}
-
         match &self.info.keys
         {
             Keys::SingleFile { .. } =>
@@ -55,20 +112,82 @@ impl Torrent
             }
         }
     }
-    pub async fn download_all(&self, peer_id: String) -> anyhow::Result<Downloaded>
+    /// Downloads straight into `output`: the file itself, or a directory for a multi-file torrent.
+    /// `max_requests` caps the blocks requested from one peer at once.
+    pub async fn download_all(&self, peer_id: String, output: &Path, max_requests: usize) -> anyhow::Result<Downloaded>
+    {
+        downloaded::all(self, peer_id, output, &[], None, max_requests).await
+    }
+    /// Like `download_all`, with peers known up front next to the trackers' ones.
+    pub async fn download_all_with_peers(&self, peer_id: String, output: &Path, peers: &[SocketAddr], max_requests: usize) -> anyhow::Result<Downloaded>
+    {
+        downloaded::all(self, peer_id, output, peers, None, max_requests).await
+    }
+    /// Like `download_all_with_peers`, with the DHT searching for peers next to the trackers.
+    pub async fn download_all_with_dht(&self, peer_id: String, output: &Path, peers: &[SocketAddr], dht: &Arc<Dht>, max_requests: usize) -> anyhow::Result<Downloaded>
+    {
+        downloaded::all(self, peer_id, output, peers, Some(dht), max_requests).await
+    }
+    pub async fn download_piece(&self, piece_i: usize, peer_id: String) -> anyhow::Result<Vec<u8>>
     {
-        downloaded::all(self, peer_id).await
+        downloaded::one(self, piece_i, peer_id).await
     }
 }
 
 
+/// A `[host, port]` entry of the `nodes` key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtNode
+{
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for DhtNode
+{
+    /// `host:port`, with brackets around an IPv6 host.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+struct DhtNodeVisitor;
+
+impl<'de> Visitor<'de> for DhtNodeVisitor
+{
+    type Value = DhtNode;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of a host and a port")
+    }
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: SeqAccess<'de> {
+        let host = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        let port = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(1, &self))?;
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(DhtNode { host, port })
+    }
+}
+
+impl<'de> Deserialize<'de> for DhtNode
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(DhtNodeVisitor)
+    }
+}
+
 impl TryFrom<&PathBuf> for Torrent
 {
     type Error = anyhow::Error;
 
     fn try_from(value: &PathBuf) -> Result<Self, Self::Error> {
-        let f = std::fs::read(value).context("Read torrent file")?;
-        serde_bencode::from_bytes(&f).context("Parse torrent file")
+        Self::read(value)
     }
 }
 
@@ -77,16 +196,76 @@ impl TryFrom<Vec<u8>> for Torrent
     type Error = anyhow::Error;
 
     fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        serde_bencode::from_bytes(&value).context("Parse torrent file")
+        let mut torrent: Self = serde_bencode::from_bytes(&value).context("Parse torrent file")?;
+        let span = info_span(&value).context("Locate info dictionary")?;
+        torrent.raw_info = value[span].to_vec();
+        torrent.check_pieces()?;
+        Ok(torrent)
+    }
+}
+
+/// Finds the byte range of the `info` value in a bencoded metainfo dictionary.
+fn info_span(bytes: &[u8]) -> anyhow::Result<Range<usize>>
+{
+    anyhow::ensure!(bytes.first() == Some(&b'd'), "Torrent should be a dictionary");
+
+    let mut pos = 1;
+    while bytes.get(pos) != Some(&b'e')
+    {
+        let (key, value_start) = byte_string(bytes, pos)?;
+        let value_end = skip_value(bytes, value_start)?;
+        if key == b"info"
+        {
+            return Ok(value_start..value_end);
+        }
+        pos = value_end;
     }
+    anyhow::bail!("No 'info' key")
+}
+
+/// Returns the position right after the bencoded value starting at `pos`.
+pub(crate) fn skip_value(bytes: &[u8], pos: usize) -> anyhow::Result<usize>
+{
+    match bytes.get(pos)
+    {
+        Some(b'i') =>
+            {
+                let end = bytes[pos..].iter().position(|&b| b == b'e')
+                    .context("Unterminated integer")?;
+                Ok(pos + end + 1)
+            }
+        Some(b'l' | b'd') =>
+            {
+                let mut pos = pos + 1;
+                while bytes.get(pos) != Some(&b'e')
+                {
+                    pos = skip_value(bytes, pos)?;
+                }
+                Ok(pos + 1)
+            }
+        Some(b'0'..=b'9') => byte_string(bytes, pos).map(|(_, end)| end),
+        Some(b) => anyhow::bail!("Unexpected byte {:?} at {}", *b as char, pos),
+        None => anyhow::bail!("Unexpected end of data at {}", pos),
+    }
+}
+
+fn byte_string(bytes: &[u8], pos: usize) -> anyhow::Result<(&[u8], usize)>
+{
+    let colon = bytes.get(pos..).unwrap_or_default().iter().position(|&b| b == b':')
+        .with_context(|| format!("Byte string at {} has no length", pos))?;
+    let length: usize = std::str::from_utf8(&bytes[pos..pos + colon])?.parse()
+        .with_context(|| format!("Byte string length at {}", pos))?;
+    let start = pos + colon + 1;
+    let string = start.checked_add(length).and_then(|end| bytes.get(start..end))
+        .with_context(|| format!("Byte string at {} is cut short", pos))?;
+    Ok((string, start + length))
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Info
 {
     pub name: String,
-    pub length: usize,
-    #[serde(rename(deserialize = "piece length"))]
+    #[serde(rename = "piece length")]
     pub piece_length: usize,
     pub pieces: Hashes,
     #[serde(flatten)]
@@ -112,4 +291,176 @@ pub struct File
 {
     pub length: usize,
     pub path: Vec<String>,
-}
\ No newline at end of file
+}
+
+impl File
+{
+    /// The file path relative to the torrent root, rejecting components that could escape it.
+    pub fn relative_path(&self) -> anyhow::Result<PathBuf>
+    {
+        anyhow::ensure!(!self.path.is_empty(), "File has an empty path");
+
+        let mut path = PathBuf::new();
+        for part in &self.path
+        {
+            anyhow::ensure!(
+                !part.is_empty() && part != "." && part != ".." && !part.contains(['/', '\\']),
+                "Unsafe path component: {:?}", part);
+            path.push(part);
+        }
+        Ok(path)
+    }
+}
+
+/// Torrents for tests, hashed from the data they describe.
+#[cfg(test)]
+pub(crate) mod fixture
+{
+    use sha1::{Digest, Sha1};
+    use crate::torrent::Torrent;
+
+    /// The file `a` holding `data`.
+    pub(crate) fn single_file(data: &[u8], piece_length: usize) -> Torrent
+    {
+        let mut bytes = format!("d4:infod6:lengthi{}e4:name1:a", data.len()).into_bytes();
+        bytes.extend(pieces(data, piece_length));
+        bytes.extend(b"ee");
+        Torrent::try_from(bytes).unwrap()
+    }
+
+    /// The directory `root` holding `files`, given by their `/`-separated path and length,
+    /// with `data` spread over them in order.
+    pub(crate) fn multi_file(files: &[(&str, usize)], data: &[u8], piece_length: usize) -> Torrent
+    {
+        let mut bytes = b"d4:infod5:filesl".to_vec();
+        for (path, length) in files
+        {
+            bytes.extend(format!("d6:lengthi{}e4:pathl", length).as_bytes());
+            for part in path.split('/')
+            {
+                bytes.extend(format!("{}:{}", part.len(), part).as_bytes());
+            }
+            bytes.extend(b"ee");
+        }
+        bytes.extend(b"e4:name4:root");
+        bytes.extend(pieces(data, piece_length));
+        bytes.extend(b"ee");
+        Torrent::try_from(bytes).unwrap()
+    }
+
+    fn pieces(data: &[u8], piece_length: usize) -> Vec<u8>
+    {
+        let hashes: Vec<u8> = data.chunks(piece_length).flat_map(Sha1::digest).collect();
+        let mut bytes = format!("12:piece lengthi{}e6:pieces{}:", piece_length, hashes.len()).into_bytes();
+        bytes.extend(hashes);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test_multi_file
+{
+    use crate::torrent::fixture::multi_file;
+    use crate::torrent::{Keys, Torrent};
+
+    fn multi_file_torrent(path: &str) -> Vec<u8>
+    {
+        let mut bytes = b"d8:announce9:http://t/4:infod5:filesl".to_vec();
+        bytes.extend(b"d6:lengthi3e4:pathl1:aee");
+        bytes.extend(format!("d6:lengthi5e4:pathl3:dir{}:{}ee", path.len(), path).as_bytes());
+        bytes.extend(b"e4:name4:root12:piece lengthi4e6:pieces40:");
+        bytes.extend([0u8; 40]);
+        bytes.extend(b"ee");
+        bytes
+    }
+
+    #[test]
+    fn parses_files()
+    {
+        let torrent = Torrent::try_from(multi_file_torrent("b")).unwrap();
+
+        assert_eq!(torrent.len(), 8, "Wrong total length");
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("Should be a multi-file torrent")
+        };
+        assert_eq!(files.len(), 2, "Wrong number of files");
+        assert_eq!(files[1].relative_path().unwrap(), std::path::Path::new("dir").join("b"));
+    }
+
+    #[test]
+    fn rejects_escaping_paths()
+    {
+        let torrent = Torrent::try_from(multi_file_torrent("..")).unwrap();
+
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("Should be a multi-file torrent")
+        };
+        assert!(files[1].relative_path().is_err(), "'..' should be rejected");
+    }
+
+    #[test]
+    fn sizes_pieces()
+    {
+        let torrent = multi_file(&[("a", 3), ("b", 6)], b"abcdefghi", 4);
+        let lengths: Vec<_> = (0..4).map(|index| torrent.piece_len(index)).collect();
+        assert_eq!(lengths, vec![4, 4, 1, 0], "Only the last piece is short");
+        assert_eq!(multi_file(&[("a", 8)], b"abcdefgh", 4).piece_len(1), 4);
+
+        let empty = multi_file(&[("a", 0)], b"", 4);
+        assert!(empty.info.pieces.0.is_empty());
+        assert_eq!(empty.piece_len(0), 0, "No pieces, nothing to underflow");
+    }
+}
+
+#[cfg(test)]
+mod test_info_hash
+{
+    use sha1::{Digest, Sha1};
+    use crate::torrent::{DhtNode, Torrent};
+
+    #[test]
+    fn hashes_original_info_bytes()
+    {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:abce";
+        let mut bytes = b"d8:announce9:http://t/4:info".to_vec();
+        bytes.extend(info);
+        bytes.extend(b"e");
+
+        let torrent = Torrent::try_from(bytes).unwrap();
+
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(torrent.raw_info, info, "Wrong info span");
+        assert_eq!(torrent.info_hash().unwrap(), expected, "Wrong info hash");
+    }
+
+    #[test]
+    fn parses_trackerless_nodes()
+    {
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend(info);
+        bytes.extend(b"5:nodesll9:127.0.0.1i6881eel7:dht.orgi1234eeee");
+
+        let torrent = Torrent::try_from(bytes).unwrap();
+
+        assert!(torrent.announce.is_empty(), "Trackerless");
+        let nodes: Vec<_> = torrent.nodes.iter().map(DhtNode::to_string).collect();
+        assert_eq!(nodes, vec!["127.0.0.1:6881", "dht.org:1234"]);
+        assert_eq!(torrent.raw_info, info, "Wrong info span");
+    }
+
+    #[test]
+    fn rejects_wrong_piece_count()
+    {
+        let torrent = |pieces: &[u8]| {
+            let mut bytes = format!("d4:infod6:lengthi5e4:name1:a12:piece lengthi4e6:pieces{}:", pieces.len()).into_bytes();
+            bytes.extend(pieces);
+            bytes.extend(b"ee");
+            Torrent::try_from(bytes)
+        };
+
+        assert!(torrent(&[0; 40]).is_ok(), "Five bytes in pieces of four make two pieces");
+        assert!(torrent(&[0; 20]).is_err(), "One hash short");
+        assert!(torrent(&[0; 60]).is_err(), "One hash too many");
+    }
+}
@@ -1,7 +1,18 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
 use anyhow::Context;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
 use crate::torrent::Torrent;
 use crate::tracker::peers::Peers;
+use crate::udp_tracker::{Retry, UdpTracker};
 
 /// FIELD INFO_HASH is not included
 #[derive(Debug, Clone, Serialize)]
@@ -16,7 +27,13 @@ pub struct TrackerRequest
     // 0
     pub left: usize,
     // the length of the file
-    pub compact: bool,
+    pub compact: u8,
+    // 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    // echoes the `tracker id` of an earlier response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
 }
 
 impl TrackerRequest
@@ -30,63 +47,555 @@ impl TrackerRequest
             uploaded: 0,
             downloaded: 0,
             left,
-            compact: true,
+            compact: 1,
+            event: None,
+            trackerid: None,
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event
+{
+    Started,
+    Completed,
+    Stopped,
+}
 pub fn url_encode(t: &[u8; 20]) -> String
 {
     let mut vec = String::with_capacity(3 * t.len());
     for bytes in t
     {
         vec.push('%');
-        vec.push_str(&hex::encode(&[*bytes]))
+        vec.push_str(&hex::encode([*bytes]))
     }
     vec
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError
+{
+    #[error("Tracker refused the request: {0}")]
+    Failure(String),
+    #[error("Tracker did not answer in time")]
+    Timeout,
+    #[error("Invalid tracker response: {0}")]
+    InvalidResponse(String),
+}
+
+/// Swarm statistics for one infohash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ScrapeStats
+{
+    // seeders
+    #[serde(default)]
+    pub complete: usize,
+    // completed downloads
+    #[serde(default)]
+    pub downloaded: usize,
+    // leechers
+    #[serde(default)]
+    pub incomplete: usize,
+}
+
+#[derive(Deserialize)]
+struct ScrapeResponse
+{
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+impl ScrapeStats
+{
+    /// Decodes a bencoded scrape response; infohashes the tracker doesn't know are left out.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<HashMap<[u8; 20], Self>>
+    {
+        let extras: TrackerExtras = serde_bencode::from_bytes(bytes)
+            .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+        if let Some(reason) = extras.failure_reason
+        {
+            return Err(TrackerError::Failure(reason).into());
+        }
+
+        let response: ScrapeResponse = serde_bencode::from_bytes(bytes)
+            .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+        response.files.into_iter()
+            .map(|(info_hash, stats)| {
+                let info_hash: [u8; 20] = info_hash.as_slice().try_into()
+                    .map_err(|_| TrackerError::InvalidResponse(format!("infohash of length {}", info_hash.len())))?;
+                Ok((info_hash, stats))
+            })
+            .collect()
+    }
+}
+
+// an HTTP tracker that has not answered by then is treated as down
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+// how long `stopped` may take before the session is dropped without it
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The client shared by every HTTP tracker request.
+fn http() -> &'static reqwest::Client
+{
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("HTTP client with default TLS"))
+}
+
+/// The scrape url of an http(s) announce url, if the tracker follows the convention.
+pub fn scrape_url(announce: &str) -> Option<String>
+{
+    let slash = announce.rfind('/')?;
+    let (base, last) = announce.split_at(slash + 1);
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{}scrape{}", base, rest))
+}
+
+/// Scrapes several infohashes from one tracker in a single request; `retry` bounds a UDP tracker.
+pub async fn scrape(announce: &str, info_hashes: &[[u8; 20]], retry: Retry) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>>
+{
+    if announce.starts_with("udp://")
+    {
+        let mut tracker = UdpTracker::new(announce).await?.with_retry(retry);
+        let mut all_stats = HashMap::new();
+        for chunk in info_hashes.chunks(74)
+        {
+            let stats = tracker.scrape(chunk).await?;
+            all_stats.extend(chunk.iter().copied().zip(stats));
+        }
+        return Ok(all_stats);
+    }
+
+    let url = scrape_url(announce).with_context(|| format!("{} does not support scrape", announce))?;
+    let params: Vec<_> = info_hashes.iter().map(|info_hash| format!("info_hash={}", url_encode(info_hash))).collect();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}{}", url, separator, params.join("&"));
+
+    let response = http().get(url).send().await.context("Fetch scrape")?;
+    let response = response.bytes().await.context("Fetch scrape response")?;
+    ScrapeStats::from_bytes(&response)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct TrackerResponse
 {
     // in seconds
     pub interval: usize,
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<String>,
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+    // seeders
+    #[serde(default)]
+    pub complete: Option<usize>,
+    // leechers
+    #[serde(default)]
+    pub incomplete: Option<usize>,
+    #[serde(default)]
     pub peers: Peers,
 
 }
+
+/// Keys read before the rest of the response: a `failure reason` and BEP 7 `peers6`.
+#[derive(Deserialize)]
+struct TrackerExtras
+{
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+    #[serde(default, deserialize_with = "peers::deserialize_compact_v6")]
+    peers6: Peers,
+}
 impl TrackerResponse
 {
    pub(crate) async fn query(torrent: &Torrent, peer_id: String) -> anyhow::Result<Self>
     {
-        let length= torrent.len();
-            let tracker_request = TrackerRequest::new(peer_id, length);
-
+        Trackers::new(torrent).query(torrent, peer_id).await
+    }
+   pub(crate) async fn announce(url: &str, info_hash: [u8; 20], tracker_request: &TrackerRequest) -> anyhow::Result<Self>
+    {
             let url_params = serde_urlencoded::to_string(tracker_request).
                 context("URL-tracker params")?;
-            let info_hash = torrent.info_hash()?;
 
-            let tracker_url = format!("{}?{}&info_hash={}",
-                                      torrent.announce,
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let tracker_url = format!("{}{}{}&info_hash={}",
+                                      url,
+                                      separator,
                                       url_params,
                                       &url_encode(&info_hash));
 
-            let response = reqwest::get(tracker_url).await.context("Fetch Tracker")?;
-            let response: TrackerResponse = serde_json::from_slice(&response.bytes().await
-                .context("Fetch tracker response")?)
-                .context("Serialising response bytes")?;
+            let response = http().get(tracker_url).send().await.context("Fetch Tracker")?;
+            let response = response.bytes().await.context("Fetch tracker response")?;
 
+        Self::from_bytes(&response)
+    }
+    /// Decodes a bencoded announce response; a `failure reason` becomes `TrackerError::Failure`.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>
+    {
+        let extras: TrackerExtras = serde_bencode::from_bytes(bytes)
+            .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+        if let Some(reason) = extras.failure_reason
+        {
+            return Err(TrackerError::Failure(reason).into());
+        }
+
+        let mut response: Self = serde_bencode::from_bytes(bytes)
+            .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+        response.peers.0.extend(extras.peers6.0);
         Ok(response)
     }
+    /// Adds the peers of another tracker's response that we don't know yet.
+    pub fn merge(&mut self, other: TrackerResponse)
+    {
+        for peer in other.peers.0
+        {
+            if !self.peers.0.contains(&peer)
+            {
+                self.peers.0.push(peer);
+            }
+        }
+    }
+}
+
+/// The trackers of a torrent grouped in BEP 12 tiers.
+#[derive(Debug)]
+pub struct Trackers
+{
+    tiers: Vec<Vec<String>>,
+    // UDP trackers keep their connection id between announces
+    udp: Mutex<HashMap<String, UdpTracker>>,
+    udp_retry: Retry,
+}
+
+impl Trackers
+{
+    /// Uses `announce-list` when present, otherwise `announce`, and shuffles every tier once.
+    pub fn new(torrent: &Torrent) -> Self
+    {
+        let mut tiers = torrent.announce_list.clone();
+        if tiers.iter().all(Vec::is_empty) && !torrent.announce.is_empty()
+        {
+            tiers = vec![vec![torrent.announce.clone()]];
+        }
+        Self::from_tiers(tiers)
+    }
+    pub fn from_tiers(tiers: Vec<Vec<String>>) -> Self
+    {
+        let mut tiers: Vec<_> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        tiers.iter_mut().for_each(|tier| fastrand::shuffle(tier));
+
+        Self { tiers, udp: Mutex::new(HashMap::new()), udp_retry: Retry::default() }
+    }
+    /// How long each UDP tracker may take to answer before the next one is asked.
+    pub fn with_udp_retry(mut self, retry: Retry) -> Self
+    {
+        self.udp_retry = retry;
+        self
+    }
+    pub fn tiers(&self) -> &[Vec<String>]
+    {
+        &self.tiers
+    }
+    pub(crate) async fn query(&mut self, torrent: &Torrent, peer_id: String) -> anyhow::Result<TrackerResponse>
+    {
+        let info_hash = torrent.info_hash()?;
+        self.announce(info_hash, &TrackerRequest::new(peer_id, torrent.len())).await
+    }
+    pub(crate) async fn announce(&mut self, info_hash: [u8; 20], request: &TrackerRequest) -> anyhow::Result<TrackerResponse>
+    {
+        let udp = &self.udp;
+        let retry = self.udp_retry;
+
+        Self::query_tiers(&mut self.tiers, |url| async move {
+            if url.starts_with("udp://")
+            {
+                let mut clients = udp.lock().await;
+                let client = match clients.entry(url) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let client = UdpTracker::new(entry.key()).await?.with_retry(retry);
+                        entry.insert(client)
+                    }
+                };
+                client.announce(info_hash, request).await
+            } else {
+                TrackerResponse::announce(&url, info_hash, request).await
+            }
+        }).await
+    }
+    /// Asks one tracker per tier, in order, and merges the peers of every tier that answered.
+    /// A tracker that answers is moved to the front of its tier.
+    async fn query_tiers<F, Fut>(tiers: &mut [Vec<String>], mut announce: F) -> anyhow::Result<TrackerResponse>
+        where
+            F: FnMut(String) -> Fut,
+            Fut: Future<Output=anyhow::Result<TrackerResponse>>
+    {
+        let mut merged: Option<TrackerResponse> = None;
+        let mut errors = Vec::new();
+        for tier in tiers
+        {
+            for i in 0..tier.len()
+            {
+                match announce(tier[i].clone()).await
+                {
+                    Ok(response) => {
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        match &mut merged {
+                            Some(merged) => merged.merge(response),
+                            None => merged = Some(response),
+                        }
+                        break;
+                    }
+                    Err(e) => errors.push(e.context(tier[i].clone())),
+                }
+            }
+        }
+        match merged {
+            Some(response) => Ok(response),
+            None if errors.is_empty() => anyhow::bail!("Torrent has no trackers"),
+            // keep a lone error as is, so a typed `TrackerError` can still be downcast
+            None if errors.len() == 1 => Err(errors.remove(0)),
+            None => {
+                let errors: Vec<_> = errors.iter().map(|e| format!("{:#}", e)).collect();
+                anyhow::bail!("Every tracker failed: {}", errors.join("; "))
+            }
+        }
+    }
+}
+
+/// Transfer counters reported to trackers, shared with the downloader.
+#[derive(Debug, Default)]
+pub struct TransferStats
+{
+    pub uploaded: AtomicUsize,
+    pub downloaded: AtomicUsize,
+    pub left: AtomicUsize,
+}
+
+impl TransferStats
+{
+    pub fn new(left: usize) -> Self
+    {
+        Self
+        {
+            left: left.into(),
+            ..Default::default()
+        }
+    }
+    /// Records a verified piece.
+    pub fn piece_done(&self, length: usize)
+    {
+        self.downloaded.fetch_add(length, Ordering::Relaxed);
+        self.left.fetch_sub(length.min(self.left.load(Ordering::Relaxed)), Ordering::Relaxed);
+    }
+}
+
+/// Announces `started` once, then re-announces on the tracker's interval in the background
+/// until `completed`/`stopped`, handing every batch of peers to the downloader.
+#[derive(Debug)]
+pub struct TrackerSession
+{
+    events: mpsc::Sender<Event>,
+    peers: mpsc::Receiver<Vec<SocketAddr>>,
+    stats: Arc<TransferStats>,
+    task: JoinHandle<()>,
+}
+
+impl TrackerSession
+{
+    /// Returns the session and the response to the `started` announce; `port` is where we accept peers.
+    pub async fn start(torrent: &Torrent, peer_id: String, port: u16, stats: Arc<TransferStats>) -> anyhow::Result<(Self, TrackerResponse)>
+    {
+        let info_hash = torrent.info_hash()?;
+        let mut trackers = Trackers::new(torrent);
+        let mut request = TrackerRequest::new(peer_id, 0);
+        request.port = port;
+
+        let response = Self::announce(&mut trackers, info_hash, &mut request, &stats, Some(Event::Started)).await?;
+
+        let (events, mut event_rx) = mpsc::channel(4);
+        let (peer_tx, peers) = mpsc::channel(16);
+        let task_stats = stats.clone();
+        let mut interval = Self::interval(&response);
+        let task = tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = tokio::time::sleep(interval) => None,
+                    event = event_rx.recv() => Some(event.unwrap_or(Event::Stopped)),
+                };
+                match Self::announce(&mut trackers, info_hash, &mut request, &task_stats, event).await {
+                    Ok(response) => {
+                        interval = Self::interval(&response);
+                        // the downloader may be busy, the next announce brings peers again
+                        let _ = peer_tx.try_send(response.peers.0);
+                    }
+                    Err(e) => eprintln!("Re-announce failed: {:#}", e),
+                }
+                if event == Some(Event::Stopped)
+                {
+                    break;
+                }
+            }
+        });
+
+        Ok((Self { events, peers, stats, task }, response))
+    }
+    pub fn stats(&self) -> &Arc<TransferStats>
+    {
+        &self.stats
+    }
+    /// Peers from re-announces since the last call.
+    pub fn new_peers(&mut self) -> Vec<SocketAddr>
+    {
+        let mut new_peers = Vec::new();
+        while let Ok(peers) = self.peers.try_recv()
+        {
+            new_peers.extend(peers);
+        }
+        new_peers
+    }
+    /// Announces `completed`; call once the last piece is verified.
+    pub async fn completed(&self)
+    {
+        let _ = self.events.send(Event::Completed).await;
+    }
+    /// Announces `stopped` and ends the session; gives up on the announce after a few seconds.
+    pub async fn stop(self)
+    {
+        self.stop_within(STOP_TIMEOUT).await
+    }
+    async fn stop_within(mut self, timeout: Duration)
+    {
+        let stopped = tokio::time::timeout(timeout, async {
+            let _ = self.events.send(Event::Stopped).await;
+            let _ = (&mut self.task).await;
+        }).await;
+        if stopped.is_err()
+        {
+            self.task.abort();
+        }
+    }
+    async fn announce(
+        trackers: &mut Trackers,
+        info_hash: [u8; 20],
+        request: &mut TrackerRequest,
+        stats: &TransferStats,
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse>
+    {
+        request.uploaded = stats.uploaded.load(Ordering::Relaxed);
+        request.downloaded = stats.downloaded.load(Ordering::Relaxed);
+        request.left = stats.left.load(Ordering::Relaxed);
+        request.event = event;
+
+        let response = trackers.announce(info_hash, request).await?;
+        if let Some(warning) = &response.warning_message
+        {
+            eprintln!("Tracker warning: {}", warning);
+        }
+        if response.tracker_id.is_some()
+        {
+            request.trackerid = response.tracker_id.clone();
+        }
+        Ok(response)
+    }
+    fn interval(response: &TrackerResponse) -> Duration
+    {
+        let seconds = response.interval.max(response.min_interval.unwrap_or(0)).max(1);
+        Duration::from_secs(seconds as u64)
+    }
 }
 
 pub mod peers
 {
     use std::fmt;
-    use std::net::{Ipv4Addr, SocketAddrV4};
-    use serde::de::{Error, Visitor};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use serde::de::{Error, SeqAccess, Visitor};
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
+
+    /// 4 bytes of IPv4 address followed by 2 bytes of port, per peer.
+    pub fn from_compact_v4(bytes: &[u8]) -> Option<Vec<SocketAddr>>
+    {
+        if !bytes.len().is_multiple_of(6)
+        {
+            return None;
+        }
+        Some(
+            bytes.chunks_exact(6)
+                .map(|bytes|
+                    {
+                        SocketAddr::new(
+                            Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into(),
+                            u16::from_be_bytes([bytes[4],bytes[5]]),
+                        )
+                    }).collect()
+        )
+    }
+
+    /// 16 bytes of IPv6 address followed by 2 bytes of port, per peer (BEP 7).
+    pub fn from_compact_v6(bytes: &[u8]) -> Option<Vec<SocketAddr>>
+    {
+        if !bytes.len().is_multiple_of(18)
+        {
+            return None;
+        }
+        Some(
+            bytes.chunks_exact(18)
+                .map(|bytes|
+                    {
+                        let ip: [u8; 16] = bytes[..16].try_into().expect("guaranty to be 16");
+                        SocketAddr::new(
+                            Ipv6Addr::from(ip).into(),
+                            u16::from_be_bytes([bytes[16],bytes[17]]),
+                        )
+                    }).collect()
+        )
+    }
+
+    /// The IPv4 peers of `peers` in compact form; the rest is skipped.
+    pub fn to_compact_v4(peers: &[SocketAddr]) -> Vec<u8>
+    {
+        let mut bytes = Vec::with_capacity(6 * peers.len());
+        for peer in peers
+        {
+            let SocketAddr::V4(peer) = peer else {
+                continue;
+            };
+            bytes.extend(peer.ip().octets());
+            bytes.extend(peer.port().to_be_bytes());
+        }
+        bytes
+    }
+
+    /// The IPv6 peers of `peers` in compact form; the rest is skipped.
+    pub fn to_compact_v6(peers: &[SocketAddr]) -> Vec<u8>
+    {
+        let mut bytes = Vec::with_capacity(18 * peers.len());
+        for peer in peers
+        {
+            let SocketAddr::V6(peer) = peer else {
+                continue;
+            };
+            bytes.extend(peer.ip().octets());
+            bytes.extend(peer.port().to_be_bytes());
+        }
+        bytes
+    }
+
+    /// The original, non-compact form of a peer; `peer id` is ignored.
+    #[derive(Deserialize)]
+    struct PeerDictionary
+    {
+        ip: String,
+        port: u16,
+    }
 
     struct PeersVisitor;
 
@@ -94,25 +603,24 @@ pub mod peers
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a String that first 4 bytes is ip, and last 2 is port number")
+            formatter.write_str("a String that first 4 bytes is ip, and last 2 is port number, or a list of peer dictionaries")
         }
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: Error {
-            if v.len() % 6 != 0
+            from_compact_v4(v)
+                .map(Peers)
+                .ok_or_else(|| E::custom(format!("Length is {}", v.len())))
+        }
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: SeqAccess<'de> {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<PeerDictionary>()?
             {
-                return Err(E::custom(format!("Length is {}", v.len())));
+                // the ip may also be a DNS name, which we don't resolve
+                if let Ok(ip) = peer.ip.parse::<IpAddr>()
+                {
+                    peers.push(SocketAddr::new(ip, peer.port));
+                }
             }
-            let peers = v.chunks_exact(6)
-                .map(|bytes|
-                    {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
-                            u16::from_be_bytes([bytes[4],bytes[5]]),
-                        )
-                    }).collect();
-
-            Ok(
-                Peers(peers)
-            )
+            Ok(Peers(peers))
         }
     }
 
@@ -121,23 +629,334 @@ pub mod peers
             where
                 D: Deserializer<'de>
         {
-            deserializer.deserialize_string(PeersVisitor)
+            deserializer.deserialize_any(PeersVisitor)
         }
     }
 
+    struct Peers6Visitor;
+
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a String that first 16 bytes is ip, and last 2 is port number")
+        }
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: Error {
+            from_compact_v6(v)
+                .map(Peers)
+                .ok_or_else(|| E::custom(format!("Length is {}", v.len())))
+        }
+    }
+
+    /// For the BEP 7 `peers6` key.
+    pub fn deserialize_compact_v6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        deserializer.deserialize_bytes(Peers6Visitor)
+    }
+
     impl Serialize for Peers
     {
+        /// Compact IPv4 form; IPv6 peers belong in a separate `peers6` string.
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
             where S: Serializer
         {
-            let mut single_slice = Vec::with_capacity(6 * self.0.len());
-            for peer in &self.0
+            serializer.serialize_bytes(&to_compact_v4(&self.0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_trackers
+{
+    use std::net::SocketAddr;
+    use crate::tracker::{TrackerResponse, Trackers};
+    use crate::tracker::peers::Peers;
+
+    fn response(peers: &[&str]) -> TrackerResponse
+    {
+        TrackerResponse
+        {
+            interval: 60,
+            peers: Peers(peers.iter().map(|peer| peer.parse::<SocketAddr>().unwrap()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_promotes()
+    {
+        let mut tiers = vec![
+            vec!["down".to_string(), "up".to_string()],
+            vec!["second".to_string()],
+            vec!["dead".to_string()],
+        ];
+
+        let response = Trackers::query_tiers(&mut tiers, |url| async move {
+            match url.as_str() {
+                "up" => Ok(response(&["1.1.1.1:1", "2.2.2.2:2"])),
+                "second" => Ok(response(&["2.2.2.2:2", "3.3.3.3:3"])),
+                _ => anyhow::bail!("unreachable tracker"),
+            }
+        }).await.unwrap();
+
+        assert_eq!(response.peers.0.len(), 3, "Peers should be merged without duplicates");
+        assert_eq!(tiers[0], vec!["up".to_string(), "down".to_string()], "Tracker should be promoted");
+    }
+
+    #[tokio::test]
+    async fn reports_every_failure()
+    {
+        let mut tiers = vec![vec!["a".to_string()], vec!["b".to_string()]];
+
+        let error = Trackers::query_tiers(&mut tiers, |_| async { anyhow::bail!("down") }).await.unwrap_err();
+
+        let error = error.to_string();
+        assert!(error.contains("a: down") && error.contains("b: down"), "Got: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod test_tracker_response
+{
+    use crate::tracker::{TrackerError, TrackerResponse};
+
+    #[test]
+    fn parses_bencoded_response()
+    {
+        let mut bytes = b"d8:completei4e10:incompletei2e8:intervali1800e12:min intervali900e5:peers6:".to_vec();
+        bytes.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        bytes.extend(b"10:tracker id3:abc15:warning message4:slowe");
+
+        let response = TrackerResponse::from_bytes(&bytes).unwrap();
+
+        assert_eq!(response.interval, 1800, "Wrong interval");
+        assert_eq!(response.min_interval, Some(900), "Wrong min interval");
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"), "Wrong tracker id");
+        assert_eq!(response.warning_message.as_deref(), Some("slow"), "Wrong warning");
+        assert_eq!((response.complete, response.incomplete), (Some(4), Some(2)), "Wrong swarm size");
+        assert_eq!(response.peers.0, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn failure_reason_is_typed()
+    {
+        let error = TrackerResponse::from_bytes(b"d14:failure reason17:torrent not founde").unwrap_err();
+
+        match error.downcast_ref::<TrackerError>() {
+            Some(TrackerError::Failure(reason)) => assert_eq!(reason, "torrent not found"),
+            _ => panic!("Expected a tracker failure, got: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn garbage_is_invalid()
+    {
+        let error = TrackerResponse::from_bytes(b"<html>").unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::InvalidResponse(_))), "Got: {:?}", error);
+    }
+}
+
+#[cfg(test)]
+mod test_peers
+{
+    use std::net::SocketAddr;
+    use crate::tracker::TrackerResponse;
+
+    #[test]
+    fn parses_peer_dictionaries()
+    {
+        let bytes = b"d8:intervali60e5:peersld2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip3:::14:porti80eed2:ip11:example.com4:porti1eeee";
+
+        let response = TrackerResponse::from_bytes(bytes).unwrap();
+
+        assert_eq!(response.peers.0, vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "[::1]:80".parse::<SocketAddr>().unwrap(),
+        ], "DNS names should be skipped");
+    }
+
+    #[test]
+    fn merges_peers6()
+    {
+        let mut bytes = b"d8:intervali60e5:peers6:".to_vec();
+        bytes.extend([10, 0, 0, 1, 0, 80]);
+        bytes.extend(b"6:peers618:");
+        bytes.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+        bytes.extend([0x1a, 0xe1]);
+        bytes.extend(b"e");
+
+        let response = TrackerResponse::from_bytes(&bytes).unwrap();
+
+        assert_eq!(response.peers.0, vec![
+            "10.0.0.1:80".parse::<SocketAddr>().unwrap(),
+            "[::1]:6881".parse::<SocketAddr>().unwrap(),
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod test_tracker_session
+{
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use crate::torrent::Torrent;
+    use crate::tracker::{TrackerRequest, TrackerSession, TransferStats};
+
+    #[test]
+    fn serializes_event()
+    {
+        let mut request = TrackerRequest::new(String::from("00112233445566778890"), 5);
+        request.event = Some(super::Event::Started);
+
+        let query = serde_urlencoded::to_string(&request).unwrap();
+
+        assert!(query.contains("compact=1"), "Got: {}", query);
+        assert!(query.contains("event=started"), "Got: {}", query);
+        assert!(!query.contains("trackerid"), "Got: {}", query);
+    }
+
+    /// HTTP stand-in tracker that answers every announce with a one second interval and
+    /// reports the query string of each request.
+    async fn stand_in() -> (String, mpsc::UnboundedReceiver<String>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (queries, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut port = 1_u16;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let length = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..length]).into_owned();
+                let query = request.split_whitespace().nth(1).unwrap().to_string();
+                queries.send(query).unwrap();
+
+                let mut body = b"d8:intervali1e10:tracker id2:id5:peers6:".to_vec();
+                body.extend([127, 0, 0, 1]);
+                body.extend(port.to_be_bytes());
+                body.extend(b"e");
+                port += 1;
+                let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                response.extend(body);
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn announce_lifecycle()
+    {
+        let (url, mut queries) = stand_in().await;
+        let mut torrent = Torrent::try_from(
+            b"d4:infod6:lengthi10e4:name1:a12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee".to_vec()
+        ).unwrap();
+        torrent.announce = url;
+        let stats = Arc::new(TransferStats::new(10));
+
+        let (mut session, response) = TrackerSession::start(&torrent, String::from("00112233445566778890"), 6881, stats.clone()).await.unwrap();
+        assert_eq!(response.peers.0.len(), 1, "Should get the first peer");
+        let started = queries.recv().await.unwrap();
+        assert!(started.contains("event=started") && started.contains("left=10"), "Got: {}", started);
+
+        let reannounce = tokio::time::timeout(Duration::from_secs(3), queries.recv()).await.unwrap().unwrap();
+        assert!(!reannounce.contains("event="), "Got: {}", reannounce);
+        assert!(reannounce.contains("trackerid=id"), "Tracker id should be echoed, got: {}", reannounce);
+        // the stand-in reports the query before the session has read the answer
+        let mut new_peers = Vec::new();
+        for _ in 0..100
+        {
+            new_peers = session.new_peers();
+            if !new_peers.is_empty()
             {
-               single_slice.extend(peer.ip().octets());
-                single_slice.extend(peer.port().to_be_bytes());
+                break;
             }
-            serializer.serialize_bytes(&single_slice)
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(new_peers.len(), 1, "Re-announce should bring a peer");
+
+        stats.piece_done(10);
+        session.completed().await;
+        let completed = queries.recv().await.unwrap();
+        assert!(completed.contains("event=completed") && completed.contains("left=0") && completed.contains("downloaded=10"), "Got: {}", completed);
+
+        session.stop().await;
+        let stopped = queries.recv().await.unwrap();
+        assert!(stopped.contains("event=stopped"), "Got: {}", stopped);
+    }
+
+    #[tokio::test]
+    async fn stop_gives_up_on_silent_tracker()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut torrent = Torrent::try_from(
+            b"d4:infod6:lengthi10e4:name1:a12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee".to_vec()
+        ).unwrap();
+        torrent.announce = format!("http://{}/announce", listener.local_addr().unwrap());
+        // answers `started`, then reads every later request and never answers
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = vec![0; 4096];
+            let _ = stream.read(&mut buffer).await.unwrap();
+            let body = b"d8:intervali1800e5:peers0:e";
+            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+            response.extend(body);
+            stream.write_all(&response).await.unwrap();
+            let mut silent = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut buffer).await;
+                silent.push(stream);
+            }
+        });
+        let (session, _) = TrackerSession::start(&torrent, String::from("00112233445566778890"), 6881, Arc::new(TransferStats::new(10))).await.unwrap();
+
+        let started = std::time::Instant::now();
+        session.stop_within(Duration::from_millis(200)).await;
+
+        assert!(started.elapsed() < Duration::from_secs(2), "Took {:?}", started.elapsed());
     }
 }
 
+#[cfg(test)]
+mod test_scrape
+{
+    use crate::tracker::{scrape_url, ScrapeStats, TrackerError};
+
+    #[test]
+    fn derives_scrape_url()
+    {
+        assert_eq!(scrape_url("http://t.org/announce").as_deref(), Some("http://t.org/scrape"));
+        assert_eq!(scrape_url("http://t.org/x/announce.php?key=1").as_deref(), Some("http://t.org/x/scrape.php?key=1"));
+        assert_eq!(scrape_url("http://t.org/a"), None);
+        assert_eq!(scrape_url("http://t.org/announce/x"), None);
+    }
+
+    #[test]
+    fn parses_files()
+    {
+        let mut bytes = b"d5:filesd20:".to_vec();
+        bytes.extend([1; 20]);
+        bytes.extend(b"d8:completei5e10:downloadedi50e10:incompletei10e4:name1:ae20:");
+        bytes.extend([2; 20]);
+        bytes.extend(b"d8:completei1eeee");
+
+        let stats = ScrapeStats::from_bytes(&bytes).unwrap();
+
+        assert_eq!(stats[&[1; 20]], ScrapeStats { complete: 5, downloaded: 50, incomplete: 10 });
+        assert_eq!(stats[&[2; 20]], ScrapeStats { complete: 1, downloaded: 0, incomplete: 0 });
+    }
+
+    #[test]
+    fn failure_reason_is_typed()
+    {
+        let error = ScrapeStats::from_bytes(b"d14:failure reason8:disablede").unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::Failure(_))), "Got: {:?}", error);
+    }
+}
@@ -0,0 +1,455 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use anyhow::Context;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use crate::tracker::{Event, ScrapeStats, TrackerError, TrackerRequest, TrackerResponse};
+use crate::tracker::peers;
+use crate::tracker::peers::Peers;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// a connection id may be used for one minute after the tracker handed it out
+const CONNECTION_TTL: Duration = Duration::from_secs(60);
+
+/// How long one request to a UDP tracker may take: `timeout * 2^n` before retransmit `n`,
+/// and no longer than `deadline` overall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry
+{
+    pub timeout: Duration,
+    pub retries: u32,
+    pub deadline: Duration,
+}
+
+impl Retry
+{
+    /// A few quick retries, for callers that would rather move on than wait out a dead tracker.
+    pub fn short() -> Self
+    {
+        Self
+        {
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for Retry
+{
+    /// BEP 15's schedule: 15 seconds doubled up to 8 times, about two hours in all.
+    fn default() -> Self
+    {
+        let timeout = Duration::from_secs(15);
+        let retries = 8;
+        Self
+        {
+            timeout,
+            retries,
+            deadline: timeout * (2_u32.pow(retries + 1) - 1),
+        }
+    }
+}
+
+/// BEP 15 UDP tracker client.
+#[derive(Debug)]
+pub struct UdpTracker
+{
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    key: u32,
+    retry: Retry,
+}
+
+impl UdpTracker
+{
+    /// Resolves a `udp://host:port[/announce]` url and binds a local socket for it.
+    pub async fn new(url: &str) -> anyhow::Result<Self>
+    {
+        let parsed = reqwest::Url::parse(url).with_context(|| format!("Parsing tracker url {}", url))?;
+        anyhow::ensure!(parsed.scheme() == "udp", "Not a UDP tracker: {}", url);
+        let host = parsed.host_str().context("Tracker url has no host")?;
+        let port = parsed.port().context("Tracker url has no port")?;
+
+        let addr = tokio::net::lookup_host((host, port)).await
+            .with_context(|| format!("Resolving {}", host))?
+            .next()
+            .with_context(|| format!("{} has no address", host))?;
+        Self::connect(addr).await
+    }
+    pub async fn connect(addr: SocketAddr) -> anyhow::Result<Self>
+    {
+        let socket = match addr {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await,
+        }.context("Binding UDP socket")?;
+        socket.connect(addr).await.with_context(|| format!("Connecting UDP socket to {}", addr))?;
+        Ok(
+            Self
+            {
+                socket,
+                connection: None,
+                key: fastrand::u32(..),
+                retry: Retry::default(),
+            }
+        )
+    }
+    pub fn with_retry(mut self, retry: Retry) -> Self
+    {
+        self.retry = retry;
+        self
+    }
+    pub async fn announce(&mut self, info_hash: [u8; 20], request: &TrackerRequest) -> anyhow::Result<TrackerResponse>
+    {
+        let peer_id: [u8; 20] = request.peer_id.as_bytes().try_into()
+            .context("Peer id should be 20 bytes")?;
+
+        let mut body = Vec::with_capacity(82);
+        body.extend(info_hash);
+        body.extend(peer_id);
+        body.extend((request.downloaded as u64).to_be_bytes());
+        body.extend((request.left as u64).to_be_bytes());
+        body.extend((request.uploaded as u64).to_be_bytes());
+        body.extend(match request.event {
+            None => 0_u32,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        }.to_be_bytes());
+        body.extend(0_u32.to_be_bytes()); // ip: the sender's address
+        body.extend(self.key.to_be_bytes());
+        body.extend((-1_i32).to_be_bytes()); // num_want: tracker default
+        body.extend(request.port.to_be_bytes());
+
+        let response = self.request(ACTION_ANNOUNCE, &body).await?;
+        // peers come in the address family of the tracker: 6 bytes for IPv4, 18 for IPv6
+        let peers = match self.socket.peer_addr().context("UDP tracker address")? {
+            _ if response.len() < 12 => None,
+            SocketAddr::V4(_) => peers::from_compact_v4(&response[12..]),
+            SocketAddr::V6(_) => peers::from_compact_v6(&response[12..]),
+        };
+        let Some(peers) = peers else {
+            return Err(TrackerError::InvalidResponse(format!("announce of length {}", response.len())).into());
+        };
+
+        let number = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().expect("guaranty to be 4")) as usize;
+        Ok(
+            TrackerResponse
+            {
+                interval: number(&response[0..4]),
+                incomplete: Some(number(&response[4..8])),
+                complete: Some(number(&response[8..12])),
+                peers: Peers(peers),
+                ..Default::default()
+            }
+        )
+    }
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>>
+    {
+        // about 74 hashes fit in one packet
+        anyhow::ensure!(!info_hashes.is_empty() && info_hashes.len() <= 74, "Can scrape 1 to 74 infohashes at once");
+
+        let response = self.request(ACTION_SCRAPE, &info_hashes.concat()).await?;
+        if response.len() != 12 * info_hashes.len()
+        {
+            return Err(TrackerError::InvalidResponse(format!("scrape of length {}", response.len())).into());
+        }
+
+        let number = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().expect("guaranty to be 4")) as usize;
+        Ok(
+            response.chunks_exact(12)
+                .map(|stats|
+                    ScrapeStats
+                    {
+                        complete: number(&stats[0..4]),
+                        downloaded: number(&stats[4..8]),
+                        incomplete: number(&stats[8..12]),
+                    })
+                .collect()
+        )
+    }
+    /// Sends `action` with `body`, (re)connecting first when needed, and returns the response after its header.
+    async fn request(&mut self, action: u32, body: &[u8]) -> anyhow::Result<Vec<u8>>
+    {
+        let deadline = Instant::now() + self.retry.deadline;
+        for n in 0..=self.retry.retries
+        {
+            if Instant::now() >= deadline
+            {
+                break;
+            }
+            let timeout = self.retry.timeout.saturating_mul(2_u32.saturating_pow(n));
+            let until = || (Instant::now() + timeout).min(deadline);
+
+            let connection_id = match self.connection {
+                Some((id, since)) if since.elapsed() < CONNECTION_TTL => id,
+                _ => {
+                    let Some(response) = self.exchange(PROTOCOL_ID, ACTION_CONNECT, &[], until()).await? else {
+                        continue;
+                    };
+                    let id: [u8; 8] = response.get(..8)
+                        .and_then(|id| id.try_into().ok())
+                        .ok_or_else(|| TrackerError::InvalidResponse(String::from("connect without id")))?;
+                    let id = u64::from_be_bytes(id);
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            if let Some(response) = self.exchange(connection_id, action, body, until()).await?
+            {
+                return Ok(response);
+            }
+        }
+        Err(TrackerError::Timeout.into())
+    }
+    /// One request/response round trip. Returns `None` when nothing matching arrived by `deadline`.
+    async fn exchange(&mut self, connection_id: u64, action: u32, body: &[u8], deadline: Instant) -> anyhow::Result<Option<Vec<u8>>>
+    {
+        let transaction_id = fastrand::u32(..);
+
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend(connection_id.to_be_bytes());
+        packet.extend(action.to_be_bytes());
+        packet.extend(transaction_id.to_be_bytes());
+        packet.extend(body);
+        self.socket.send(&packet).await.context("Sending to UDP tracker")?;
+
+        let mut buffer = vec![0_u8; 16 * 1024];
+        loop {
+            let Ok(received) = tokio::time::timeout_at(deadline, self.socket.recv(&mut buffer)).await else {
+                return Ok(None);
+            };
+            let length = received.context("Receiving from UDP tracker")?;
+            if length < 8
+            {
+                continue;
+            }
+            let response_action = u32::from_be_bytes(buffer[0..4].try_into().expect("guaranty to be 4"));
+            let response_transaction = u32::from_be_bytes(buffer[4..8].try_into().expect("guaranty to be 4"));
+            if response_transaction != transaction_id
+            {
+                // a late answer to an earlier attempt
+                continue;
+            }
+            if response_action == ACTION_ERROR
+            {
+                // the tracker may have forgotten our connection id, so the next request asks for a new one
+                self.connection = None;
+                let message = String::from_utf8_lossy(&buffer[8..length]).into_owned();
+                return Err(TrackerError::Failure(message).into());
+            }
+            if response_action != action
+            {
+                return Err(TrackerError::InvalidResponse(format!("expected action {}, got {}", action, response_action)).into());
+            }
+            return Ok(Some(buffer[8..length].to_vec()));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test_udp_tracker
+{
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use crate::tracker::{ScrapeStats, TrackerError, TrackerRequest};
+    use crate::udp_tracker::{Retry, PROTOCOL_ID, UdpTracker};
+
+    const KNOWN_HASH: [u8; 20] = [7; 20];
+    const CONNECTION_ID: u64 = 0xdead_beef;
+
+    /// Loopback stand-in tracker. It ignores the first `drop_first` packets, answers every
+    /// request with a stale transaction id before the real answer, and counts connects.
+    async fn stand_in(drop_first: usize) -> (SocketAddr, tokio::task::JoinHandle<usize>)
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut connects = 0;
+            let mut seen = 0;
+            let mut buffer = [0_u8; 2048];
+            loop {
+                let Ok(Ok((length, from))) = tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer)).await else {
+                    return connects;
+                };
+                seen += 1;
+                if seen <= drop_first
+                {
+                    continue;
+                }
+                let packet = &buffer[..length];
+                let connection_id = u64::from_be_bytes(packet[0..8].try_into().unwrap());
+                let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+                let transaction = &packet[12..16];
+
+                let mut stale = action.to_be_bytes().to_vec();
+                stale.extend(transaction.iter().map(|b| !b));
+                socket.send_to(&stale, from).await.unwrap();
+
+                let mut response = Vec::new();
+                match action {
+                    0 => {
+                        assert_eq!(connection_id, PROTOCOL_ID, "Connect should carry the protocol id");
+                        connects += 1;
+                        response.extend(0_u32.to_be_bytes());
+                        response.extend(transaction);
+                        response.extend(CONNECTION_ID.to_be_bytes());
+                    }
+                    _ if connection_id != CONNECTION_ID => {
+                        response.extend(3_u32.to_be_bytes());
+                        response.extend(transaction);
+                        response.extend(b"unknown connection");
+                    }
+                    1 if packet[16..36] == KNOWN_HASH => {
+                        assert_eq!(packet.len(), 98, "Wrong announce length");
+                        response.extend(1_u32.to_be_bytes());
+                        response.extend(transaction);
+                        response.extend(1800_u32.to_be_bytes());
+                        response.extend(1_u32.to_be_bytes());
+                        response.extend(2_u32.to_be_bytes());
+                        response.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                    }
+                    2 => {
+                        response.extend(2_u32.to_be_bytes());
+                        response.extend(transaction);
+                        for hash in packet[16..].chunks_exact(20)
+                        {
+                            let known = (hash == KNOWN_HASH) as u32;
+                            response.extend((2 * known).to_be_bytes());
+                            response.extend((5 * known).to_be_bytes());
+                            response.extend(known.to_be_bytes());
+                        }
+                    }
+                    _ => {
+                        response.extend(3_u32.to_be_bytes());
+                        response.extend(transaction);
+                        response.extend(b"torrent not registered");
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (addr, handle)
+    }
+
+    fn retry(millis: u64, retries: u32) -> Retry
+    {
+        Retry { timeout: Duration::from_millis(millis), retries, deadline: Duration::from_secs(10) }
+    }
+
+    fn request() -> TrackerRequest
+    {
+        TrackerRequest::new(String::from("00112233445566778890"), 100)
+    }
+
+    #[tokio::test]
+    async fn announces_and_caches_connection()
+    {
+        let (addr, stand_in) = stand_in(0).await;
+        let mut tracker = UdpTracker::connect(addr).await.unwrap()
+            .with_retry(retry(100, 2));
+
+        let response = tracker.announce(KNOWN_HASH, &request()).await.unwrap();
+        assert_eq!(response.interval, 1800, "Wrong interval");
+        assert_eq!(response.peers.0, vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse::<SocketAddr>().unwrap(),
+        ]);
+
+        let stats = tracker.scrape(&[KNOWN_HASH, [0; 20]]).await.unwrap();
+        assert_eq!(stats, vec![
+            ScrapeStats { complete: 2, downloaded: 5, incomplete: 1 },
+            ScrapeStats { complete: 0, downloaded: 0, incomplete: 0 },
+        ]);
+
+        drop(tracker);
+        assert_eq!(stand_in.await.unwrap(), 1, "Connection id should be reused");
+    }
+
+    #[tokio::test]
+    async fn retransmits_after_timeout()
+    {
+        let (addr, _stand_in) = stand_in(1).await;
+        let mut tracker = UdpTracker::connect(addr).await.unwrap()
+            .with_retry(retry(50, 2));
+
+        let response = tracker.announce(KNOWN_HASH, &request()).await.unwrap();
+
+        assert_eq!(response.peers.0.len(), 2, "Should answer after the retransmit");
+    }
+
+    #[tokio::test]
+    async fn surfaces_error_action()
+    {
+        let (addr, _stand_in) = stand_in(0).await;
+        let mut tracker = UdpTracker::connect(addr).await.unwrap()
+            .with_retry(retry(100, 2));
+
+        let error = tracker.announce([1; 20], &request()).await.unwrap_err();
+
+        match error.downcast_ref::<TrackerError>() {
+            Some(TrackerError::Failure(message)) => assert_eq!(message, "torrent not registered"),
+            _ => panic!("Expected a tracker failure, got: {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_error_action()
+    {
+        let (addr, stand_in) = stand_in(0).await;
+        let mut tracker = UdpTracker::connect(addr).await.unwrap()
+            .with_retry(retry(100, 2));
+
+        tracker.announce([1; 20], &request()).await.unwrap_err();
+        tracker.announce(KNOWN_HASH, &request()).await.unwrap();
+
+        drop(tracker);
+        assert_eq!(stand_in.await.unwrap(), 2, "An error should drop the connection id");
+    }
+
+    #[tokio::test]
+    async fn times_out_without_tracker()
+    {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = UdpTracker::connect(silent.local_addr().unwrap()).await.unwrap()
+            .with_retry(retry(10, 1));
+
+        let error = tracker.announce(KNOWN_HASH, &request()).await.unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::Timeout)), "Got: {:?}", error);
+    }
+
+    #[test]
+    fn defaults_to_bep_15_schedule()
+    {
+        let retry = Retry::default();
+
+        assert_eq!(retry.timeout, Duration::from_secs(15));
+        assert_eq!(retry.retries, 8);
+        assert_eq!(retry.deadline, Duration::from_secs(15 * 511), "The deadline should not cut the last wait short");
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_deadline()
+    {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // the waits alone would add up to about 17 minutes
+        let mut tracker = UdpTracker::connect(silent.local_addr().unwrap()).await.unwrap()
+            .with_retry(Retry { deadline: Duration::from_millis(200), ..retry(1000, 9) });
+
+        let started = std::time::Instant::now();
+        let error = tracker.announce(KNOWN_HASH, &request()).await.unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::Timeout)), "Got: {:?}", error);
+        assert!(started.elapsed() < Duration::from_secs(1), "Took {:?}", started.elapsed());
+    }
+}
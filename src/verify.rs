@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status
+{
+    Complete,
+    /// Not on disk: a missing or short file.
+    Missing,
+    /// On disk, but the hash does not match.
+    Corrupt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileReport
+{
+    pub path: PathBuf,
+    pub length: usize,
+    pub status: Status,
+}
+
+/// What `verify` found: every piece, and every file judged by the pieces it overlaps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report
+{
+    pub pieces: Vec<Status>,
+    pub files: Vec<FileReport>,
+}
+
+impl Report
+{
+    pub fn is_complete(&self) -> bool
+    {
+        self.pieces.iter().all(|status| *status == Status::Complete)
+    }
+    pub fn count(&self, status: Status) -> usize
+    {
+        self.pieces.iter().filter(|piece| **piece == status).count()
+    }
+    /// The indices of the pieces with `status`.
+    pub fn pieces_with(&self, status: Status) -> Vec<usize>
+    {
+        self.pieces.iter().enumerate().filter(|(_, piece)| **piece == status).map(|(index, _)| index).collect()
+    }
+    /// The report for scripts: counts, the failed pieces and every file.
+    pub fn to_json(&self) -> serde_json::Value
+    {
+        serde_json::json!({
+            "complete": self.is_complete(),
+            "pieces": {
+                "total": self.pieces.len(),
+                "complete": self.count(Status::Complete),
+                "missing": self.pieces_with(Status::Missing),
+                "corrupt": self.pieces_with(Status::Corrupt),
+            },
+            "files": self.files,
+        })
+    }
+}
+
+/// Hashes every piece of `storage` against the torrent and judges each file by its pieces:
+/// an absent file is missing, an empty one complete, otherwise it takes the worst status of
+/// the pieces it overlaps.
+pub fn verify(torrent: &Torrent, storage: &Storage) -> Report
+{
+    let pieces = pieces(torrent, storage);
+    let piece_length = torrent.info.piece_length;
+    let files = storage.layout()
+        .map(|(path, range)| {
+            let status = if !path.is_file()
+            {
+                Status::Missing
+            } else if range.is_empty() {
+                // overlaps no piece, even where it starts inside one
+                Status::Complete
+            } else {
+                let overlapped = range.start / piece_length..range.end.div_ceil(piece_length);
+                pieces[overlapped].iter().copied().max().unwrap_or(Status::Complete)
+            };
+            FileReport { path: path.to_path_buf(), length: range.len(), status }
+        })
+        .collect();
+    Report { pieces, files }
+}
+
+/// The status of every piece, hashed on all cores.
+pub fn pieces(torrent: &Torrent, storage: &Storage) -> Vec<Status>
+{
+    in_parallel(torrent.info.pieces.0.len(), |index| piece(torrent, storage, index))
+}
+
+/// `work(0..count)` spread over all cores, in order.
+pub(crate) fn in_parallel<T: Send>(count: usize, work: impl Fn(usize) -> T + Sync) -> Vec<T>
+{
+    let workers = std::thread::available_parallelism().map_or(1, usize::from).min(count.max(1));
+    // workers take the next index until none are left, so slow reads do not hold others up
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<T>> = (0..count).map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| scope.spawn(|| {
+                let mut done = Vec::new();
+                loop
+                {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= count
+                    {
+                        return done;
+                    }
+                    done.push((index, work(index)));
+                }
+            }))
+            .collect();
+        for handle in handles
+        {
+            for (index, result) in handle.join().expect("Worker thread panicked")
+            {
+                results[index] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(|result| result.expect("Every index is done")).collect()
+}
+
+fn piece(torrent: &Torrent, storage: &Storage, index: usize) -> Status
+{
+    match storage.read_piece(torrent, index) {
+        Err(_) => Status::Missing,
+        Ok(piece) if <[u8; 20]>::from(Sha1::digest(&piece)) == torrent.info.pieces.0[index] => Status::Complete,
+        Ok(_) => Status::Corrupt,
+    }
+}
+
+
+#[cfg(test)]
+mod test_verify
+{
+    use crate::create::TorrentBuilder;
+    use crate::storage::Storage;
+    use crate::torrent::fixture::multi_file;
+    use crate::torrent::Torrent;
+    use crate::verify::{verify, Status};
+
+    /// Three files, `a` with 3 bytes, `b` with 5 and `c` with 4, in pieces of 4.
+    fn torrent(data: &[u8]) -> Torrent
+    {
+        multi_file(&[("a", 3), ("b", 5), ("c", 4)], data, 4)
+    }
+
+    #[test]
+    fn reports_pieces_and_files()
+    {
+        let torrent = torrent(b"abcdefghijkl");
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("root")).unwrap();
+        std::fs::write(dir.path().join("root/a"), b"abc").unwrap();
+        std::fs::write(dir.path().join("root/b"), b"dXfgh").unwrap();
+        let storage = Storage::new(&torrent, dir.path()).unwrap();
+
+        let report = verify(&torrent, &storage);
+
+        assert_eq!(report.pieces, vec![Status::Complete, Status::Corrupt, Status::Missing]);
+        let files: Vec<_> = report.files.iter().map(|file| (file.length, file.status)).collect();
+        assert_eq!(files, vec![(3, Status::Complete), (5, Status::Corrupt), (4, Status::Missing)]);
+        assert!(!report.is_complete());
+        assert_eq!(report.to_json()["pieces"]["corrupt"], serde_json::json!([1]));
+    }
+
+    #[test]
+    fn accepts_complete_data()
+    {
+        let data = b"abcdefghijkl";
+        let torrent = torrent(data);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&torrent, dir.path()).unwrap();
+        for index in 0..3
+        {
+            storage.write_piece(&torrent, index, &data[index * 4..][..4]).unwrap();
+        }
+
+        let report = verify(&torrent, &storage);
+
+        assert!(report.is_complete());
+        assert!(report.files.iter().all(|file| file.status == Status::Complete));
+        assert_eq!(report.to_json()["complete"], serde_json::json!(true));
+    }
+
+    #[test]
+    fn judges_empty_files_complete()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a"), b"abc").unwrap();
+        std::fs::write(root.join("b"), b"").unwrap();
+        std::fs::write(root.join("c"), b"defg").unwrap();
+        let torrent = Torrent::try_from(TorrentBuilder::new(&root).build().unwrap()).unwrap();
+        std::fs::write(root.join("a"), b"aXc").unwrap();
+
+        let report = verify(&torrent, &Storage::new(&torrent, dir.path()).unwrap());
+
+        let files: Vec<_> = report.files.iter().map(|file| (file.length, file.status)).collect();
+        assert_eq!(files, vec![(3, Status::Corrupt), (0, Status::Complete), (4, Status::Corrupt)], "The empty file shares no data with the piece");
+    }
+}